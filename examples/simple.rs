@@ -1,7 +1,7 @@
 use std::path::Path;
 use std::time::Duration;
 
-#[cfg(feature = "async-std")]
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
 use async_std1 as async_std;
 use futures_util::StreamExt;
 use log::info;
@@ -13,7 +13,7 @@ use fsevent_stream::ffi::{
     kFSEventStreamCreateFlagUseCFTypes, kFSEventStreamCreateFlagUseExtendedData,
     kFSEventStreamEventIdSinceNow,
 };
-use fsevent_stream::stream::create_event_stream;
+use fsevent_stream::stream::{create_event_stream, EventStreamHandler};
 
 #[cfg(feature = "tokio")]
 #[tokio::main]
@@ -21,7 +21,7 @@ async fn main() {
     run().await;
 }
 
-#[cfg(feature = "async-std")]
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
 #[async_std::main]
 async fn main() {
     run().await;
@@ -29,7 +29,7 @@ async fn main() {
 
 async fn run() {
     pretty_env_logger::init();
-    let (stream, _handler) = create_event_stream(
+    let (stream, handler) = create_event_stream(
         [Path::new("./")],
         kFSEventStreamEventIdSinceNow,
         Duration::ZERO,
@@ -39,8 +39,28 @@ async fn run() {
             | kFSEventStreamCreateFlagUseCFTypes,
     )
     .expect("stream to be created");
+    abort_on_ctrl_c(handler);
+
+    // The stream ends once the handler aborts it.
     let mut stream = stream.into_flatten();
     while let Some(event) = stream.next().await {
         info!("{}", event);
     }
+    info!("stream stopped");
+}
+
+#[cfg(feature = "tokio")]
+fn abort_on_ctrl_c(mut handler: EventStreamHandler) {
+    tokio::spawn(async move {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Ctrl-C handler to be installed");
+        handler.abort();
+    });
+}
+
+// async-std doesn't handle signals, so resort to a dedicated thread.
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+fn abort_on_ctrl_c(mut handler: EventStreamHandler) {
+    ctrlc::set_handler(move || handler.abort()).expect("Ctrl-C handler to be installed");
 }
@@ -0,0 +1,701 @@
+//! Adaptors on [`EventStream`](crate::stream::EventStream).
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use futures_core::Stream;
+use futures_util::future::{ready, select, Either};
+use futures_util::stream::{iter, unfold, BoxStream, StreamExt};
+use futures_util::task::{waker_ref, ArcWake};
+
+use crate::runtime::sleep;
+use crate::stream::{
+    Event, EventStream, EventWithParent, StreamFlags, StreamItem, SymlinkEvent, TransientPolicy,
+    UnmountPolicy,
+};
+
+// Flags with which `FSEvents` asks to rescan the directory of the event.
+const RESCAN_FLAGS: StreamFlags = StreamFlags::from_bits_truncate(
+    StreamFlags::MUST_SCAN_SUBDIRS.bits()
+        | StreamFlags::USER_DROPPED.bits()
+        | StreamFlags::KERNEL_DROPPED.bits(),
+);
+
+impl EventStream {
+    /// Regroup events into batches of at most `max_events` events, each spanning at most
+    /// `max_delay`.
+    ///
+    /// A batch is yielded as soon as it's full, or `max_delay` after its first event arrived,
+    /// whichever comes first. This decouples downstream batch sizing from the `latency` given to
+    /// `FSEvents`.
+    ///
+    /// # Panics
+    /// Panic when `max_events` is zero.
+    pub fn rebatch(self, max_events: usize, max_delay: Duration) -> impl Stream<Item = Vec<Event>> {
+        assert!(max_events > 0, "max_events must be positive");
+        unfold(
+            Some(self.into_flatten().boxed()),
+            move |events| async move {
+                let mut events = match events {
+                    Some(events) => events,
+                    None => return None,
+                };
+                let mut batch = match events.next().await {
+                    Some(event) => vec![event],
+                    None => return None,
+                };
+
+                let mut deadline = Box::pin(sleep(max_delay));
+                while batch.len() < max_events {
+                    match select(events.next(), deadline.as_mut()).await {
+                        Either::Left((Some(event), _)) => batch.push(event),
+                        Either::Left((None, _)) => return Some((batch, None)),
+                        Either::Right(((), _)) => break,
+                    }
+                }
+                Some((batch, Some(events)))
+            },
+        )
+    }
+
+    /// Split batches so that none yielded has more than `max` events, preserving their order.
+    ///
+    /// Unlike [`rebatch`](Self::rebatch), batches are never merged nor delayed: a batch of at most
+    /// `max` events is yielded as is, and a larger one is yielded in several parts right away.
+    ///
+    /// # Panics
+    /// Panic when `max` is zero.
+    pub fn chunked(self, max: usize) -> impl Stream<Item = Vec<Event>> {
+        assert!(max > 0, "max must be positive");
+        self.flat_map(move |batch| {
+            let mut events = batch.into_iter();
+            iter(std::iter::from_fn(move || {
+                let chunk: Vec<_> = events.by_ref().take(max).collect();
+                (!chunk.is_empty()).then_some(chunk)
+            }))
+        })
+    }
+
+    /// Emit at most `max_per_sec` batches per second, coalescing batches arriving in between.
+    ///
+    /// Unlike debouncing, which waits for events to calm down, batches keep being emitted at a
+    /// steady rate under sustained load. Each emitted batch holds, in order, all events received
+    /// since the previous emission. The first batch is emitted as soon as it arrives, and pending
+    /// events are emitted right away once the stream ends.
+    ///
+    /// While waiting for the next emission, incoming batches are drained into an unbounded buffer,
+    /// so a burst of events costs memory proportional to the events received in one interval. If
+    /// the consumer itself is slower than the cap, events queue up in the channel instead, subject
+    /// to the [`DeliveryPolicy`](crate::stream::DeliveryPolicy) of the stream.
+    ///
+    /// # Panics
+    /// Panic when `max_per_sec` is zero.
+    pub fn throttle(self, max_per_sec: u32) -> impl Stream<Item = Vec<Event>> {
+        assert!(max_per_sec > 0, "max_per_sec must be positive");
+        let interval = Duration::from_secs(1) / max_per_sec;
+        unfold(
+            (Some(self.boxed()), None::<Instant>),
+            move |(batches, last_emitted)| async move {
+                let mut batches = batches?;
+                let mut pending = batches.next().await?;
+
+                if let Some(last_emitted) = last_emitted {
+                    let remaining =
+                        (last_emitted + interval).saturating_duration_since(Instant::now());
+                    let mut deadline = Box::pin(sleep(remaining));
+                    loop {
+                        match select(batches.next(), deadline.as_mut()).await {
+                            Either::Left((Some(mut batch), _)) => pending.append(&mut batch),
+                            Either::Left((None, _)) => return Some((pending, (None, None))),
+                            Either::Right(((), _)) => break,
+                        }
+                    }
+                }
+                Some((pending, (Some(batches), Some(Instant::now()))))
+            },
+        )
+    }
+
+    /// Merge batches until no event arrives for `quiet`, and yield the events of the window at
+    /// once.
+    ///
+    /// Unlike [`throttle`](Self::throttle), nothing is yielded as long as events keep arriving
+    /// within `quiet` of each other. Pending events are yielded right away once the stream ends.
+    pub fn debounce(self, quiet: Duration) -> impl Stream<Item = Vec<Event>> {
+        unfold(Some(self), move |batches| async move {
+            let mut batches = batches?;
+            let mut window = batches.next().await?;
+            loop {
+                match select(batches.next(), Box::pin(sleep(quiet))).await {
+                    Either::Left((Some(mut batch), _)) => window.append(&mut batch),
+                    Either::Left((None, _)) => return Some((window, None)),
+                    Either::Right(((), _)) => return Some((window, Some(batches))),
+                }
+            }
+        })
+    }
+
+    /// Flatten event batches and produce a stream of [`StreamItem`](StreamItem), turning mount
+    /// and unmount events, as well as `HISTORY_DONE` and `IDS_WRAPPED` sentinel events, into
+    /// structured notifications.
+    ///
+    /// See [`UnmountPolicy`](UnmountPolicy) for how a volume being unmounted is handled.
+    pub fn into_items(self, policy: UnmountPolicy) -> impl Stream<Item = StreamItem> {
+        TakeThrough::new(self.into_flatten().map(StreamItem::from), move |item| {
+            policy == UnmountPolicy::End && matches!(item, StreamItem::Unmounted { .. })
+        })
+    }
+
+    /// Flatten event batches and call the async `handler` with each event, with at most
+    /// `concurrency` handlers in flight.
+    ///
+    /// Events are passed to handlers in order, but handlers may complete in any order. The
+    /// returned future completes once the stream has ended and all pending handlers have
+    /// completed, so abort the [`EventStreamHandler`](crate::stream::EventStreamHandler) to finish
+    /// processing. Dropping the future cancels pending handlers.
+    ///
+    /// # Panics
+    /// Panic when `concurrency` is zero.
+    pub async fn for_each_events<Fut>(
+        self,
+        concurrency: usize,
+        handler: impl FnMut(Event) -> Fut + Send,
+    ) where
+        Fut: Future<Output = ()> + Send,
+    {
+        assert!(concurrency > 0, "concurrency must be positive");
+        self.into_flatten()
+            .for_each_concurrent(concurrency, handler)
+            .await;
+    }
+
+    /// Drop sentinel events, i.e. those flagged with `HISTORY_DONE` or `IDS_WRAPPED`, which don't
+    /// report a change and carry no meaningful path. Batches which end up empty are skipped.
+    ///
+    /// Use [`into_items`](Self::into_items) instead to be notified of them.
+    pub fn skip_sentinels(self) -> impl Stream<Item = Vec<Event>> {
+        self.filter_map(|mut batch| {
+            batch.retain(|event| !event.flags.is_sentinel());
+            ready((!batch.is_empty()).then_some(batch))
+        })
+    }
+
+    /// Replace events asking for a rescan, i.e. flagged with `MUST_SCAN_SUBDIRS`, `USER_DROPPED`
+    /// or `KERNEL_DROPPED`, with a synthetic event about the directory, flagged with
+    /// `MUST_SCAN_SUBDIRS` only.
+    ///
+    /// This lets consumers which handle every event as a change to its path deal with rescans the
+    /// same way: walk the directory and everything under it. Note that `FSEvents` doesn't tell
+    /// what has changed in the subtree, and neither does this. The event only marks it as stale.
+    ///
+    /// Within a batch, a directory yields a single synthetic event, with the id of the first event
+    /// asking to rescan it. `raw_flags` keep what `FSEvents` reported, so that drops can still be
+    /// told apart. Other events are passed through.
+    pub fn rescans_as_events(self) -> impl Stream<Item = Vec<Event>> {
+        self.map(|batch| {
+            let mut rescanned = HashSet::new();
+            batch
+                .into_iter()
+                .filter_map(|event| {
+                    if !event.flags.intersects(RESCAN_FLAGS) {
+                        return Some(event);
+                    }
+                    rescanned.insert(event.path.clone()).then_some(Event {
+                        path: event.path,
+                        inode: None,
+                        flags: StreamFlags::MUST_SCAN_SUBDIRS,
+                        raw_flags: event.raw_flags,
+                        id: event.id,
+                        extended_data: None,
+                        received_at: event.received_at,
+                    })
+                })
+                .collect()
+        })
+    }
+
+    /// Flatten event batches and attach the target of symlinks to events about them.
+    ///
+    /// For events flagged with `IS_SYMLINK` and either `ITEM_CREATED` or `ITEM_MODIFIED`, the link
+    /// is read with [`fs::read_link`](fs::read_link), without following it. If the link has
+    /// already been removed or replaced by then, the target is `None`.
+    ///
+    /// This performs a blocking syscall per symlink event in the stream, so only use it when you
+    /// need the targets.
+    pub fn with_symlink_targets(self) -> impl Stream<Item = SymlinkEvent> {
+        self.into_flatten().map(|event| {
+            let symlink_target = if event.flags.contains(StreamFlags::IS_SYMLINK)
+                && event
+                    .flags
+                    .intersects(StreamFlags::ITEM_CREATED | StreamFlags::ITEM_MODIFIED)
+            {
+                fs::read_link(&event.path).ok()
+            } else {
+                None
+            };
+            SymlinkEvent {
+                event,
+                symlink_target,
+            }
+        })
+    }
+
+    /// Flatten event batches and attach the parent directory of their path to events.
+    ///
+    /// See [`EventWithParent::parent`](EventWithParent::parent) for edge cases.
+    pub fn with_parents(self) -> impl Stream<Item = EventWithParent> {
+        self.into_flatten().map(|event| EventWithParent {
+            parent: event.path.parent().map(Path::to_path_buf),
+            event,
+        })
+    }
+
+    /// Drop items which are both created and removed within a single batch.
+    ///
+    /// An item is a candidate if its events in the batch carry both `ITEM_CREATED` and
+    /// `ITEM_REMOVED`, either coalesced into one event by `FSEvents` or spread over several. Items
+    /// are matched by path, and also by inode if the stream is created with
+    /// `kFSEventStreamCreateFlagUseExtendedData`, so that a temporary file renamed before being
+    /// removed is dropped as well. No event is emitted for dropped items, and batches which end
+    /// up empty are skipped.
+    ///
+    /// Since flags accumulate, a candidate may also be an item replaced in place or one created
+    /// in an earlier batch. See [`TransientPolicy`](TransientPolicy) for how these are told apart.
+    pub fn drop_transient(self, policy: TransientPolicy) -> impl Stream<Item = Vec<Event>> {
+        self.filter_map(move |batch| {
+            let mut by_path: HashMap<&Path, StreamFlags> = HashMap::new();
+            let mut by_inode: HashMap<u64, (StreamFlags, Vec<&Path>)> = HashMap::new();
+            for event in &batch {
+                *by_path.entry(&event.path).or_insert(StreamFlags::NONE) |= event.flags;
+                if let Some(inode) = event.inode {
+                    let (flags, paths) = by_inode
+                        .entry(inode)
+                        .or_insert((StreamFlags::NONE, Vec::new()));
+                    *flags |= event.flags;
+                    paths.push(&event.path);
+                }
+            }
+            let is_candidate = |flags: &StreamFlags| {
+                flags.contains(StreamFlags::ITEM_CREATED | StreamFlags::ITEM_REMOVED)
+            };
+            let is_gone = |path: &Path| match policy {
+                TransientPolicy::CheckExistence => {
+                    fs::symlink_metadata(path).is_err_and(|e| e.kind() == io::ErrorKind::NotFound)
+                }
+                TransientPolicy::FlagsOnly => true,
+            };
+            // An inode is only gone if none of the paths it was seen at exists, e.g. the temporary
+            // file of an atomic save lives on under the saved path.
+            let is_transient = |event: &Event| {
+                (by_path.get(event.path.as_path()).is_some_and(is_candidate)
+                    && is_gone(&event.path))
+                    || event
+                        .inode
+                        .and_then(|inode| by_inode.get(&inode))
+                        .is_some_and(|(flags, paths)| {
+                            is_candidate(flags) && paths.iter().all(|path| is_gone(path))
+                        })
+            };
+            let keep: Vec<_> = batch.iter().map(|event| !is_transient(event)).collect();
+
+            let batch: Vec<_> = batch
+                .into_iter()
+                .zip(keep)
+                .filter_map(|(event, keep)| keep.then_some(event))
+                .collect();
+            ready((!batch.is_empty()).then_some(batch))
+        })
+    }
+
+    /// Produce, per batch, the set of directories which need to be rescanned.
+    ///
+    /// The parent of every changed item is included, since its listing may have changed. So are
+    /// directories reported as changed themselves, i.e. flagged with `IS_DIR` or
+    /// `MUST_SCAN_SUBDIRS`. Directories are deduplicated within a batch, so any number of changes
+    /// in one directory result in a single entry. Combine with [`rebatch`](Self::rebatch) to
+    /// deduplicate over a longer window.
+    ///
+    /// This is meant for file-granular streams, and yields a more precise result than
+    /// directory-granular streams, which report the directories only.
+    pub fn changed_dirs(self) -> impl Stream<Item = HashSet<PathBuf>> {
+        self.filter_map(|batch| {
+            let mut dirs = HashSet::new();
+            for event in batch {
+                if let Some(parent) = event.path.parent() {
+                    dirs.insert(parent.to_path_buf());
+                }
+                if event
+                    .flags
+                    .intersects(StreamFlags::IS_DIR | StreamFlags::MUST_SCAN_SUBDIRS)
+                {
+                    dirs.insert(event.path);
+                }
+            }
+            ready((!dirs.is_empty()).then_some(dirs))
+        })
+    }
+
+    /// Flatten event batches and produce a stream of events about the file with the given inode.
+    ///
+    /// The inode of a file stays the same when it's renamed, so this follows the file through
+    /// `ITEM_RENAMED` events even though its path changes. Note that many editors save files by
+    /// replacing them with a new file, which gets a new inode.
+    ///
+    /// Inodes are only reported by streams created with both
+    /// `kFSEventStreamCreateFlagUseExtendedData` and `kFSEventStreamCreateFlagFileEvents`. On
+    /// other streams, no event ever matches.
+    pub fn track_inode(self, inode: u64) -> impl Stream<Item = Event> {
+        self.into_flatten()
+            .filter(move |event| ready(event.inode == Some(inode)))
+    }
+
+    /// Flatten event batches and produce a stream of events whose flags intersect with `mask`.
+    ///
+    /// An event matches if it has any flag of `mask` set, so an empty mask matches nothing.
+    ///
+    /// If the mask is known when the stream is created, prefer
+    /// [`EventStreamBuilder::filter_flags`](crate::stream::EventStreamBuilder::filter_flags),
+    /// which skips filtered-out events before they're constructed.
+    pub fn filter_flags(self, mask: StreamFlags) -> impl Stream<Item = Event> {
+        self.into_flatten()
+            .filter(move |event| ready(event.flags.intersects(mask)))
+    }
+
+    /// Flatten event batches and suppress events identical to one of the last `capacity` emitted
+    /// events.
+    ///
+    /// Events are considered identical if they have the same path and flags. This gives
+    /// at-most-once-per-window semantics across batches, e.g. while a file is being written and
+    /// re-reported repeatedly.
+    ///
+    /// Note that this trades completeness for less downstream work: a change happening again
+    /// within the window is dropped even if it's a genuinely new change. It's also distinct from
+    /// coalescing within a batch, which `FSEvents` already does to some extent.
+    pub fn dedup_recent(self, capacity: usize) -> impl Stream<Item = Event> {
+        let mut window = RecentWindow::new(capacity);
+        self.into_flatten()
+            .filter(move |event| ready(window.insert(event)))
+    }
+
+    /// Flatten event batches and produce events in ascending order of their ids.
+    ///
+    /// Events are buffered for `window` after the first one of a round arrives, and emitted
+    /// sorted by id at the end of the window. This adds up to `window` of latency, and only
+    /// orders events arriving within the same window.
+    ///
+    /// Event ids of a device-relative stream are only comparable with other ids on the same
+    /// device. So when merging streams of different devices, order each of them before merging
+    /// instead of the merged stream.
+    pub fn ordered(self, window: Duration) -> impl Stream<Item = Event> {
+        self.rebatch(usize::MAX, window).flat_map(|mut batch| {
+            // Stable sort to keep events with the same id in arrival order.
+            batch.sort_by_key(|event| event.id);
+            iter(batch)
+        })
+    }
+
+    /// Attach a user-defined context to each event batch.
+    ///
+    /// This is useful to tell streams apart after merging them, e.g. to know which project a
+    /// batch belongs to.
+    pub fn map_context<C: Clone>(self, ctx: C) -> impl Stream<Item = (C, Vec<Event>)> {
+        self.map(move |events| (ctx.clone(), events))
+    }
+
+    /// Yield events until one matches `predicate`, then complete.
+    ///
+    /// The matching event is yielded before the stream completes. The backing `RunLoop` is then
+    /// asked to stop itself, as if [`AbortToken::abort`](crate::stream::AbortToken::abort) were
+    /// called from it, so neither the executor nor any other thread waits for it. It's still fine,
+    /// and needed to join the thread, to call
+    /// [`EventStreamHandler::abort`](crate::stream::EventStreamHandler::abort) afterwards.
+    ///
+    /// Streams served by a [`WatcherPool`](crate::pool::WatcherPool) are not stopped. Neither are
+    /// streams whose handler has watched more paths with
+    /// [`add_path`](crate::stream::EventStreamHandler::add_path) since this method was called:
+    /// `add_path` moves the stream to a new `RunLoop`, which the token taken here doesn't know
+    /// about, so abort the handler yourself in that case.
+    pub fn take_until_event(
+        mut self,
+        mut predicate: impl FnMut(&Event) -> bool,
+    ) -> impl Stream<Item = Event> {
+        let mut token = self.take_abort_token();
+        TakeThrough::new(self.into_flatten(), move |event| {
+            let matched = predicate(event);
+            if matched {
+                if let Some(token) = token.take() {
+                    // Aborting from here would wait for the RunLoop to be idle, which may take a
+                    // while if its callback is blocked on this very stream.
+                    token.abort_on_runloop();
+                }
+            }
+            matched
+        })
+    }
+
+    /// Group events of each batch by the watched root they belong to.
+    ///
+    /// Each batch is split into one `(root, events)` item per root which has events in it, in the
+    /// order the roots first appear in the batch. Roots are taken from
+    /// [`roots`](EventStream::roots), and an event belongs to the deepest root it's under, so
+    /// nested roots don't share events. Events which are under no root, which shouldn't happen,
+    /// are grouped under `None`.
+    pub fn group_by_root(self) -> impl Stream<Item = (Option<PathBuf>, Vec<Event>)> {
+        let mut roots = self.roots().to_vec();
+        // Deeper roots first, so that the first match is the owning root.
+        roots.sort_by_key(|root| std::cmp::Reverse(root.components().count()));
+        self.flat_map(move |events| {
+            let mut groups: Vec<(Option<PathBuf>, Vec<Event>)> = vec![];
+            for event in events {
+                let root = roots.iter().find(|root| event.path.starts_with(root));
+                match groups.iter_mut().find(|(group, _)| group.as_ref() == root) {
+                    Some((_, group)) => group.push(event),
+                    None => groups.push((root.cloned(), vec![event])),
+                }
+            }
+            iter(groups)
+        })
+    }
+
+    /// Split events into a pair of streams `(files, dirs)`.
+    ///
+    /// Events flagged with `IS_DIR`, and events without any item type flag (as in
+    /// directory-granular streams), go to `dirs`. All other events, including symlinks, go to
+    /// `files`.
+    ///
+    /// Each side can be polled independently. Events for the side which isn't being polled are
+    /// buffered, up to `capacity` events. Once the buffer is full, the polled side waits until
+    /// the other one catches up, so drop a side if you don't intend to consume it.
+    ///
+    /// # Panics
+    /// Panic when `capacity` is zero.
+    pub fn partition_file_dir(
+        self,
+        capacity: usize,
+    ) -> (
+        impl Stream<Item = Event> + Send,
+        impl Stream<Item = Event> + Send,
+    ) {
+        assert!(capacity > 0, "capacity must be positive");
+        let shared = Arc::new(Mutex::new(PartitionState {
+            events: self.into_flatten().boxed(),
+            capacity,
+            queues: [VecDeque::new(), VecDeque::new()],
+            closed: [false, false],
+            done: false,
+        }));
+        let waker = Arc::new(PartitionWaker {
+            wakers: Mutex::new([None, None]),
+        });
+        (
+            Partition {
+                shared: shared.clone(),
+                waker: waker.clone(),
+                side: PARTITION_FILES,
+            },
+            Partition {
+                shared,
+                waker,
+                side: PARTITION_DIRS,
+            },
+        )
+    }
+}
+
+/// A bounded window of the most recently emitted `(path, flags)` pairs.
+struct RecentWindow {
+    capacity: usize,
+    order: VecDeque<(PathBuf, StreamFlags)>,
+    members: HashSet<(PathBuf, StreamFlags)>,
+}
+
+impl RecentWindow {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            order: VecDeque::with_capacity(capacity),
+            members: HashSet::with_capacity(capacity),
+        }
+    }
+
+    /// Record `event` and return `true` if it's not in the window.
+    fn insert(&mut self, event: &Event) -> bool {
+        let key = (event.path.clone(), event.flags);
+        if self.members.contains(&key) {
+            return false;
+        }
+        if self.capacity == 0 {
+            return true;
+        }
+
+        if self.order.len() == self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.members.remove(&oldest);
+            }
+        }
+        self.members.insert(key.clone());
+        self.order.push_back(key);
+        true
+    }
+}
+
+const PARTITION_FILES: usize = 0;
+const PARTITION_DIRS: usize = 1;
+
+struct PartitionState {
+    events: BoxStream<'static, Event>,
+    capacity: usize,
+    queues: [VecDeque<Event>; 2],
+    closed: [bool; 2],
+    done: bool,
+}
+
+/// Wakes both sides of a partition, because either side may be the one to receive the next event.
+struct PartitionWaker {
+    wakers: Mutex<[Option<Waker>; 2]>,
+}
+
+impl PartitionWaker {
+    fn register(&self, side: usize, waker: &Waker) {
+        if let Ok(mut wakers) = self.wakers.lock() {
+            wakers[side] = Some(waker.clone());
+        }
+    }
+
+    fn wake_side(&self, side: usize) {
+        let waker = self
+            .wakers
+            .lock()
+            .ok()
+            .and_then(|mut wakers| wakers[side].take());
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl ArcWake for PartitionWaker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.wake_side(PARTITION_FILES);
+        arc_self.wake_side(PARTITION_DIRS);
+    }
+}
+
+struct Partition {
+    shared: Arc<Mutex<PartitionState>>,
+    waker: Arc<PartitionWaker>,
+    side: usize,
+}
+
+impl Stream for Partition {
+    type Item = Event;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let side = self.side;
+        let mut state = self
+            .shared
+            .lock()
+            .expect("partition lock not to be poisoned");
+        if let Some(event) = state.queues[side].pop_front() {
+            // The other side may be waiting for room in this queue.
+            if state.queues[side].len() + 1 == state.capacity {
+                self.waker.wake_side(1 - side);
+            }
+            return Poll::Ready(Some(event));
+        }
+
+        self.waker.register(side, cx.waker());
+        let waker = waker_ref(&self.waker);
+        let mut inner_cx = Context::from_waker(&waker);
+        while !state.done {
+            // Woken up by the other side once it makes room.
+            if state.queues[1 - side].len() >= state.capacity {
+                return Poll::Pending;
+            }
+            match state.events.poll_next_unpin(&mut inner_cx) {
+                Poll::Ready(Some(event)) => {
+                    let target = if event.flags.contains(StreamFlags::IS_DIR)
+                        || !event
+                            .flags
+                            .intersects(StreamFlags::IS_FILE | StreamFlags::IS_SYMLINK)
+                    {
+                        PARTITION_DIRS
+                    } else {
+                        PARTITION_FILES
+                    };
+                    if target == side {
+                        return Poll::Ready(Some(event));
+                    }
+                    if !state.closed[target] {
+                        state.queues[target].push_back(event);
+                        self.waker.wake_side(target);
+                    }
+                }
+                Poll::Ready(None) => {
+                    state.done = true;
+                    self.waker.wake_side(1 - side);
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(None)
+    }
+}
+
+impl Drop for Partition {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.shared.lock() {
+            state.closed[self.side] = true;
+            state.queues[self.side].clear();
+        }
+        // The other side may be waiting for an event which only this side would have pulled.
+        self.waker.wake_side(1 - self.side);
+    }
+}
+
+/// Yields items up to and including the first one for which `is_last` returns `true`.
+///
+/// Unlike ending a `scan`, the inner stream is dropped right after the last item instead of being
+/// polled for another one, which may never come.
+struct TakeThrough<T, F> {
+    events: Option<BoxStream<'static, T>>,
+    is_last: F,
+}
+
+impl<T, F: FnMut(&T) -> bool> TakeThrough<T, F> {
+    fn new(events: impl Stream<Item = T> + Send + 'static, is_last: F) -> Self {
+        Self {
+            events: Some(events.boxed()),
+            is_last,
+        }
+    }
+}
+
+// `is_last` is never pinned.
+impl<T, F> Unpin for TakeThrough<T, F> {}
+
+impl<T, F: FnMut(&T) -> bool> Stream for TakeThrough<T, F> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let Some(events) = this.events.as_mut() else {
+            return Poll::Ready(None);
+        };
+        let item = match events.poll_next_unpin(cx) {
+            Poll::Ready(item) => item,
+            Poll::Pending => return Poll::Pending,
+        };
+        if item.as_ref().is_none_or(|item| (this.is_last)(item)) {
+            this.events = None;
+        }
+        Poll::Ready(item)
+    }
+}
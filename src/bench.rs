@@ -0,0 +1,173 @@
+//! Drive `FSEvents` callbacks with synthetic events, without a `RunLoop`.
+//!
+//! This module only exists for the benchmarks in `benches/`, and is not part of the public API.
+#![allow(clippy::borrow_interior_mutable_const)]
+
+use std::ffi::{c_void, CString};
+use std::os::raw::c_char;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use core_foundation::array::CFArray;
+use core_foundation::base::{CFType, TCFType};
+use core_foundation::dictionary::CFDictionary;
+use core_foundation::number::CFNumber;
+use core_foundation::string::CFString;
+
+use crate::ffi::{
+    kFSEventStreamEventExtendedDataPathKey, kFSEventStreamEventExtendedFileIDKey,
+    kFSEventStreamEventFlagItemCreated, kFSEventStreamEventFlagItemIsFile, FSEventStreamEventFlags,
+    FSEventStreamEventId,
+};
+use crate::stream::{
+    cf_callback, cf_ext_callback, cf_ext_with_id_callback, normal_callback, Event,
+    StreamContextInfo,
+};
+
+/// Which callback to drive, as selected by the stream creation flags.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum CallbackKind {
+    /// Paths as C strings.
+    Plain,
+    /// `kFSEventStreamCreateFlagUseCFTypes`.
+    CfTypes,
+    /// `kFSEventStreamCreateFlagUseCFTypes | kFSEventStreamCreateFlagUseExtendedData`.
+    ExtendedData,
+    /// Extended data with file ids, as delivered with `kFSEventStreamCreateFlagFileEvents`.
+    ExtendedDataWithId,
+}
+
+enum Paths {
+    /// The strings are kept alive for the pointers into them.
+    Plain(#[allow(dead_code)] Vec<CString>, Vec<*const c_char>),
+    Cf(CFArray<CFString>),
+    Extended(CFArray<CFDictionary<CFString, CFType>>),
+}
+
+/// A synthetic batch of events, laid out the way `FSEvents` passes them to the callback.
+pub struct Input {
+    kind: CallbackKind,
+    paths: Paths,
+    flags: Vec<FSEventStreamEventFlags>,
+    ids: Vec<FSEventStreamEventId>,
+}
+
+impl Input {
+    /// Create a batch of `num_events` file creation events.
+    #[must_use]
+    pub fn new(kind: CallbackKind, num_events: usize) -> Self {
+        let path_strs: Vec<_> = (0..num_events)
+            .map(|idx| format!("/Users/fsevent-stream/project/src/module_{}/file.rs", idx))
+            .collect();
+        let paths = match kind {
+            CallbackKind::Plain => {
+                let c_paths: Vec<_> = path_strs
+                    .into_iter()
+                    .map(|path| CString::new(path).expect("no nul in path"))
+                    .collect();
+                let ptrs = c_paths.iter().map(|path| path.as_ptr()).collect();
+                Paths::Plain(c_paths, ptrs)
+            }
+            CallbackKind::CfTypes => Paths::Cf(CFArray::from_CFTypes(
+                &path_strs
+                    .iter()
+                    .map(|path| CFString::new(path))
+                    .collect::<Vec<_>>(),
+            )),
+            CallbackKind::ExtendedData | CallbackKind::ExtendedDataWithId => {
+                let dicts: Vec<_> = (0_i64..)
+                    .zip(&path_strs)
+                    .map(|(file_id, path)| {
+                        CFDictionary::from_CFType_pairs(&[
+                            (
+                                (*kFSEventStreamEventExtendedDataPathKey).clone(),
+                                CFString::new(path).as_CFType(),
+                            ),
+                            (
+                                (*kFSEventStreamEventExtendedFileIDKey).clone(),
+                                CFNumber::from(file_id).as_CFType(),
+                            ),
+                        ])
+                    })
+                    .collect();
+                Paths::Extended(CFArray::from_CFTypes(&dicts))
+            }
+        };
+        Self {
+            kind,
+            paths,
+            flags: vec![
+                kFSEventStreamEventFlagItemCreated | kFSEventStreamEventFlagItemIsFile;
+                num_events
+            ],
+            ids: (0..num_events as u64).collect(),
+        }
+    }
+
+    /// Number of events in this batch.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    /// Whether this batch has no event.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    fn paths_ptr(&self) -> *mut c_void {
+        match &self.paths {
+            Paths::Plain(_, ptrs) => ptrs.as_ptr() as *mut c_void,
+            Paths::Cf(array) => array.as_concrete_TypeRef() as *mut c_void,
+            Paths::Extended(array) => array.as_concrete_TypeRef() as *mut c_void,
+        }
+    }
+}
+
+/// Delivers batches to a handler which only counts events.
+pub struct Driver {
+    info: StreamContextInfo,
+    delivered: Arc<AtomicUsize>,
+}
+
+impl Driver {
+    /// Create a driver with a fresh stream context.
+    #[must_use]
+    pub fn new() -> Self {
+        let delivered = Arc::new(AtomicUsize::new(0));
+        let info = StreamContextInfo::with_handler({
+            let delivered = delivered.clone();
+            move |events: Vec<Event>| {
+                delivered.fetch_add(events.len(), Ordering::Relaxed);
+            }
+        });
+        Self { info, delivered }
+    }
+
+    /// Run the callback for `input` once, and return the number of events delivered so far.
+    pub fn run(&self, input: &Input) -> usize {
+        let callback = match input.kind {
+            CallbackKind::Plain => normal_callback,
+            CallbackKind::CfTypes => cf_callback,
+            CallbackKind::ExtendedData => cf_ext_callback,
+            CallbackKind::ExtendedDataWithId => cf_ext_with_id_callback,
+        };
+        callback(
+            ptr::null_mut(),
+            ptr::addr_of!(self.info) as *mut c_void,
+            input.len(),
+            input.paths_ptr(),
+            input.flags.as_ptr(),
+            input.ids.as_ptr(),
+        );
+        self.delivered.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for Driver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,207 @@
+//! Watch locations referred to by bookmark data.
+//!
+//! Bookmarks (and Finder aliases) keep referring to a file system object even after it's moved or
+//! renamed. They're also the way sandboxed apps persist access to user-selected locations.
+
+#![allow(non_snake_case, non_upper_case_globals)]
+
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::path::PathBuf;
+use std::ptr;
+use std::time::Duration;
+
+use core_foundation::base::{kCFAllocatorDefault, Boolean, CFAllocatorRef, CFOptionFlags, TCFType};
+use core_foundation::data::{CFData, CFDataRef};
+use core_foundation::error::CFErrorRef;
+use core_foundation::url::{CFURLRef, CFURL};
+
+use crate::ffi::{FSEventStreamCreateFlags, FSEventStreamEventId};
+use crate::stream::{EventStream, EventStreamBuilder, EventStreamHandler};
+
+const kCFURLBookmarkResolutionWithoutUIMask: CFOptionFlags = 1 << 8;
+const kCFURLBookmarkResolutionWithoutMountingMask: CFOptionFlags = 1 << 9;
+const kCFURLBookmarkResolutionWithSecurityScope: CFOptionFlags = 1 << 10;
+
+#[link(name = "CoreFoundation", kind = "framework")]
+extern "C" {
+    fn CFURLCreateByResolvingBookmarkData(
+        allocator: CFAllocatorRef,
+        bookmark: CFDataRef,
+        options: CFOptionFlags,
+        relativeToURL: CFURLRef,
+        resourcePropertiesToInclude: *const std::ffi::c_void,
+        isStale: *mut Boolean,
+        error: *mut CFErrorRef,
+    ) -> CFURLRef;
+}
+
+/// Serialized bookmark data, as produced by `CFURLCreateBookmarkData` or
+/// `NSURL.bookmarkData(options:includingResourceValuesForKeys:relativeTo:)`.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct BookmarkData {
+    data: Vec<u8>,
+    security_scoped: bool,
+}
+
+impl BookmarkData {
+    /// Wrap a regular bookmark or alias.
+    #[must_use]
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            security_scoped: false,
+        }
+    }
+
+    /// Wrap a security-scoped bookmark.
+    ///
+    /// Resolving it doesn't grant access to the location by itself. Sandboxed apps still need to
+    /// start accessing the security-scoped resource before watching it.
+    #[must_use]
+    pub fn security_scoped(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            security_scoped: true,
+        }
+    }
+
+    /// Resolve the bookmark to the current path of the location it refers to.
+    ///
+    /// No UI is shown and no volume is mounted during resolution.
+    ///
+    /// # Errors
+    /// Return error when the bookmark can't be resolved, or it's stale and should be recreated.
+    pub fn resolve(&self) -> Result<PathBuf, BookmarkError> {
+        let data = CFData::from_buffer(&self.data);
+        let mut options =
+            kCFURLBookmarkResolutionWithoutUIMask | kCFURLBookmarkResolutionWithoutMountingMask;
+        if self.security_scoped {
+            options |= kCFURLBookmarkResolutionWithSecurityScope;
+        }
+
+        let mut is_stale: Boolean = 0;
+        let url = unsafe {
+            CFURLCreateByResolvingBookmarkData(
+                kCFAllocatorDefault,
+                data.as_concrete_TypeRef(),
+                options,
+                ptr::null(),
+                ptr::null(),
+                &mut is_stale,
+                ptr::null_mut(),
+            )
+        };
+        if url.is_null() {
+            return Err(BookmarkError::Unresolvable);
+        }
+        let path = unsafe { CFURL::wrap_under_create_rule(url) }
+            .to_path()
+            .ok_or(BookmarkError::Unresolvable)?;
+        if is_stale != 0 {
+            return Err(BookmarkError::Stale { path });
+        }
+        Ok(path)
+    }
+}
+
+impl From<Vec<u8>> for BookmarkData {
+    fn from(data: Vec<u8>) -> Self {
+        Self::new(data)
+    }
+}
+
+/// An error occurred when resolving a bookmark.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum BookmarkError {
+    /// The bookmark is malformed, or the location it refers to can't be found.
+    Unresolvable,
+    /// The bookmark is resolved to `path`, but it's stale and should be recreated from `path`.
+    Stale { path: PathBuf },
+}
+
+impl Display for BookmarkError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unresolvable => write!(f, "unable to resolve bookmark"),
+            Self::Stale { path } => write!(f, "bookmark to {:?} is stale", path),
+        }
+    }
+}
+
+impl Error for BookmarkError {}
+
+/// An error occurred when creating a stream from bookmarks.
+#[derive(Debug)]
+pub enum CreateFromBookmarksError {
+    /// Some bookmarks failed to be resolved, along with their indices in the given slice.
+    Bookmarks(Vec<(usize, BookmarkError)>),
+    /// The stream failed to be created.
+    Io(io::Error),
+}
+
+impl Display for CreateFromBookmarksError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Bookmarks(errors) => {
+                write!(f, "unable to resolve {} bookmark(s):", errors.len())?;
+                for (idx, e) in errors {
+                    write!(f, " [{}] {};", idx, e)?;
+                }
+                Ok(())
+            }
+            Self::Io(e) => write!(f, "unable to create stream: {}", e),
+        }
+    }
+}
+
+impl Error for CreateFromBookmarksError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Bookmarks(_) => None,
+            Self::Io(e) => Some(e),
+        }
+    }
+}
+
+/// Create a new [`EventStream`](EventStream) and [`EventStreamHandler`](EventStreamHandler) pair
+/// which watches the locations referred to by `bookmarks`.
+///
+/// Each bookmark is resolved by [`BookmarkData::resolve`](BookmarkData::resolve) before watching.
+/// Note that the resolved paths are watched, so the stream doesn't follow the locations if
+/// they're moved afterwards.
+///
+/// # Errors
+/// Return [`CreateFromBookmarksError::Bookmarks`](CreateFromBookmarksError::Bookmarks) with every
+/// bookmark which failed to be resolved, including stale ones, or
+/// [`CreateFromBookmarksError::Io`](CreateFromBookmarksError::Io) when the stream fails to be
+/// created.
+///
+/// # Panics
+/// Panic when the given flags combination is illegal.
+pub fn create_event_stream_from_bookmarks(
+    bookmarks: &[BookmarkData],
+    since_when: FSEventStreamEventId,
+    latency: Duration,
+    flags: FSEventStreamCreateFlags,
+) -> Result<(EventStream, EventStreamHandler), CreateFromBookmarksError> {
+    let mut paths = Vec::with_capacity(bookmarks.len());
+    let mut errors = vec![];
+    for (idx, bookmark) in bookmarks.iter().enumerate() {
+        match bookmark.resolve() {
+            Ok(path) => paths.push(path),
+            Err(e) => errors.push((idx, e)),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(CreateFromBookmarksError::Bookmarks(errors));
+    }
+
+    EventStreamBuilder::new(paths)
+        .since_when(since_when)
+        .latency(latency)
+        .flags(flags)
+        .build()
+        .map_err(CreateFromBookmarksError::Io)
+}
@@ -0,0 +1,165 @@
+//! Debounced, classified events for the common case of watching a tree for changes.
+//!
+//! `FSEvents` reports coalesced flags per path, and leaves it to the caller to tell what actually
+//! happened. [`create_debounced_watcher`](create_debounced_watcher) does this once: it waits for
+//! changes to settle, pairs the two halves of a rename, and turns the rest into one
+//! [`DebouncedEvent`](DebouncedEvent) per path.
+
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use futures_core::Stream;
+use futures_util::stream::{iter, StreamExt};
+
+use crate::ffi::{
+    kFSEventStreamCreateFlagFileEvents, kFSEventStreamCreateFlagNoDefer,
+    kFSEventStreamCreateFlagUseCFTypes, kFSEventStreamCreateFlagUseExtendedData,
+    kFSEventStreamEventIdSinceNow,
+};
+use crate::stream::{create_event_stream, Event, EventStream, EventStreamHandler, StreamFlags};
+
+/// A change which has settled, in the spirit of `notify`'s `DebouncedEvent`.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DebouncedEvent {
+    /// The item has been created, or moved into the watched tree.
+    Create(PathBuf),
+    /// The content of the item has been modified.
+    Write(PathBuf),
+    /// Only metadata of the item, e.g. permissions, owner or extended attributes, has changed.
+    Chmod(PathBuf),
+    /// The item has been removed, or moved out of the watched tree.
+    Remove(PathBuf),
+    /// The item has been renamed from the first path to the second one.
+    Rename(PathBuf, PathBuf),
+    /// Events have been coalesced or dropped, so the directory must be rescanned.
+    Rescan(PathBuf),
+}
+
+impl DebouncedEvent {
+    /// The path this event is about. For renames, it's the new path.
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        match self {
+            Self::Create(path)
+            | Self::Write(path)
+            | Self::Chmod(path)
+            | Self::Remove(path)
+            | Self::Rename(_, path)
+            | Self::Rescan(path) => path,
+        }
+    }
+}
+
+impl EventStream {
+    /// Debounce event batches with [`debounce`](Self::debounce) and produce a stream of
+    /// [`DebouncedEvent`](DebouncedEvent).
+    ///
+    /// Within each window:
+    /// - Renamed items are paired by inode, yielding a single `Rename`. This requires the stream to
+    ///   be created with `kFSEventStreamCreateFlagUseExtendedData` and
+    ///   `kFSEventStreamCreateFlagFileEvents`. Unpaired renames are moves into or out of the
+    ///   watched tree.
+    /// - Flags of the remaining events are merged per path, and the path is checked on disk to
+    ///   tell what the net change is. Items created and removed within the window yield nothing.
+    ///
+    /// Events are yielded in the order their paths first appear in the window. Since `FSEvents`
+    /// doesn't tell the order of coalesced changes, this is best effort: e.g. a file removed and
+    /// recreated within the window is reported as created.
+    pub fn into_debounced(self, timeout: Duration) -> impl Stream<Item = DebouncedEvent> {
+        self.debounce(timeout)
+            .flat_map(|window| iter(coalesce(window)))
+    }
+}
+
+/// Watch `paths_to_watch` and produce a stream of [`DebouncedEvent`](DebouncedEvent), emitted once
+/// no change has happened for `timeout`.
+///
+/// The stream is created with file-level events and extended data, so that renames can be paired.
+/// See [`EventStream::into_debounced`](EventStream::into_debounced) for how events are classified.
+///
+/// # Errors
+/// Return error when there's any invalid path in `paths_to_watch`.
+pub fn create_debounced_watcher<P: AsRef<Path>>(
+    paths_to_watch: impl IntoIterator<Item = P>,
+    timeout: Duration,
+) -> io::Result<(impl Stream<Item = DebouncedEvent>, EventStreamHandler)> {
+    let (stream, handler) = create_event_stream(
+        paths_to_watch,
+        kFSEventStreamEventIdSinceNow,
+        Duration::ZERO,
+        kFSEventStreamCreateFlagFileEvents
+            | kFSEventStreamCreateFlagUseCFTypes
+            | kFSEventStreamCreateFlagUseExtendedData
+            | kFSEventStreamCreateFlagNoDefer,
+    )?;
+    Ok((stream.into_debounced(timeout), handler))
+}
+
+fn coalesce(window: Vec<Event>) -> Vec<DebouncedEvent> {
+    // Both halves of a rename carry `ITEM_RENAMED` and the inode of the item.
+    let mut sources: HashMap<u64, PathBuf> = HashMap::new();
+    let mut renames: HashMap<PathBuf, PathBuf> = HashMap::new();
+    let mut destinations: HashSet<PathBuf> = HashSet::new();
+    for event in &window {
+        let inode = match event.inode {
+            Some(inode) if event.flags.contains(StreamFlags::ITEM_RENAMED) => inode,
+            _ => continue,
+        };
+        match sources.remove(&inode) {
+            Some(from) if from != event.path => {
+                destinations.insert(event.path.clone());
+                renames.insert(from, event.path.clone());
+            }
+            _ => {
+                sources.insert(inode, event.path.clone());
+            }
+        }
+    }
+
+    let mut order = Vec::new();
+    let mut merged: HashMap<PathBuf, StreamFlags> = HashMap::new();
+    for event in window {
+        if destinations.contains(&event.path) {
+            continue;
+        }
+        match merged.entry(event.path) {
+            Entry::Occupied(mut entry) => *entry.get_mut() |= event.flags,
+            Entry::Vacant(entry) => {
+                order.push(entry.key().clone());
+                entry.insert(event.flags);
+            }
+        }
+    }
+
+    order
+        .into_iter()
+        .filter_map(|path| {
+            let flags = merged[&path];
+            if let Some(to) = renames.remove(&path) {
+                return Some(DebouncedEvent::Rename(path, to));
+            }
+            if flags.contains(StreamFlags::MUST_SCAN_SUBDIRS) {
+                return Some(DebouncedEvent::Rescan(path));
+            }
+            if fs::symlink_metadata(&path).is_err() {
+                let gone = flags.intersects(StreamFlags::ITEM_REMOVED | StreamFlags::ITEM_RENAMED)
+                    && !flags.contains(StreamFlags::ITEM_CREATED);
+                return gone.then_some(DebouncedEvent::Remove(path));
+            }
+            if flags.intersects(StreamFlags::ITEM_CREATED | StreamFlags::ITEM_RENAMED) {
+                Some(DebouncedEvent::Create(path))
+            } else if flags.is_content_change() {
+                Some(DebouncedEvent::Write(path))
+            } else if flags.is_metadata_change() {
+                Some(DebouncedEvent::Chmod(path))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
@@ -5,6 +5,7 @@
 #![allow(
     non_snake_case,
     non_upper_case_globals,
+    non_camel_case_types,
     clippy::unreadable_literal,
     clippy::declare_interior_mutable_const
 )]
@@ -13,24 +14,55 @@ use std::ffi::c_void;
 use std::io;
 use std::marker::{PhantomData, PhantomPinned};
 use std::os::raw::c_uint;
-use std::path::Path;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
 use core_foundation::array::{CFArray, CFArrayRef};
 use core_foundation::base::{
     kCFAllocatorDefault, Boolean, CFAllocatorCopyDescriptionCallBack, CFAllocatorRef,
-    CFAllocatorReleaseCallBack, CFAllocatorRetainCallBack, CFIndex, TCFType,
+    CFAllocatorReleaseCallBack, CFAllocatorRetainCallBack, CFIndex, CFRelease, CFTypeRef, TCFType,
 };
-use core_foundation::date::CFTimeInterval;
+use core_foundation::date::{CFAbsoluteTime, CFTimeInterval};
+use core_foundation::number::{CFNumber, CFNumberRef};
 use core_foundation::runloop::{CFRunLoop, CFRunLoopIsWaiting, CFRunLoopMode, CFRunLoopRef};
 use core_foundation::string::{CFString, CFStringRef};
-use core_foundation::url::{kCFURLPOSIXPathStyle, CFURL};
+use core_foundation::url::{
+    kCFURLPOSIXPathStyle, CFURLCopyFileSystemPath, CFURLCreateFromFileSystemRepresentation, CFURL,
+};
 use once_cell::unsync::Lazy;
 
+// `FSEvents` takes paths as `CFString`s. The URL is built from the raw bytes of the path, but a
+// `CFString` can't hold bytes which aren't valid UTF-8, so such paths are rejected rather than
+// watched under a different name.
 fn str_path_to_cfstring_ref(source: &Path) -> io::Result<CFString> {
-    CFURL::from_path(source, source.is_dir())
-        .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
-        .map(|path| path.absolute().get_file_system_path(kCFURLPOSIXPathStyle))
+    let bytes = source.as_os_str().as_bytes();
+    let url = unsafe {
+        CFURLCreateFromFileSystemRepresentation(
+            kCFAllocatorDefault,
+            bytes.as_ptr(),
+            bytes.len() as CFIndex,
+            Boolean::from(source.is_dir()),
+        )
+    };
+    if url.is_null() {
+        return Err(io::Error::from(io::ErrorKind::NotFound));
+    }
+    let url = unsafe { CFURL::wrap_under_create_rule(url) }.absolute();
+
+    // Bytes which aren't valid UTF-8 are percent-escaped in the URL, and either fail to be
+    // decoded, or are decoded into other characters.
+    let path = unsafe { CFURLCopyFileSystemPath(url.as_concrete_TypeRef(), kCFURLPOSIXPathStyle) };
+    let path = (!path.is_null()).then(|| unsafe { CFString::wrap_under_create_rule(path) });
+    path.filter(|_| source.to_str().is_some()).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "path isn't valid UTF-8 and can't be passed to FSEvents: {:?}",
+                source
+            ),
+        )
+    })
 }
 
 pub(crate) trait CFRunLoopExt {
@@ -77,6 +109,8 @@ pub type FSEventStreamCreateFlags = c_uint;
 
 pub type FSEventStreamEventFlags = c_uint;
 
+pub type dev_t = i32;
+
 pub const kFSEventStreamEventIdSinceNow: FSEventStreamEventId = 0xFFFFFFFFFFFFFFFF;
 
 pub const kFSEventStreamCreateFlagNone: FSEventStreamCreateFlags = 0x00000000;
@@ -87,6 +121,7 @@ pub const kFSEventStreamCreateFlagIgnoreSelf: FSEventStreamCreateFlags = 0x00000
 pub const kFSEventStreamCreateFlagFileEvents: FSEventStreamCreateFlags = 0x00000010;
 pub const kFSEventStreamCreateFlagMarkSelf: FSEventStreamCreateFlags = 0x00000020;
 pub const kFSEventStreamCreateFlagUseExtendedData: FSEventStreamCreateFlags = 0x00000040;
+pub const kFSEventStreamCreateFlagFullHistory: FSEventStreamCreateFlags = 0x00000080;
 
 pub const kFSEventStreamEventFlagNone: FSEventStreamEventFlags = 0x00000000;
 pub const kFSEventStreamEventFlagMustScanSubDirs: FSEventStreamEventFlags = 0x00000001;
@@ -168,13 +203,68 @@ impl SysFSEventStreamContext {
             copy_description: None,
         }
     }
+
+    /// Create a new [`SysFSEventStreamContext`](SysFSEventStreamContext) with optional `retain`
+    /// and `copy_description` callbacks.
+    ///
+    /// `FSEvents` calls `retain_callback` whenever it retains the context, and balances each call
+    /// with a `release_callback` call. If `retain_callback` is given, `release_callback` must
+    /// therefore only free the context when it's released for the last time.
+    ///
+    /// `copy_description_callback` is used to describe the context in debugging output, e.g. in
+    /// [`SysFSEventStream::show`](SysFSEventStream::show).
+    pub fn with_callbacks<T>(
+        ctx: T,
+        release_callback: CFAllocatorReleaseCallBack,
+        retain_callback: Option<CFAllocatorRetainCallBack>,
+        copy_description_callback: Option<CFAllocatorCopyDescriptionCallBack>,
+    ) -> Self {
+        Self {
+            retain: retain_callback,
+            copy_description: copy_description_callback,
+            ..Self::new(ctx, release_callback)
+        }
+    }
+
+    /// Free the context with its `release` callback.
+    ///
+    /// A stream takes ownership of its context once created, so this is only needed when no
+    /// stream could be created with it.
+    ///
+    /// # Safety
+    /// The context must not be owned by a stream, and must be released at most once.
+    pub unsafe fn release(&self) {
+        if let Some(release) = self.release {
+            release(self.info);
+        }
+    }
+}
+
+/// Convert paths into an array accepted by
+/// [`SysFSEventStream::new_with_cf_paths`](SysFSEventStream::new_with_cf_paths).
+///
+/// Paths are made absolute during conversion, so the array can be built once and reused across
+/// stream creations.
+///
+/// # Errors
+/// Return error when there's any invalid path in `paths`, including paths which aren't valid
+/// UTF-8, since `CFString`s can't hold them.
+pub fn paths_to_cf_array<P: AsRef<Path>>(
+    paths: impl IntoIterator<Item = P>,
+) -> io::Result<CFArray<CFString>> {
+    let cf_paths: Vec<_> = paths
+        .into_iter()
+        .map(|item| str_path_to_cfstring_ref(item.as_ref()))
+        .collect::<Result<_, _>>()?;
+    Ok(CFArray::from_CFTypes(&cf_paths))
 }
 
 impl SysFSEventStream {
     /// Create a new [`SysFSEventStream`](SysFSEventStream).
     ///
     /// # Errors
-    /// Return error when there's any invalid path in `paths_to_watch`.
+    /// Return error when there's any invalid path in `paths_to_watch`, or `FSEvents` fails to
+    /// create the stream.
     pub fn new<P: AsRef<Path>>(
         callback: FSEventStreamCallback,
         context: &SysFSEventStreamContext,
@@ -183,22 +273,160 @@ impl SysFSEventStream {
         latency: Duration,
         flags: FSEventStreamCreateFlags,
     ) -> io::Result<Self> {
-        let cf_paths: Vec<_> = paths_to_watch
+        Self::new_with_cf_paths(
+            callback,
+            context,
+            &paths_to_cf_array(paths_to_watch)?,
+            since_when,
+            latency,
+            flags,
+        )
+    }
+    /// Create a new [`SysFSEventStream`](SysFSEventStream) from paths already converted by
+    /// [`paths_to_cf_array`](paths_to_cf_array).
+    ///
+    /// # Errors
+    /// Return error when `FSEvents` fails to create the stream.
+    pub fn new_with_cf_paths(
+        callback: FSEventStreamCallback,
+        context: &SysFSEventStreamContext,
+        paths_to_watch: &CFArray<CFString>,
+        since_when: FSEventStreamEventId,
+        latency: Duration,
+        flags: FSEventStreamCreateFlags,
+    ) -> io::Result<Self> {
+        unsafe {
+            Self::create(
+                kCFAllocatorDefault,
+                callback,
+                context,
+                paths_to_watch,
+                since_when,
+                latency,
+                flags,
+            )
+        }
+    }
+    /// Create a new [`SysFSEventStream`](SysFSEventStream) whose memory is allocated by
+    /// `allocator`.
+    ///
+    /// # Errors
+    /// Return error when there's any invalid path in `paths_to_watch`, or `FSEvents` fails to
+    /// create the stream.
+    ///
+    /// # Safety
+    /// `allocator` must be a valid `CFAllocatorRef` which outlives the stream.
+    pub unsafe fn new_with_allocator<P: AsRef<Path>>(
+        allocator: CFAllocatorRef,
+        callback: FSEventStreamCallback,
+        context: &SysFSEventStreamContext,
+        paths_to_watch: impl IntoIterator<Item = P>,
+        since_when: FSEventStreamEventId,
+        latency: Duration,
+        flags: FSEventStreamCreateFlags,
+    ) -> io::Result<Self> {
+        Self::create(
+            allocator,
+            callback,
+            context,
+            &paths_to_cf_array(paths_to_watch)?,
+            since_when,
+            latency,
+            flags,
+        )
+    }
+    unsafe fn create(
+        allocator: CFAllocatorRef,
+        callback: FSEventStreamCallback,
+        context: &SysFSEventStreamContext,
+        paths_to_watch: &CFArray<CFString>,
+        since_when: FSEventStreamEventId,
+        latency: Duration,
+        flags: FSEventStreamCreateFlags,
+    ) -> io::Result<Self> {
+        Self::from_created(FSEventStreamCreate(
+            allocator,
+            callback,
+            context,
+            paths_to_watch.as_concrete_TypeRef(),
+            since_when,
+            latency.as_secs_f64() as CFTimeInterval,
+            flags,
+        ))
+    }
+    // `FSEventStreamCreate` returns null on failure, e.g. when given arguments it rejects.
+    fn from_created(stream_ref: SysFSEventStreamRef) -> io::Result<Self> {
+        if stream_ref.is_null() {
+            Err(io::Error::other("FSEvents failed to create the stream"))
+        } else {
+            Ok(Self(stream_ref))
+        }
+    }
+    /// Create a new [`SysFSEventStream`](SysFSEventStream) which watches paths relative to the root
+    /// of `device`.
+    ///
+    /// # Errors
+    /// Return error when there's any non UTF-8 path in `paths_to_watch_relative_to_device`, or
+    /// `FSEvents` fails to create the stream.
+    pub fn new_relative_to_device<P: AsRef<Path>>(
+        callback: FSEventStreamCallback,
+        context: &SysFSEventStreamContext,
+        device: dev_t,
+        paths_to_watch_relative_to_device: impl IntoIterator<Item = P>,
+        since_when: FSEventStreamEventId,
+        latency: Duration,
+        flags: FSEventStreamCreateFlags,
+    ) -> io::Result<Self> {
+        let cf_paths: Vec<_> = paths_to_watch_relative_to_device
             .into_iter()
-            .map(|item| str_path_to_cfstring_ref(item.as_ref()))
+            .map(|item| {
+                item.as_ref().to_str().map(CFString::new).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "path relative to device isn't valid UTF-8: {:?}",
+                            item.as_ref()
+                        ),
+                    )
+                })
+            })
             .collect::<Result<_, _>>()?;
-        let cf_path_array = CFArray::from_CFTypes(&*cf_paths);
-        Ok(Self(unsafe {
-            FSEventStreamCreate(
+        let cf_path_array = CFArray::from_CFTypes(&cf_paths);
+        Self::from_created(unsafe {
+            FSEventStreamCreateRelativeToDevice(
                 kCFAllocatorDefault,
                 callback,
                 context,
+                device,
                 cf_path_array.as_concrete_TypeRef(),
                 since_when,
                 latency.as_secs_f64() as CFTimeInterval,
                 flags,
             )
-        }))
+        })
+    }
+    /// Get the id of the last event delivered to the callback, or `since_when` if no event has been
+    /// delivered yet.
+    pub fn latest_event_id(&self) -> FSEventStreamEventId {
+        unsafe { FSEventStreamGetLatestEventId(self.0) }
+    }
+    /// Get the device being watched, or 0 if this stream isn't created relative to a device.
+    pub fn device_being_watched(&self) -> dev_t {
+        unsafe { FSEventStreamGetDeviceBeingWatched(self.0) }
+    }
+    /// Get the paths being watched, as held by `FSEvents`.
+    pub fn paths_being_watched(&self) -> Vec<PathBuf> {
+        let paths: CFArray<CFString> =
+            unsafe { CFArray::wrap_under_create_rule(FSEventStreamCopyPathsBeingWatched(self.0)) };
+        paths
+            .iter()
+            .map(|path| PathBuf::from(path.to_string()))
+            .collect()
+    }
+    /// Get a description of the stream, as printed by [`show`](Self::show).
+    pub fn description(&self) -> String {
+        unsafe { CFString::wrap_under_create_rule(FSEventStreamCopyDescription(self.0)) }
+            .to_string()
     }
     pub fn show(&mut self) {
         unsafe { FSEventStreamShow(self.0) }
@@ -220,9 +448,33 @@ impl SysFSEventStream {
     pub fn start(&mut self) -> bool {
         unsafe { FSEventStreamStart(self.0) != 0 }
     }
+    /// Set directories to be filtered from the stream.
+    ///
+    /// # Errors
+    /// Return error when there's any invalid path in `paths_to_exclude`.
+    pub fn set_exclusion_paths<P: AsRef<Path>>(
+        &mut self,
+        paths_to_exclude: impl IntoIterator<Item = P>,
+    ) -> io::Result<bool> {
+        let cf_paths: Vec<_> = paths_to_exclude
+            .into_iter()
+            .map(|item| str_path_to_cfstring_ref(item.as_ref()))
+            .collect::<Result<_, _>>()?;
+        let cf_path_array = CFArray::from_CFTypes(&cf_paths);
+        Ok(unsafe {
+            FSEventStreamSetExclusionPaths(self.0, cf_path_array.as_concrete_TypeRef()) != 0
+        })
+    }
     pub fn flush_sync(&mut self) {
         unsafe { FSEventStreamFlushSync(self.0) };
     }
+    /// Ask `FSEvents` to deliver pending events to the callback as soon as possible, without
+    /// waiting for them.
+    ///
+    /// Return the id of the last pending event, or 0 if there was none.
+    pub fn flush_async(&mut self) -> FSEventStreamEventId {
+        unsafe { FSEventStreamFlushAsync(self.0) }
+    }
     pub fn stop(&mut self) {
         unsafe { FSEventStreamStop(self.0) };
     }
@@ -249,6 +501,20 @@ extern "C" {
         flags: FSEventStreamCreateFlags,
     ) -> SysFSEventStreamRef;
 
+    fn FSEventStreamCreateRelativeToDevice(
+        allocator: CFAllocatorRef,
+        callback: FSEventStreamCallback,
+        context: *const SysFSEventStreamContext,
+        deviceToWatch: dev_t,
+        pathsToWatchRelativeToDevice: CFArrayRef,
+        sinceWhen: FSEventStreamEventId,
+        latency: CFTimeInterval,
+        flags: FSEventStreamCreateFlags,
+    ) -> SysFSEventStreamRef;
+
+    fn FSEventStreamGetDeviceBeingWatched(stream_ref: SysFSEventStreamRef) -> dev_t;
+    fn FSEventStreamCopyPathsBeingWatched(stream_ref: SysFSEventStreamRef) -> CFArrayRef;
+    fn FSEventStreamCopyDescription(stream_ref: SysFSEventStreamRef) -> CFStringRef;
     fn FSEventStreamShow(stream_ref: SysFSEventStreamRef);
     fn FSEventStreamScheduleWithRunLoop(
         stream_ref: SysFSEventStreamRef,
@@ -262,11 +528,48 @@ extern "C" {
         run_loop_mode: CFRunLoopMode,
     );
 
+    fn FSEventStreamSetExclusionPaths(
+        stream_ref: SysFSEventStreamRef,
+        paths_to_exclude: CFArrayRef,
+    ) -> Boolean;
     fn FSEventStreamStart(stream_ref: SysFSEventStreamRef) -> Boolean;
     fn FSEventStreamFlushSync(stream_ref: SysFSEventStreamRef);
+    fn FSEventStreamFlushAsync(stream_ref: SysFSEventStreamRef) -> FSEventStreamEventId;
+    fn FSEventStreamGetLatestEventId(stream_ref: SysFSEventStreamRef) -> FSEventStreamEventId;
     fn FSEventStreamStop(stream_ref: SysFSEventStreamRef);
     fn FSEventStreamInvalidate(stream_ref: SysFSEventStreamRef);
     fn FSEventStreamRelease(stream_ref: SysFSEventStreamRef);
 
     pub fn FSEventsGetCurrentEventId() -> FSEventStreamEventId;
+    pub fn FSEventsGetLastEventIdForDeviceBeforeTime(
+        dev: dev_t,
+        time: CFAbsoluteTime,
+    ) -> FSEventStreamEventId;
+    pub fn FSEventsCopyUUIDForDevice(dev: dev_t) -> CFTypeRef;
+
+    fn CFUUIDGetUUIDBytes(uuid: CFTypeRef) -> CFUUIDBytes;
+    fn CFNumberIsFloatType(number: CFNumberRef) -> Boolean;
+}
+
+#[repr(C)]
+struct CFUUIDBytes([u8; 16]);
+
+/// Get the UUID of the event history of `device`.
+///
+/// The UUID changes whenever the event history of the device is invalidated, e.g. when the
+/// `FSEvents` database is purged or the volume is erased. Return `None` if the device doesn't
+/// keep an event history.
+pub fn device_uuid(device: dev_t) -> Option<[u8; 16]> {
+    let uuid = unsafe { FSEventsCopyUUIDForDevice(device) };
+    if uuid.is_null() {
+        return None;
+    }
+    let bytes = unsafe { CFUUIDGetUUIDBytes(uuid) };
+    unsafe { CFRelease(uuid) };
+    Some(bytes.0)
+}
+
+/// Whether `number` is stored as a floating point type.
+pub(crate) fn cf_number_is_float(number: &CFNumber) -> bool {
+    unsafe { CFNumberIsFloatType(number.as_concrete_TypeRef()) != 0 }
 }
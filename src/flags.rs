@@ -109,3 +109,158 @@ impl Display for StreamFlags {
         write!(f, "")
     }
 }
+
+/// What happened to an item, derived from [`StreamFlags`](StreamFlags).
+///
+/// `FSEvents` coalesces changes, so an event may report several kinds at once, e.g. a file
+/// created and modified within the latency.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum EventKind {
+    /// The item has been created.
+    Created,
+    /// The item has been removed.
+    Removed,
+    /// The item has been renamed, either from or to this path.
+    Renamed,
+    /// The content of the item has been modified.
+    Modified,
+    /// The inode metadata (e.g. permissions or timestamps) or Finder info has been modified.
+    MetadataChanged,
+    /// The owner of the item has changed.
+    OwnerChanged,
+    /// The extended attributes of the item have been modified.
+    XattrChanged,
+    /// The item has been cloned.
+    Cloned,
+    /// Events have been coalesced or dropped, so the directory must be rescanned.
+    Rescan,
+}
+
+// Flags reporting a change of metadata only.
+const METADATA_CHANGE_FLAGS: StreamFlags = StreamFlags::from_bits_truncate(
+    StreamFlags::INODE_META_MOD.bits()
+        | StreamFlags::FINDER_INFO_MOD.bits()
+        | StreamFlags::ITEM_CHANGE_OWNER.bits()
+        | StreamFlags::ITEM_XATTR_MOD.bits(),
+);
+
+// Flags of marker events which aren't about any item.
+const SENTINEL_FLAGS: StreamFlags = StreamFlags::from_bits_truncate(
+    StreamFlags::HISTORY_DONE.bits() | StreamFlags::IDS_WRAPPED.bits(),
+);
+
+impl StreamFlags {
+    /// Convert from raw bits, keeping any bit which doesn't correspond to a known flag.
+    ///
+    /// Newer versions of macOS may introduce flags this crate doesn't name yet. Unlike
+    /// [`from_bits`](Self::from_bits) and [`from_bits_truncate`](Self::from_bits_truncate), this
+    /// preserves them, so that [`bits`](Self::bits) round-trips the exact value `FSEvents`
+    /// reported. Unknown bits are left out when formatting with `Display`.
+    #[must_use]
+    pub const fn from_bits_retain(bits: u32) -> Self {
+        // Safety: flags of bitflags 1.x are plain integers, and every operation is well-defined
+        // on unknown bits.
+        unsafe { Self::from_bits_unchecked(bits) }
+    }
+
+    /// Whether these flags mark a sentinel event, i.e. `HISTORY_DONE` or `IDS_WRAPPED` is set.
+    ///
+    /// Sentinel events don't report a change, and their path is meaningless, usually empty.
+    #[must_use]
+    pub const fn is_sentinel(self) -> bool {
+        self.intersects(SENTINEL_FLAGS)
+    }
+
+    /// Whether the data of the item has changed, i.e. `ITEM_MODIFIED` is set.
+    ///
+    /// Creation, removal and renaming aren't considered content changes on their own. Since
+    /// `FSEvents` coalesces changes, this may hold along with
+    /// [`is_metadata_change`](Self::is_metadata_change), in which case both happened.
+    #[must_use]
+    pub const fn is_content_change(self) -> bool {
+        self.contains(Self::ITEM_MODIFIED)
+    }
+
+    /// Whether metadata of the item has changed, i.e. any of `INODE_META_MOD`, `FINDER_INFO_MOD`,
+    /// `ITEM_CHANGE_OWNER` or `ITEM_XATTR_MOD` is set.
+    ///
+    /// An event with only metadata changed, e.g. by `chmod` or `xattr`, doesn't affect the data
+    /// of the item, so tools caching content digests can skip it if
+    /// [`is_content_change`](Self::is_content_change) doesn't hold.
+    #[must_use]
+    pub const fn is_metadata_change(self) -> bool {
+        self.intersects(METADATA_CHANGE_FLAGS)
+    }
+
+    /// Kinds of change reported by these flags, in the order of [`EventKind`](EventKind) variants.
+    #[must_use]
+    pub fn kinds(self) -> Vec<EventKind> {
+        [
+            (Self::ITEM_CREATED, EventKind::Created),
+            (Self::ITEM_REMOVED, EventKind::Removed),
+            (Self::ITEM_RENAMED, EventKind::Renamed),
+            (Self::ITEM_MODIFIED, EventKind::Modified),
+            (
+                Self::INODE_META_MOD | Self::FINDER_INFO_MOD,
+                EventKind::MetadataChanged,
+            ),
+            (Self::ITEM_CHANGE_OWNER, EventKind::OwnerChanged),
+            (Self::ITEM_XATTR_MOD, EventKind::XattrChanged),
+            (Self::ITEM_CLONED, EventKind::Cloned),
+            (Self::MUST_SCAN_SUBDIRS, EventKind::Rescan),
+        ]
+        .into_iter()
+        .filter(|(flags, _)| self.intersects(*flags))
+        .map(|(_, kind)| kind)
+        .collect()
+    }
+}
+
+#[cfg(feature = "notify")]
+impl StreamFlags {
+    /// Translate a [`notify`](https://github.com/notify-rs/notify) event kind into the flags
+    /// `FSEvents` reports for such a change, e.g. to compare events from both crates in tests.
+    ///
+    /// The mapping is lossy in both directions. `FSEvents` has flags with no `notify` counterpart,
+    /// like `ITEM_CLONED`, `FINDER_INFO_MOD` or the stream-level ones, which are never produced.
+    /// Conversely, access events and unspecified kinds yield no flag, and metadata changes which
+    /// aren't about the owner or extended attributes all map to `INODE_META_MOD`.
+    #[must_use]
+    pub fn from_notify_kind(kind: &notify::EventKind) -> Self {
+        use notify::event::{CreateKind, MetadataKind, ModifyKind, RemoveKind};
+        use notify::EventKind as NotifyKind;
+
+        match kind {
+            NotifyKind::Create(CreateKind::File) => Self::ITEM_CREATED | Self::IS_FILE,
+            NotifyKind::Create(CreateKind::Folder) => Self::ITEM_CREATED | Self::IS_DIR,
+            NotifyKind::Create(_) => Self::ITEM_CREATED,
+            NotifyKind::Modify(ModifyKind::Metadata(MetadataKind::Ownership)) => {
+                Self::ITEM_CHANGE_OWNER
+            }
+            NotifyKind::Modify(ModifyKind::Metadata(MetadataKind::Extended)) => {
+                Self::ITEM_XATTR_MOD
+            }
+            NotifyKind::Modify(ModifyKind::Metadata(_)) => Self::INODE_META_MOD,
+            NotifyKind::Modify(ModifyKind::Name(_)) => Self::ITEM_RENAMED,
+            NotifyKind::Modify(_) => Self::ITEM_MODIFIED,
+            NotifyKind::Remove(RemoveKind::File) => Self::ITEM_REMOVED | Self::IS_FILE,
+            NotifyKind::Remove(RemoveKind::Folder) => Self::ITEM_REMOVED | Self::IS_DIR,
+            NotifyKind::Remove(_) => Self::ITEM_REMOVED,
+            NotifyKind::Access(_) | NotifyKind::Any | NotifyKind::Other => Self::NONE,
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for StreamFlags {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u32(self.bits())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for StreamFlags {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        u32::deserialize(deserializer).map(Self::from_bits_retain)
+    }
+}
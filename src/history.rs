@@ -0,0 +1,479 @@
+//! Resume watching from a persisted position in the event history.
+//!
+//! `FSEvents` keeps a per-device history of events, so a stream can replay events which happened
+//! while the app wasn't running by passing a previously seen event id as `since_when`. Event ids
+//! are only meaningful as long as the history they come from is intact, which is tracked by the
+//! UUID of the device history.
+//!
+//! Event ids carry no timestamps. To watch for changes since a wall-clock time, either ask
+//! `FSEvents` with [`last_event_id_before`](last_event_id_before), or look up checkpoints recorded
+//! by an [`IdTimeline`](IdTimeline).
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter, LowerHex, UpperHex};
+use std::fs;
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures_core::Stream;
+use futures_util::future::ready;
+use futures_util::StreamExt;
+
+use crate::ffi::{
+    dev_t, device_uuid, kFSEventStreamCreateFlagFileEvents, kFSEventStreamEventIdSinceNow,
+    FSEventStreamCreateFlags, FSEventStreamEventId, FSEventsGetCurrentEventId,
+    FSEventsGetLastEventIdForDeviceBeforeTime,
+};
+use crate::flags::StreamFlags;
+use crate::stream::{Event, EventStream, EventStreamBuilder, EventStreamHandler};
+
+/// Seconds between the Unix epoch and the `CFAbsoluteTime` reference date, 2001-01-01.
+const CF_ABSOLUTE_TIME_INTERVAL_SINCE_1970: f64 = 978_307_200.0;
+
+/// Decide where to resume watching `device` from.
+///
+/// `last_id` and `last_uuid` are the last event id seen, and the history UUID of `device` at
+/// that time (see [`device_uuid`](crate::ffi::device_uuid)), as persisted by the caller.
+///
+/// Return `last_id` if the history of `device` is still the same, or
+/// `kFSEventStreamEventIdSinceNow` if it's been invalidated, in which case the caller must
+/// rescan the watched paths since events may have been missed.
+#[must_use]
+pub fn resume_point(
+    device: dev_t,
+    last_id: FSEventStreamEventId,
+    last_uuid: &[u8; 16],
+) -> FSEventStreamEventId {
+    resume_point_with(device_uuid(device).as_ref(), last_id, last_uuid)
+}
+
+pub(crate) fn resume_point_with(
+    current_uuid: Option<&[u8; 16]>,
+    last_id: FSEventStreamEventId,
+    last_uuid: &[u8; 16],
+) -> FSEventStreamEventId {
+    if current_uuid == Some(last_uuid) {
+        last_id
+    } else {
+        kFSEventStreamEventIdSinceNow
+    }
+}
+
+/// Whether `id` is the `kFSEventStreamEventIdSinceNow` sentinel rather than a real position in the
+/// event history.
+#[must_use]
+pub const fn is_since_now(id: FSEventStreamEventId) -> bool {
+    id == kFSEventStreamEventIdSinceNow
+}
+
+/// An event id which formats the `kFSEventStreamEventIdSinceNow` sentinel as `SinceNow`, so it
+/// can't be mistaken for a real position in logs.
+///
+/// Other ids are formatted in decimal by `Display`, and in hex by `LowerHex` and `UpperHex`. It
+/// converts from and into the raw `u64`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct EventId(pub FSEventStreamEventId);
+
+impl EventId {
+    /// The `kFSEventStreamEventIdSinceNow` sentinel.
+    pub const SINCE_NOW: Self = Self(kFSEventStreamEventIdSinceNow);
+
+    /// Whether this is the `kFSEventStreamEventIdSinceNow` sentinel.
+    #[must_use]
+    pub const fn is_since_now(self) -> bool {
+        is_since_now(self.0)
+    }
+}
+
+impl From<FSEventStreamEventId> for EventId {
+    fn from(id: FSEventStreamEventId) -> Self {
+        Self(id)
+    }
+}
+
+impl From<EventId> for FSEventStreamEventId {
+    fn from(id: EventId) -> Self {
+        id.0
+    }
+}
+
+impl Display for EventId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.is_since_now() {
+            f.write_str("SinceNow")
+        } else {
+            Display::fmt(&self.0, f)
+        }
+    }
+}
+
+impl LowerHex for EventId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.is_since_now() {
+            f.write_str("SinceNow")
+        } else {
+            LowerHex::fmt(&self.0, f)
+        }
+    }
+}
+
+impl UpperHex for EventId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.is_since_now() {
+            f.write_str("SinceNow")
+        } else {
+            UpperHex::fmt(&self.0, f)
+        }
+    }
+}
+
+/// Compare event id `a` to event id `b`, where `a` is observed after `b`.
+///
+/// Event ids restart from a small number when they wrap around, which is reported by an event
+/// flagged with [`IDS_WRAPPED`](crate::flags::StreamFlags::IDS_WRAPPED). Pass `wrapped` as
+/// `true` if such an event is observed between `b` and `a`, in which case `a` is newer regardless
+/// of its numeric value.
+///
+/// Return `None` if either id is `kFSEventStreamEventIdSinceNow`, since the sentinel isn't a
+/// position which can be compared.
+#[must_use]
+pub fn compare_ids(
+    a: FSEventStreamEventId,
+    b: FSEventStreamEventId,
+    wrapped: bool,
+) -> Option<Ordering> {
+    if is_since_now(a) || is_since_now(b) {
+        None
+    } else if wrapped {
+        Some(Ordering::Greater)
+    } else {
+        Some(a.cmp(&b))
+    }
+}
+
+/// Replay the events recorded for `paths_to_watch` with ids in `from_id..=to_id`, without
+/// watching for new events.
+///
+/// The returned stream completes once `FSEvents` reports that the history is exhausted with an
+/// event flagged with `HISTORY_DONE`, which isn't yielded. The backing `RunLoop` is stopped then,
+/// but [`EventStreamHandler::abort`](EventStreamHandler::abort) should still be called to join
+/// its thread, or to stop replaying early.
+///
+/// Events happening while replaying get ids greater than `to_id`, and are skipped.
+///
+/// # Errors
+/// Return error when `from_id` is `kFSEventStreamEventIdSinceNow`, `from_id` is greater than
+/// `to_id`, there's any invalid path in `paths_to_watch`, or the stream fails to be created.
+///
+/// # Panics
+/// Panic when the given flags combination is illegal.
+pub fn history_events<P: AsRef<Path>>(
+    paths_to_watch: impl IntoIterator<Item = P>,
+    from_id: FSEventStreamEventId,
+    to_id: FSEventStreamEventId,
+    flags: FSEventStreamCreateFlags,
+) -> io::Result<(impl Stream<Item = Event>, EventStreamHandler)> {
+    if is_since_now(from_id) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "kFSEventStreamEventIdSinceNow is not a position in the history",
+        ));
+    }
+    if from_id > to_id {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("empty id range: {} to {}", from_id, to_id),
+        ));
+    }
+
+    let (stream, handler) = EventStreamBuilder::new(paths_to_watch)
+        .since_when(from_id)
+        .latency(Duration::ZERO)
+        .flags(flags)
+        .build()?;
+    let events = stream
+        .take_until_event(|event| event.flags.contains(StreamFlags::HISTORY_DONE))
+        .filter(move |event| {
+            ready(!event.flags.contains(StreamFlags::HISTORY_DONE) && event.id <= to_id)
+        });
+    Ok((events, handler))
+}
+
+/// List the paths under `paths_to_watch` changed since event `since_id`, to catch up after being
+/// offline.
+///
+/// The history is replayed by a short-lived file-granular stream, and this blocks until
+/// `FSEvents` reports that it's exhausted. Each path is listed once, in the order it first
+/// changed, with the flags of all its events merged. Events flagged with `MUST_SCAN_SUBDIRS`
+/// are listed too, meaning that the whole directory must be rescanned.
+///
+/// # Errors
+/// Return error when `since_id` is `kFSEventStreamEventIdSinceNow`, there's any invalid path in
+/// `paths_to_watch`, the stream fails to be created, or it stops before the history is replayed.
+pub fn changed_paths_since<P: AsRef<Path>>(
+    paths_to_watch: impl IntoIterator<Item = P>,
+    since_id: FSEventStreamEventId,
+) -> io::Result<Vec<(PathBuf, StreamFlags)>> {
+    if is_since_now(since_id) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "kFSEventStreamEventIdSinceNow is not a position in the history",
+        ));
+    }
+
+    let mut changed: Vec<(PathBuf, StreamFlags)> = vec![];
+    let mut index: HashMap<PathBuf, usize> = HashMap::new();
+    for event in replay_since(paths_to_watch, since_id)? {
+        match index.get(&event.path) {
+            Some(&idx) => changed[idx].1 |= event.flags,
+            None => {
+                index.insert(event.path.clone(), changed.len());
+                changed.push((event.path, event.flags));
+            }
+        }
+    }
+    Ok(changed)
+}
+
+/// The net effect of the changes to a path over a period, as folded by
+/// [`fold_net_changes`](fold_net_changes).
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum NetChange {
+    /// The item didn't exist at the start of the period, and exists at the end of it.
+    Created,
+    /// The item existed at both ends of the period, and has changed in between.
+    Modified,
+    /// The item existed at the start of the period, and doesn't exist at the end of it.
+    Removed,
+    /// The item has been created and removed within the period, so there's no net change.
+    Transient,
+    /// Events have been coalesced or dropped, so the directory must be rescanned.
+    Rescan,
+}
+
+/// Fold events, in the order they have been delivered, into the net change of each path, e.g. to
+/// back up the changes made while the app wasn't running.
+///
+/// Whether the item existed at the start of the period is told by the first event about it: it
+/// didn't if it's flagged with `ITEM_CREATED`, or with `ITEM_RENAMED` and the item exists now,
+/// i.e. it's been moved in. Whether it exists at the end is checked on disk, since `FSEvents`
+/// coalesces flags and doesn't tell their order within an event. This is best effort: e.g. an
+/// item replaced within a single event is reported as created.
+///
+/// Sentinel events are skipped, and any path flagged with `MUST_SCAN_SUBDIRS` is reported as
+/// [`NetChange::Rescan`](NetChange::Rescan).
+#[must_use]
+pub fn fold_net_changes(events: impl IntoIterator<Item = Event>) -> HashMap<PathBuf, NetChange> {
+    // Flags of the first event about each path, and whether it needs a rescan.
+    let mut first_flags: HashMap<PathBuf, (StreamFlags, bool)> = HashMap::new();
+    for event in events {
+        if event.flags.is_sentinel() {
+            continue;
+        }
+        let rescan = event.flags.contains(StreamFlags::MUST_SCAN_SUBDIRS);
+        first_flags
+            .entry(event.path)
+            .or_insert((event.flags, false))
+            .1 |= rescan;
+    }
+
+    first_flags
+        .into_iter()
+        .map(|(path, (flags, rescan))| {
+            let exists = fs::symlink_metadata(&path).is_ok();
+            let change = if rescan {
+                NetChange::Rescan
+            } else if exists {
+                if flags.intersects(StreamFlags::ITEM_CREATED | StreamFlags::ITEM_RENAMED) {
+                    NetChange::Created
+                } else {
+                    NetChange::Modified
+                }
+            } else if flags.contains(StreamFlags::ITEM_CREATED) {
+                NetChange::Transient
+            } else {
+                NetChange::Removed
+            };
+            (path, change)
+        })
+        .collect()
+}
+
+/// Fold the changes under `paths_to_watch` since event `since_id` into the net change of each
+/// path, to catch up after being offline.
+///
+/// The history is replayed as by [`changed_paths_since`](changed_paths_since), and folded by
+/// [`fold_net_changes`](fold_net_changes).
+///
+/// # Errors
+/// Return error when `since_id` is `kFSEventStreamEventIdSinceNow`, there's any invalid path in
+/// `paths_to_watch`, the stream fails to be created, or it stops before the history is replayed.
+pub fn net_changes_since<P: AsRef<Path>>(
+    paths_to_watch: impl IntoIterator<Item = P>,
+    since_id: FSEventStreamEventId,
+) -> io::Result<HashMap<PathBuf, NetChange>> {
+    if is_since_now(since_id) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "kFSEventStreamEventIdSinceNow is not a position in the history",
+        ));
+    }
+    Ok(fold_net_changes(replay_since(paths_to_watch, since_id)?))
+}
+
+// Replay the history since `since_id` with a file-granular stream, blocking until it's exhausted.
+fn replay_since<P: AsRef<Path>>(
+    paths_to_watch: impl IntoIterator<Item = P>,
+    since_id: FSEventStreamEventId,
+) -> io::Result<Vec<Event>> {
+    let changes = Arc::new(Mutex::new(vec![]));
+    let (done_tx, done_rx) = channel();
+    let mut handler = EventStreamBuilder::new(paths_to_watch)
+        .since_when(since_id)
+        .latency(Duration::ZERO)
+        .flags(kFSEventStreamCreateFlagFileEvents)
+        .build_with_handler({
+            let changes = changes.clone();
+            let mut done = false;
+            move |events: Vec<Event>| {
+                let mut changes = changes.lock().unwrap_or_else(PoisonError::into_inner);
+                for event in events {
+                    if done {
+                        // Live events may follow the history in the same batch.
+                        break;
+                    }
+                    if event.flags.contains(StreamFlags::HISTORY_DONE) {
+                        done = true;
+                        let _ = done_tx.send(());
+                    } else {
+                        changes.push(event);
+                    }
+                }
+            }
+        })?;
+    // The handler may never see `HISTORY_DONE` if the backing thread dies, e.g. by a panic.
+    let done = handler
+        .abort_token()
+        .and_then(|token| token.wait_from_runloop(&done_rx));
+    handler.abort();
+    if done.is_none() {
+        return Err(io::Error::other(
+            "stream stopped before the history was replayed",
+        ));
+    }
+
+    let mut changes = changes.lock().unwrap_or_else(PoisonError::into_inner);
+    Ok(mem::take(&mut *changes))
+}
+
+/// Find the last event id recorded on `device` before `time`, to be used as `since_when` when
+/// watching for changes since a wall-clock time.
+///
+/// Event ids don't carry timestamps, so this is an approximation backed by `FSEvents`' own
+/// bookkeeping of the device history. Events which happened around `time` may or may not be
+/// replayed, so consumers should tolerate seeing a few changes from before `time`.
+///
+/// Return `kFSEventStreamEventIdSinceNow` if `time` is in the future, or `device` doesn't keep an
+/// event history, in which case no change before now can be replayed.
+#[must_use]
+pub fn last_event_id_before(device: dev_t, time: SystemTime) -> FSEventStreamEventId {
+    if time > SystemTime::now() || device_uuid(device).is_none() {
+        return kFSEventStreamEventIdSinceNow;
+    }
+    let secs_since_1970 = match time.duration_since(UNIX_EPOCH) {
+        Ok(elapsed) => elapsed.as_secs_f64(),
+        Err(e) => -e.duration().as_secs_f64(),
+    };
+    unsafe {
+        FSEventsGetLastEventIdForDeviceBeforeTime(
+            device,
+            secs_since_1970 - CF_ABSOLUTE_TIME_INTERVAL_SINCE_1970,
+        )
+    }
+}
+
+/// A map from wall-clock time to event ids, recorded as events are observed.
+///
+/// Each checkpoint pairs a time with the latest event id of the system at that time, so every
+/// event happening after a checkpoint is guaranteed to have a greater id. Checkpoints are taken by
+/// [`EventStream::record_timeline`](EventStream::record_timeline) whenever a batch is delivered,
+/// or manually with [`checkpoint`](IdTimeline::checkpoint).
+///
+/// Clones share the same checkpoints.
+#[derive(Debug, Clone, Default)]
+pub struct IdTimeline {
+    checkpoints: Arc<Mutex<BTreeMap<SystemTime, FSEventStreamEventId>>>,
+}
+
+impl IdTimeline {
+    /// Create an empty timeline.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record that `id` was the latest event id at `time`.
+    ///
+    /// # Panics
+    /// Panic when the lock is poisoned.
+    pub fn record(&self, time: SystemTime, id: FSEventStreamEventId) {
+        self.checkpoints.lock().expect("lock").insert(time, id);
+    }
+
+    /// Record the current event id of the system at the current time.
+    pub fn checkpoint(&self) {
+        self.record(SystemTime::now(), unsafe { FSEventsGetCurrentEventId() });
+    }
+
+    /// Find the event id of the last checkpoint at or before `time`, e.g. to watch for changes
+    /// since "10 minutes ago".
+    ///
+    /// Return `kFSEventStreamEventIdSinceNow` if there's no checkpoint that early.
+    ///
+    /// # Panics
+    /// Panic when the lock is poisoned.
+    #[must_use]
+    pub fn id_before(&self, time: SystemTime) -> FSEventStreamEventId {
+        self.checkpoints
+            .lock()
+            .expect("lock")
+            .range(..=time)
+            .next_back()
+            .map_or(kFSEventStreamEventIdSinceNow, |(_, id)| *id)
+    }
+
+    /// Number of recorded checkpoints.
+    ///
+    /// # Panics
+    /// Panic when the lock is poisoned.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.checkpoints.lock().expect("lock").len()
+    }
+
+    /// Whether no checkpoint has been recorded.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl EventStream {
+    /// Take a checkpoint in `timeline` whenever a batch of events is delivered, yielding batches
+    /// unchanged.
+    ///
+    /// Replayed events may be delivered long after they happened, so checkpoints use the current
+    /// event id of the system rather than the ids of delivered events.
+    pub fn record_timeline(self, timeline: IdTimeline) -> impl Stream<Item = Vec<Event>> {
+        self.map(move |events| {
+            timeline.checkpoint();
+            events
+        })
+    }
+}
@@ -0,0 +1,30 @@
+//! Dump events as [JSON Lines](https://jsonlines.org).
+
+use std::io;
+use std::io::Write;
+
+use futures_util::StreamExt;
+
+use crate::stream::EventStream;
+
+impl EventStream {
+    /// Write every event to `writer` as one JSON object per line, until the stream ends.
+    ///
+    /// `writer` is flushed after each batch, so events show up as soon as they're delivered even
+    /// if `writer` is buffered. Writes are blocking, so prefer writing to a file or stdout rather
+    /// than something that may stall the executor for long.
+    ///
+    /// # Errors
+    /// Return error when the writer fails. Events delivered after that are not written.
+    pub async fn write_jsonl<W: Write>(self, mut writer: W) -> io::Result<()> {
+        let mut batches = self.into_batches();
+        while let Some(batch) = batches.next().await {
+            for event in &batch.events {
+                serde_json::to_writer(&mut writer, event)?;
+                writer.write_all(b"\n")?;
+            }
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
@@ -8,6 +8,9 @@
 //!
 //! - Support directory-granular and file-granular events.
 //! - Retrieve related file inode with `kFSEventStreamCreateFlagUseExtendedData`.
+//! - Optional [`tracing`](https://github.com/tokio-rs/tracing) spans covering the worker thread with the `tracing` feature.
+//! - Serialize events with the `serde` feature, and dump them as JSON Lines with the `jsonl` feature.
+//! - Translate [`notify`](https://github.com/notify-rs/notify) event kinds into flags with the `notify` feature.
 //!
 //! ## Example
 //!
@@ -24,10 +27,10 @@
 //! use futures_util::StreamExt;
 //! # #[cfg(feature = "tokio")]
 //! # use tokio1 as tokio;
-//! # #[cfg(feature = "async-std")]
+//! # #[cfg(all(feature = "async-std", not(feature = "tokio")))]
 //! # use async_std1 as async_std;
 //! #
-//! # #[cfg(feature = "async-std")]
+//! # #[cfg(all(feature = "async-std", not(feature = "tokio")))]
 //! # #[async_std::main]
 //! # async fn main() {
 //! #     run().await;
@@ -60,10 +63,10 @@
 //! let mut stream = stream.into_flatten();
 //! while let Some(event) = stream.next().await {
 //!     println!(
-//!         "[{}] path: {:?}({}), flags: {} ({:x})",
+//!         "[{}] path: {:?}({:?}), flags: {} ({:x})",
 //!         event.id,
 //!         event.path,
-//!         event.inode.unwrap_or(-1),
+//!         event.inode,
 //!         event.flags,
 //!         event.raw_flags
 //!     );
@@ -79,6 +82,9 @@
 //! `tokio` support is enabled by default. To enable `async-std` support, disable default features and enable `async-std`
 //! feature.
 //!
+//! Both features may be enabled at once, e.g. when different dependencies ask for different runtimes. Streams then work
+//! on either runtime, and timers used by adaptors follow the runtime they're polled in.
+//!
 //! ## Acknowledgement
 //!
 //! Some code in this project is adapted from the following projects:
@@ -90,11 +96,26 @@
 //!
 //! This project is licensed under MIT License.
 
+#[cfg(not(any(feature = "tokio", feature = "async-std")))]
+compile_error!("either the `tokio` or the `async-std` feature must be enabled");
+
+mod adaptors;
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub mod bench;
+pub mod bookmark;
+pub mod debounce;
 pub mod stream;
 #[macro_use]
 pub mod ffi;
 pub mod flags;
+pub mod history;
+#[cfg(feature = "jsonl")]
+mod jsonl;
 mod observer;
+mod perform;
+pub mod pool;
+mod runtime;
 #[cfg(test)]
 mod tests;
 mod utils;
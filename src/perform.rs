@@ -0,0 +1,88 @@
+#![allow(clippy::module_name_repetitions)]
+
+use std::ffi::c_void;
+use std::panic::catch_unwind;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+use core_foundation::base::TCFType;
+use core_foundation::runloop::{
+    kCFRunLoopDefaultMode, CFRunLoop, CFRunLoopTimer, CFRunLoopTimerContext, CFRunLoopTimerRef,
+    CFRunLoopWakeUp,
+};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+pub struct PerformContextInfo {
+    job: Option<Job>,
+}
+
+impl_release_callback!(release_perform_ctx, const PerformContextInfo);
+
+extern "C" fn perform_callback(_timer: CFRunLoopTimerRef, info: *mut c_void) {
+    drop(catch_unwind(move || {
+        let ctx: &mut PerformContextInfo = unsafe { &mut *(info.cast()) };
+        if let Some(job) = ctx.job.take() {
+            job();
+        }
+    }));
+}
+
+/// Run `f` on the thread of `runloop` without waiting for it.
+///
+/// `f` is dropped without being run if the `RunLoop` never gets to it.
+pub fn perform(runloop: &CFRunLoop, f: impl FnOnce() + Send + 'static) {
+    let mut ctx = CFRunLoopTimerContext {
+        version: 0,
+        info: Box::into_raw(Box::new(PerformContextInfo {
+            job: Some(Box::new(f)),
+        }))
+        .cast(),
+        retain: None,
+        release: Some(release_perform_ctx),
+        copyDescription: None,
+    };
+    // A fire date in the past makes the timer fire as soon as the RunLoop gets to it.
+    let timer = CFRunLoopTimer::new(0., 0., 0, 0, perform_callback, &mut ctx);
+    runloop.add_timer(&timer, unsafe { kCFRunLoopDefaultMode });
+    unsafe { CFRunLoopWakeUp(runloop.as_concrete_TypeRef()) };
+}
+
+/// Run `f` on the thread of `runloop`, and return a receiver of its result.
+///
+/// Nothing is received if `f` panics, or the `RunLoop` never gets to it. Wait for the result with
+/// [`wait_result`](wait_result).
+pub fn perform_with_result<R: Send + 'static>(
+    runloop: &CFRunLoop,
+    f: impl FnOnce() -> R + Send + 'static,
+) -> Receiver<R> {
+    let (tx, rx) = channel();
+    perform(runloop, move || {
+        let _ = tx.send(f());
+    });
+    rx
+}
+
+/// How often a waiter checks whether the thread it waits for has exited.
+pub const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Wait for a result from the thread of a `RunLoop`, e.g. as scheduled by
+/// [`perform_with_result`](perform_with_result).
+///
+/// Return `None` if the sender is dropped, or once `exited` is set, i.e. the thread has exited
+/// without sending anything. A job scheduled on a `RunLoop` which is no longer run is never
+/// dropped, so the receiver alone would wait forever.
+pub fn wait_result<R>(rx: &Receiver<R>, exited: &AtomicBool) -> Option<R> {
+    loop {
+        match rx.recv_timeout(EXIT_POLL_INTERVAL) {
+            Ok(result) => return Some(result),
+            Err(RecvTimeoutError::Disconnected) => return None,
+            // The result may have been sent right before the thread exited.
+            Err(RecvTimeoutError::Timeout) if exited.load(Ordering::SeqCst) => {
+                return rx.try_recv().ok()
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+        }
+    }
+}
@@ -0,0 +1,392 @@
+//! Multiplex many watch registrations onto a few `FSEvents` streams.
+
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures_core::Stream;
+use futures_util::stream::{iter, select_all, StreamExt};
+use log::error;
+
+use crate::ffi::FSEventStreamCreateFlags;
+use crate::runtime::TrySendError;
+use crate::stream::{
+    Event, EventSender, EventStream, EventStreamBuilder, EventStreamHandler, RawBatch,
+};
+
+/// A pool which serves many independent watch registrations with as few `FSEvents` streams as
+/// possible.
+///
+/// Registrations sharing the same flags are consolidated into a single `FSEvents` stream running
+/// on one worker thread, and events are routed back to each registration by path prefix.
+/// Registrations with different flags are served by separate streams.
+pub struct WatcherPool {
+    latency: Duration,
+    groups: Vec<Group>,
+}
+
+struct Group {
+    flags: FSEventStreamCreateFlags,
+    routes: Arc<Mutex<Vec<Route>>>,
+    handler: EventStreamHandler,
+}
+
+struct Route {
+    roots: Vec<PathBuf>,
+    tx: EventSender,
+}
+
+impl Route {
+    fn matches(&self, event: &Event) -> bool {
+        self.roots.iter().any(|root| event.path.starts_with(root))
+    }
+}
+
+impl WatcherPool {
+    /// Create an empty pool whose streams are created with the given latency.
+    #[must_use]
+    pub const fn new(latency: Duration) -> Self {
+        Self {
+            latency,
+            groups: Vec::new(),
+        }
+    }
+
+    /// Watch the given paths with `flags`.
+    ///
+    /// Return an [`EventStream`](EventStream) which only yields events under `paths_to_watch`.
+    /// Dropping it stops routing events to it, but its paths are still watched by the shared
+    /// stream until the next registration with the same flags, or [`prune`](Self::prune).
+    ///
+    /// Batch sequence numbers count callback invocations of the shared stream, so they may skip
+    /// invocations without any event for this registration. They restart from 0 whenever the
+    /// shared stream is replaced.
+    ///
+    /// If a stream with the same flags is already running, it's replaced by a new one watching
+    /// the new paths as well. The new stream is started before the old one is stopped, so no
+    /// event is lost, but events happening during the switch may be delivered twice.
+    ///
+    /// # Errors
+    /// Return error when there's any path which can't be canonicalized, or the stream fails to
+    /// be created.
+    ///
+    /// # Panics
+    /// Panic when the given flags combination is illegal.
+    pub fn register<P: AsRef<Path>>(
+        &mut self,
+        paths_to_watch: impl IntoIterator<Item = P>,
+        flags: FSEventStreamCreateFlags,
+    ) -> io::Result<EventStream> {
+        // FSEvents reports canonicalized paths, so routing must be done against them too.
+        let roots = paths_to_watch
+            .into_iter()
+            .map(|path| path.as_ref().canonicalize())
+            .collect::<io::Result<Vec<_>>>()?;
+        let (tx, stream) = EventStream::channel(1024);
+        let stream = stream.with_roots(roots.clone());
+        let route = Route { roots, tx };
+
+        if let Some(group) = self.groups.iter_mut().find(|group| group.flags == flags) {
+            let mut routes = group.routes.lock().expect("routes to be unpoisoned");
+            routes.retain(|route| !route.tx.is_closed());
+            routes.push(route);
+            let roots = all_roots(&routes);
+            drop(routes);
+
+            match spawn(roots, self.latency, flags, group.routes.clone()) {
+                Ok(handler) => mem::replace(&mut group.handler, handler).abort(),
+                Err(e) => {
+                    group.routes.lock().expect("routes to be unpoisoned").pop();
+                    return Err(e);
+                }
+            }
+        } else {
+            let roots = route.roots.clone();
+            let routes = Arc::new(Mutex::new(vec![route]));
+            let handler = spawn(roots, self.latency, flags, routes.clone())?;
+            self.groups.push(Group {
+                flags,
+                routes,
+                handler,
+            });
+        }
+
+        Ok(stream)
+    }
+
+    /// Stop watching the paths of registrations whose [`EventStream`](EventStream) has been
+    /// dropped.
+    ///
+    /// Streams left with no registration are stopped, and the others are replaced by new ones
+    /// watching the remaining paths, the same way as in [`register`](Self::register).
+    ///
+    /// # Errors
+    /// Return error when a replacing stream fails to be created. The stream it should have
+    /// replaced is kept running.
+    pub fn prune(&mut self) -> io::Result<()> {
+        let mut result = Ok(());
+        self.groups.retain_mut(|group| {
+            let mut routes = group.routes.lock().expect("routes to be unpoisoned");
+            let count = routes.len();
+            routes.retain(|route| !route.tx.is_closed());
+            if routes.len() == count {
+                return true;
+            }
+            if routes.is_empty() {
+                drop(routes);
+                group.handler.abort();
+                return false;
+            }
+            let roots = all_roots(&routes);
+            drop(routes);
+
+            match spawn(roots, self.latency, group.flags, group.routes.clone()) {
+                Ok(handler) => mem::replace(&mut group.handler, handler).abort(),
+                Err(e) => result = Err(e),
+            }
+            true
+        });
+        result
+    }
+
+    /// Number of `FSEvents` streams currently running in this pool.
+    #[must_use]
+    pub fn stream_count(&self) -> usize {
+        self.groups.len()
+    }
+
+    /// Flush pending events of all streams in this pool.
+    ///
+    /// See [`EventStreamHandler::flush_sync`](EventStreamHandler::flush_sync).
+    pub fn flush_sync(&self) {
+        for group in &self.groups {
+            group.handler.flush_sync();
+        }
+    }
+
+    /// Stop all streams in this pool.
+    ///
+    /// All [`EventStream`](EventStream)s registered to this pool are then closed.
+    pub fn abort(&mut self) {
+        for mut group in self.groups.drain(..) {
+            group.handler.abort();
+        }
+    }
+}
+
+/// An [`Event`](Event) tagged with the group it comes from.
+///
+/// Produced by [`watch_groups`](watch_groups).
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct GroupedEvent {
+    /// Index of the group in the slice given to [`watch_groups`](watch_groups).
+    pub group: usize,
+    /// The original event.
+    pub event: Event,
+}
+
+/// Watch several groups of paths, each with its own flags, and merge their events into a single
+/// stream.
+///
+/// Unlike [`WatcherPool`](WatcherPool), groups are never consolidated even if they share flags,
+/// so every event is tagged with the index of the group which produced it. This makes it possible
+/// to watch a large tree with cheap directory-granular events, and only a few subtrees of it with
+/// file-granular events, while still telling them apart. Note that a path covered by several
+/// groups is reported once by each of them.
+///
+/// One stream is created per group, and the returned handlers are in the same order as `groups`.
+/// The merged stream ends once all of them are stopped.
+///
+/// # Errors
+/// Return error when any of the streams fails to be created. Streams already created are stopped.
+///
+/// # Panics
+/// Panic when any of the given flags combinations is illegal.
+pub fn watch_groups(
+    groups: &[(Vec<PathBuf>, FSEventStreamCreateFlags)],
+    latency: Duration,
+) -> io::Result<(impl Stream<Item = GroupedEvent>, Vec<EventStreamHandler>)> {
+    let mut streams = Vec::with_capacity(groups.len());
+    let mut handlers: Vec<EventStreamHandler> = Vec::with_capacity(groups.len());
+    for (paths, flags) in groups {
+        match EventStreamBuilder::new(paths)
+            .latency(latency)
+            .flags(*flags)
+            .build()
+        {
+            Ok((stream, handler)) => {
+                streams.push(stream.map_context(streams.len()).boxed());
+                handlers.push(handler);
+            }
+            Err(e) => {
+                for handler in &mut handlers {
+                    handler.abort();
+                }
+                return Err(e);
+            }
+        }
+    }
+
+    let stream = select_all(streams).flat_map(|(group, events)| {
+        iter(
+            events
+                .into_iter()
+                .map(move |event| GroupedEvent { group, event }),
+        )
+    });
+    Ok((stream, handlers))
+}
+
+/// Watch several groups of paths, each with its own latency, and merge their events into a
+/// single stream.
+///
+/// This makes it possible to watch a small "hot" directory with a low latency, and a large
+/// "cold" tree with a high one to save CPU. Groups sharing the same latency are consolidated
+/// into a single `FSEvents` stream, so one stream and worker thread is created per distinct
+/// latency. Events are routed back by path prefix, and tagged with the index of every group
+/// they're under, so a path covered by several groups is reported once per group. Events under
+/// none of the groups, e.g. sentinel events without a path, are dropped.
+///
+/// The returned handlers are in the order their latency first appears in `groups`. The merged
+/// stream ends once all of them are stopped.
+///
+/// # Errors
+/// Return error when there's any path which can't be canonicalized, or any of the streams fails
+/// to be created. Streams already created are stopped.
+///
+/// # Panics
+/// Panic when the given flags combination is illegal.
+pub fn watch_latency_groups(
+    groups: &[(Vec<PathBuf>, Duration)],
+    flags: FSEventStreamCreateFlags,
+) -> io::Result<(impl Stream<Item = GroupedEvent>, Vec<EventStreamHandler>)> {
+    // FSEvents reports canonicalized paths, so routing must be done against them too.
+    let groups = groups
+        .iter()
+        .map(|(paths, latency)| {
+            let roots = paths
+                .iter()
+                .map(|path| path.canonicalize())
+                .collect::<io::Result<Vec<_>>>()?;
+            Ok((roots, *latency))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    let mut latencies: Vec<Duration> = Vec::new();
+    for (_, latency) in &groups {
+        if !latencies.contains(latency) {
+            latencies.push(*latency);
+        }
+    }
+
+    let mut streams = Vec::with_capacity(latencies.len());
+    let mut handlers: Vec<EventStreamHandler> = Vec::with_capacity(latencies.len());
+    for latency in latencies {
+        let members: Vec<_> = groups
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, group_latency))| *group_latency == latency)
+            .map(|(group, (roots, _))| (group, roots.clone()))
+            .collect();
+        let mut roots: Vec<_> = members
+            .iter()
+            .flat_map(|(_, roots)| roots.clone())
+            .collect();
+        roots.sort();
+        roots.dedup();
+
+        match EventStreamBuilder::new(roots)
+            .latency(latency)
+            .flags(flags)
+            .build()
+        {
+            Ok((stream, handler)) => {
+                streams.push(
+                    stream
+                        .into_flatten()
+                        .flat_map(move |event| iter(tag_by_roots(&members, &event)))
+                        .boxed(),
+                );
+                handlers.push(handler);
+            }
+            Err(e) => {
+                for handler in &mut handlers {
+                    handler.abort();
+                }
+                return Err(e);
+            }
+        }
+    }
+
+    Ok((select_all(streams), handlers))
+}
+
+fn tag_by_roots(members: &[(usize, Vec<PathBuf>)], event: &Event) -> Vec<GroupedEvent> {
+    members
+        .iter()
+        .filter(|(_, roots)| roots.iter().any(|root| event.path.starts_with(root)))
+        .map(|(group, _)| GroupedEvent {
+            group: *group,
+            event: event.clone(),
+        })
+        .collect()
+}
+
+fn all_roots(routes: &[Route]) -> Vec<PathBuf> {
+    let mut roots: Vec<_> = routes
+        .iter()
+        .flat_map(|route| route.roots.clone())
+        .collect();
+    roots.sort();
+    roots.dedup();
+    roots
+}
+
+fn spawn(
+    roots: Vec<PathBuf>,
+    latency: Duration,
+    flags: FSEventStreamCreateFlags,
+    routes: Arc<Mutex<Vec<Route>>>,
+) -> io::Result<EventStreamHandler> {
+    EventStreamBuilder::new(roots)
+        .latency(latency)
+        .flags(flags)
+        .build_with_handler({
+            let mut seq = 0;
+            move |events: Vec<Event>| {
+                dispatch(&routes, seq, &events);
+                seq += 1;
+            }
+        })
+}
+
+fn dispatch(routes: &Mutex<Vec<Route>>, seq: u64, events: &[Event]) {
+    let mut routes = match routes.lock() {
+        Ok(routes) => routes,
+        Err(_) => {
+            error!("Pool routes are poisoned by a previous panic");
+            return;
+        }
+    };
+    routes.retain(|route| {
+        let batch: Vec<_> = events
+            .iter()
+            .filter(|event| route.matches(event))
+            .cloned()
+            .map(Ok)
+            .collect();
+        if batch.is_empty() {
+            return true;
+        }
+        match route.tx.try_send(RawBatch { seq, events: batch }) {
+            Ok(()) => true,
+            // The stream has been dropped, so there's no need to route events to it any more.
+            Err(TrySendError::Closed(_)) => false,
+            Err(e) => {
+                error!("Unable to send event from callback: {}", e);
+                true
+            }
+        }
+    });
+}
@@ -0,0 +1,111 @@
+//! Runtime-specific primitives used by events and stream adaptors.
+//!
+//! This is the only place where the `tokio` and `async-std` features are told apart. Both may be
+//! enabled at once, e.g. by different crates in the same dependency graph. Channels are then
+//! backed by `tokio`, whose channels work on any executor, while timers and file system
+//! operations use `tokio` within a `tokio` runtime and `async-std` elsewhere.
+
+use std::fs::Metadata;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+#[cfg(feature = "async-std")]
+use async_std1 as async_std;
+#[cfg(feature = "tokio")]
+use tokio1 as tokio;
+
+#[cfg(feature = "tokio")]
+pub type Sender<T> = tokio::sync::mpsc::Sender<T>;
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+pub type Sender<T> = async_std::channel::Sender<T>;
+
+#[cfg(feature = "tokio")]
+pub type Receiver<T> = tokio_stream::wrappers::ReceiverStream<T>;
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+pub type Receiver<T> = async_std::channel::Receiver<T>;
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+pub use async_std::channel::{SendError, TrySendError};
+#[cfg(feature = "tokio")]
+pub use tokio::sync::mpsc::error::{SendError, TrySendError};
+
+/// Create a bounded channel whose receiving half is a stream.
+#[cfg(feature = "tokio")]
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let (tx, rx) = tokio::sync::mpsc::channel(capacity);
+    (tx, Receiver::new(rx))
+}
+
+/// Create a bounded channel whose receiving half is a stream.
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    async_std::channel::bounded(capacity)
+}
+
+/// Send `value` through `tx`, blocking the current thread while the channel is full.
+///
+/// Must not be called from an async context.
+#[cfg(feature = "tokio")]
+pub fn blocking_send<T>(tx: &Sender<T>, value: T) -> Result<(), SendError<T>> {
+    tx.blocking_send(value)
+}
+
+/// Send `value` through `tx`, blocking the current thread while the channel is full.
+///
+/// Must not be called from an async context.
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+pub fn blocking_send<T>(tx: &Sender<T>, value: T) -> Result<(), SendError<T>> {
+    async_std::task::block_on(tx.send(value))
+}
+
+/// Wait until `duration` has elapsed, using the timer of the active runtime.
+#[cfg(all(feature = "tokio", not(feature = "async-std")))]
+pub async fn sleep(duration: Duration) {
+    tokio::time::sleep(duration).await;
+}
+
+/// Wait until `duration` has elapsed, using the timer of the active runtime.
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+pub async fn sleep(duration: Duration) {
+    async_std::task::sleep(duration).await;
+}
+
+/// Wait until `duration` has elapsed, using the timer of the active runtime.
+#[cfg(all(feature = "tokio", feature = "async-std"))]
+pub async fn sleep(duration: Duration) {
+    if in_tokio() {
+        tokio::time::sleep(duration).await;
+    } else {
+        async_std::task::sleep(duration).await;
+    }
+}
+
+/// Query the metadata of `path` without blocking the executor.
+#[cfg(all(feature = "tokio", not(feature = "async-std")))]
+pub async fn metadata(path: &Path) -> io::Result<Metadata> {
+    tokio::fs::metadata(path).await
+}
+
+/// Query the metadata of `path` without blocking the executor.
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+pub async fn metadata(path: &Path) -> io::Result<Metadata> {
+    async_std::fs::metadata(path).await
+}
+
+/// Query the metadata of `path` without blocking the executor.
+#[cfg(all(feature = "tokio", feature = "async-std"))]
+pub async fn metadata(path: &Path) -> io::Result<Metadata> {
+    if in_tokio() {
+        tokio::fs::metadata(path).await
+    } else {
+        async_std::fs::metadata(path).await
+    }
+}
+
+/// Whether we're polled within a tokio runtime, whose timers and blocking pool are unavailable
+/// elsewhere.
+#[cfg(all(feature = "tokio", feature = "async-std"))]
+pub fn in_tokio() -> bool {
+    tokio::runtime::Handle::try_current().is_ok()
+}
@@ -6,167 +6,2174 @@
     clippy::module_name_repetitions
 )]
 
-use std::ffi::{c_void, CStr, OsStr};
-use std::fmt::{Display, Formatter};
+use std::any::Any;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, VecDeque};
+use std::error::Error;
+use std::ffi::{c_void, CStr, OsStr, OsString};
+use std::fmt::{Debug, Display, Formatter};
+use std::future::Future;
 use std::io;
 use std::os::raw::c_char;
-use std::os::unix::ffi::OsStrExt;
-use std::panic::catch_unwind;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::panic::{catch_unwind, AssertUnwindSafe};
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
-use std::sync::mpsc::channel;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Mutex, PoisonError};
 use std::task::{Context, Poll};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
-#[cfg(feature = "async-std")]
-use async_std1 as async_std;
 use core_foundation::array::CFArray;
-use core_foundation::base::{CFIndex, FromVoid};
+use core_foundation::base::{Boolean, CFIndex, CFRange, CFType, CFTypeRef, FromVoid, TCFType};
 use core_foundation::dictionary::CFDictionary;
 use core_foundation::number::CFNumber;
 use core_foundation::runloop::{kCFRunLoopBeforeWaiting, kCFRunLoopDefaultMode, CFRunLoop};
-use core_foundation::string::CFString;
+use core_foundation::string::{
+    kCFStringEncodingUTF8, CFString, CFStringGetBytes, CFStringGetCStringPtr, CFStringRef,
+};
 use futures_core::Stream;
+use futures_util::future::{select, Either, FutureExt};
 use futures_util::stream::{iter, StreamExt};
-use log::{debug, error};
-#[cfg(feature = "tokio")]
-use tokio1 as tokio;
-#[cfg(feature = "tokio")]
-use tokio_stream::wrappers::ReceiverStream;
+use log::{debug, error, log, Level};
+use once_cell::sync::OnceCell;
+
+use crate::ffi::{
+    cf_number_is_float, dev_t, kFSEventStreamCreateFlagFileEvents,
+    kFSEventStreamCreateFlagFullHistory, kFSEventStreamCreateFlagIgnoreSelf,
+    kFSEventStreamCreateFlagNoDefer, kFSEventStreamCreateFlagNone,
+    kFSEventStreamCreateFlagUseCFTypes, kFSEventStreamCreateFlagUseExtendedData,
+    kFSEventStreamEventExtendedDataPathKey, kFSEventStreamEventExtendedFileIDKey,
+    kFSEventStreamEventIdSinceNow, paths_to_cf_array, CFRunLoopExt, FSEventStreamCreateFlags,
+    FSEventStreamEventFlags, FSEventStreamEventId, SysFSEventStream, SysFSEventStreamContext,
+    SysFSEventStreamRef,
+};
+pub use crate::flags::{EventKind, StreamFlags};
+use crate::history::EventId;
+use crate::impl_release_callback;
+use crate::observer::create_oneshot_observer;
+use crate::perform::{perform, perform_with_result, wait_result, EXIT_POLL_INTERVAL};
+use crate::runtime::{self, TrySendError};
+use crate::utils::FlagsExt;
+
+#[cfg(test)]
+pub(crate) static TEST_RUNNING_RUNLOOP_COUNT: std::sync::atomic::AtomicUsize =
+    std::sync::atomic::AtomicUsize::new(0);
+
+/// An owned permission to stop an [`EventStream`](EventStream) and terminate its backing `RunLoop`.
+///
+/// A `EventStreamHandler` *detaches* the associated Stream and `RunLoop` when it is dropped, which
+/// means that there is no longer any handle to them and no way to `abort` them.
+///
+/// Dropping the handler without first calling [`abort`](EventStreamHandler::abort) is not
+/// recommended because this leaves a spawned thread behind and causes memory leaks.
+pub struct EventStreamHandler {
+    worker: Option<Worker>,
+    flags: FSEventStreamCreateFlags,
+}
+
+struct Worker {
+    token: AbortToken,
+    thread_handle: thread::JoinHandle<()>,
+    diagnostics: Arc<Diagnostics>,
+    // Taken and released by the worker thread once the RunLoop stops.
+    stream: Arc<Mutex<Option<SysFSEventStream>>>,
+    // Kept to recreate the stream with a different configuration.
+    builder: EventStreamBuilder,
+    // Taken by the worker thread once it exits, so that the channel isn't kept open by the
+    // handler.
+    sink: Arc<Mutex<Option<EventSink>>>,
+}
+
+// Safety:
+// - According to the Apple documentation, it's safe to move `CFRef`s across threads.
+//   https://developer.apple.com/library/archive/documentation/Cocoa/Conceptual/Multithreading/ThreadSafetySummary/ThreadSafetySummary.html
+unsafe impl Send for EventStreamHandler {}
+
+impl EventStreamHandler {
+    /// Stop an [`EventStream`](EventStream), terminate its backing `RunLoop` and wait for the
+    /// backing thread to shut down.
+    ///
+    /// Calling this method multiple times has no extra effect and won't cause any panic, error,
+    /// or undefined behavior.
+    ///
+    /// The [`EventStream`](EventStream) may be dropped before or after this is called:
+    /// - If the stream is dropped first, batches delivered meanwhile are discarded. A callback
+    ///   blocked on a full stream with [`DeliveryPolicy::BlockOnFull`](DeliveryPolicy::BlockOnFull)
+    ///   is released, so this returns as usual.
+    /// - If this is called first, the `RunLoop` stops once the current callback returns. The
+    ///   channel is closed only after the backing thread has exited, so the stream yields the
+    ///   batches buffered so far, then ends, and never yields anything after that.
+    pub fn abort(&mut self) {
+        if let Some(Worker {
+            token,
+            thread_handle,
+            ..
+        }) = self.worker.take()
+        {
+            token.abort();
+
+            // Wait for the thread to shut down, unless this is the thread, which then exits once
+            // the current callback returns.
+            if !token.is_runloop_thread() {
+                thread_handle.join().expect("thread to shut down");
+            }
+        }
+    }
+
+    /// Paths watched by the stream, as given to the builder and [`add_path`](Self::add_path).
+    ///
+    /// Return an empty slice once the stream has been aborted.
+    #[must_use]
+    pub fn paths(&self) -> &[PathBuf] {
+        match &self.worker {
+            Some(worker) => &worker.builder.paths_to_watch,
+            None => &[],
+        }
+    }
+
+    /// Sizes of the batches recently delivered by `FSEvents`, to react to sustained large batches,
+    /// e.g. by switching to a directory-granular stream.
+    ///
+    /// Sizes are counted before events are filtered, and reset by [`add_path`](Self::add_path).
+    /// Return all zeros once the stream has been aborted.
+    #[must_use]
+    pub fn stats(&self) -> HandlerStats {
+        self.worker
+            .as_ref()
+            .map_or_else(HandlerStats::default, |worker| worker.diagnostics.stats())
+    }
+
+    /// Time between the start of the stream and the first batch delivered by `FSEvents`, to tell
+    /// the latency and coalescing of `FSEvents` apart from issues in the setup.
+    ///
+    /// The batch is counted before events are filtered. Return `None` until the first batch
+    /// arrives, and once the stream has been aborted. Reset by [`add_path`](Self::add_path).
+    #[must_use]
+    pub fn time_to_first_event(&self) -> Option<Duration> {
+        self.worker
+            .as_ref()
+            .and_then(|worker| worker.diagnostics.time_to_first_event())
+    }
+
+    /// Whether events carry extended data, i.e. the stream is created with both
+    /// `kFSEventStreamCreateFlagUseCFTypes` and `kFSEventStreamCreateFlagUseExtendedData`.
+    ///
+    /// Only then can [`Event::inode`](Event::inode) be `Some`, and only for file-granular streams.
+    #[must_use]
+    pub fn has_extended_data(&self) -> bool {
+        self.uses_cf_types() && self.flags.contains(kFSEventStreamCreateFlagUseExtendedData)
+    }
+
+    /// Whether the stream is created with `kFSEventStreamCreateFlagUseCFTypes`.
+    #[must_use]
+    pub fn uses_cf_types(&self) -> bool {
+        self.flags.contains(kFSEventStreamCreateFlagUseCFTypes)
+    }
+
+    /// Watch `path` in addition to the paths being watched.
+    ///
+    /// `FSEvents` streams can't be changed once created, so a new stream watching all the paths
+    /// is created, resuming from the last event delivered by the current one, which is then
+    /// stopped. Events keep being delivered to the same [`EventStream`](EventStream) or
+    /// [`EventHandler`](EventHandler). Note that:
+    /// - Events happening while both streams are running may be delivered twice.
+    /// - Batch sequence numbers restart from 0, and a batch flagged with `HISTORY_DONE` is
+    ///   delivered once the new stream catches up.
+    /// - [`EventStream::roots`](EventStream::roots) isn't updated.
+    /// - [`stats`](Self::stats) and [`time_to_first_event`](Self::time_to_first_event) start over.
+    ///
+    /// # Errors
+    /// Return error when the stream has been aborted, its backing thread has exited, `path` is
+    /// invalid, or the new stream fails to be created. The current stream is kept running if so.
+    pub fn add_path(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        let worker = self.worker.as_ref().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotConnected, "stream has been aborted")
+        })?;
+
+        let mut builder = worker.builder.clone();
+        builder.paths_to_watch.push(path.as_ref().to_path_buf());
+        builder.prepared_paths = None;
+        if let Some(latest_id) = worker
+            .stream
+            .lock()
+            .expect("stream lock not to be poisoned")
+            .as_ref()
+            .map(SysFSEventStream::latest_event_id)
+        {
+            builder.since_when = latest_id;
+        }
+
+        let sink = worker
+            .sink
+            .lock()
+            .expect("sink lock not to be poisoned")
+            .clone()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "stream has exited"))?;
+        let handler = builder.spawn(sink)?;
+        std::mem::replace(self, handler).abort();
+        Ok(())
+    }
+
+    /// Check whether the backing thread is still alive.
+    ///
+    /// Return `false` once the stream has been aborted, or the thread has exited unexpectedly,
+    /// e.g. because of a panic. The [`EventStream`](EventStream) yields no more event after that.
+    #[must_use]
+    pub fn is_running(&self) -> bool {
+        self.worker
+            .as_ref()
+            .is_some_and(|worker| !worker.thread_handle.is_finished())
+    }
+
+    /// Check whether the backing `RunLoop` is waiting for events, i.e. idle.
+    ///
+    /// Return `false` if the `RunLoop` is busy dispatching events, or the stream has been
+    /// aborted.
+    #[must_use]
+    pub fn is_runloop_waiting(&self) -> bool {
+        self.worker.as_ref().is_some_and(|worker| {
+            worker
+                .token
+                .with_running_runloop(CFRunLoopExt::is_waiting)
+                .unwrap_or(false)
+        })
+    }
+
+    /// Block until the backing thread has entered its `RunLoop`, i.e. the stream is live.
+    ///
+    /// The stream is started before the handler is returned, but the `RunLoop` which dispatches
+    /// its events may not be running yet. This is useful e.g. to synchronize tests without
+    /// sleeping. Once the `RunLoop` is running, this only waits for the callback in progress, if
+    /// any. Called from the `RunLoop` thread, e.g. in an [`EventHandler`](EventHandler), it
+    /// returns right away.
+    ///
+    /// Return `false` if the stream has been aborted, or the backing thread has exited.
+    pub fn wait_ready(&self) -> bool {
+        self.worker
+            .as_ref()
+            .is_some_and(|worker| worker.token.perform_sync(|| ()).is_some())
+    }
+
+    /// Wait until the backing thread has entered its `RunLoop`, without blocking the current
+    /// thread.
+    ///
+    /// See [`wait_ready`](Self::wait_ready) for details. The returned future doesn't borrow the
+    /// handler.
+    pub fn wait_ready_async(&self) -> impl Future<Output = bool> + Send + 'static {
+        let (tx, mut rx) = runtime::channel(1);
+        let token = self.worker.as_ref().and_then(|worker| {
+            worker
+                .token
+                .with_running_runloop(|runloop| {
+                    perform(runloop, move || {
+                        let _ = tx.try_send(());
+                    });
+                })
+                .map(|()| worker.token.clone())
+        });
+        async move {
+            let Some(token) = token else {
+                return false;
+            };
+            // The job is never dropped if the thread exits without running it, so check for that
+            // from time to time.
+            loop {
+                match select(rx.next(), Box::pin(runtime::sleep(EXIT_POLL_INTERVAL))).await {
+                    Either::Left((ready, _)) => return ready.is_some(),
+                    Either::Right(((), _)) if token.has_exited() => {
+                        return rx.next().now_or_never().flatten().is_some();
+                    }
+                    Either::Right(_) => {}
+                }
+            }
+        }
+    }
+
+    /// Flush events which have occurred but haven't been delivered yet, e.g. because of the
+    /// latency, and wait until the callback has handled them.
+    ///
+    /// Once this returns, these events are in the [`EventStream`](EventStream) buffer, or have
+    /// been passed to the [`EventHandler`](EventHandler). This is useful to collect events
+    /// deterministically, e.g. right before aborting the stream. Do nothing if the stream has
+    /// been aborted, or the backing thread has exited.
+    ///
+    /// Events can't be delivered while a callback is in progress, so when called from the
+    /// `RunLoop` thread, e.g. in an [`EventHandler`](EventHandler), they're flushed
+    /// asynchronously instead, and delivered once the current callback returns.
+    pub fn flush_sync(&self) {
+        if let Some(worker) = &self.worker {
+            let stream = worker.stream.clone();
+            let on_runloop_thread = worker.token.is_runloop_thread();
+            worker.token.perform_sync(move || {
+                if let Some(stream) = stream
+                    .lock()
+                    .expect("stream lock not to be poisoned")
+                    .as_mut()
+                {
+                    if on_runloop_thread {
+                        stream.flush_async();
+                    } else {
+                        stream.flush_sync();
+                    }
+                }
+            });
+        }
+    }
+
+    /// Get an [`AbortToken`](AbortToken) which can stop the stream from elsewhere.
+    ///
+    /// Return `None` if the stream has been aborted by this handler.
+    #[must_use]
+    pub fn abort_token(&self) -> Option<AbortToken> {
+        self.worker.as_ref().map(|worker| worker.token.clone())
+    }
+
+    /// Get the device being watched if the stream is created relative to a device.
+    ///
+    /// Return `None` if the stream watches absolute paths, or has been aborted.
+    #[must_use]
+    pub fn device_being_watched(&self) -> Option<dev_t> {
+        self.worker
+            .as_ref()
+            .and_then(|worker| worker.stream.lock().ok())
+            .and_then(|stream| stream.as_ref().map(SysFSEventStream::device_being_watched))
+            .filter(|device| *device != 0)
+    }
+
+    /// Describe the active configuration of the stream, so that monitoring code can check it.
+    ///
+    /// `FSEvents` doesn't expose the size of its kernel buffers or how it coalesces events, so this
+    /// is limited to what the stream has been created with, and its current state.
+    ///
+    /// Return `None` once the stream has been aborted.
+    #[must_use]
+    pub fn describe(&self) -> Option<StreamDescription> {
+        let worker = self.worker.as_ref()?;
+        let stream = worker.stream.lock().unwrap_or_else(PoisonError::into_inner);
+        let stream = stream.as_ref()?;
+        let device = stream.device_being_watched();
+        Some(StreamDescription {
+            paths: stream.paths_being_watched(),
+            flags: self.flags,
+            latency: worker.builder.latency,
+            device: (device != 0).then_some(device),
+            latest_event_id: stream.latest_event_id(),
+            running: self.is_running(),
+            raw: stream.description(),
+        })
+    }
+
+    /// Set directories to be filtered from the [`EventStream`](EventStream).
+    ///
+    /// This is far more efficient than filtering events in userspace, because the OS stops
+    /// reporting events under these directories at all. Each call replaces the previous set.
+    ///
+    /// Return `false` if the stream has been aborted, the backing thread has exited, more than
+    /// [`MAX_EXCLUSION_PATHS`](MAX_EXCLUSION_PATHS) paths are given, or `FSEvents` rejects them.
+    ///
+    /// The paths are set on the `RunLoop` thread, so this waits for the callback in progress, if
+    /// any. It may be called from the `RunLoop` thread itself, e.g. in an
+    /// [`EventHandler`](EventHandler).
+    ///
+    /// # Note
+    /// `FSEventStreamSetExclusionPaths` is only guaranteed to be effective when called before the
+    /// stream is started. The stream is already running when its handler is returned, so events
+    /// generated before this call, or shortly after it, may still be delivered.
+    pub fn set_exclusion_paths(&self, paths: &[PathBuf]) -> bool {
+        if paths.len() > MAX_EXCLUSION_PATHS {
+            return false;
+        }
+        self.worker.as_ref().is_some_and(|worker| {
+            let stream = worker.stream.clone();
+            let paths = paths.to_vec();
+            worker
+                .token
+                .perform_sync(move || {
+                    stream.lock().is_ok_and(|mut stream| {
+                        stream.as_mut().is_some_and(|stream| {
+                            stream.set_exclusion_paths(&paths).unwrap_or(false)
+                        })
+                    })
+                })
+                .unwrap_or(false)
+        })
+    }
+}
+
+/// A cheap, clonable permission to stop an [`EventStream`](EventStream).
+///
+/// Unlike [`EventStreamHandler`](EventStreamHandler), a token only signals the `RunLoop` to stop,
+/// and doesn't wait for the backing thread to shut down. The [`EventStream`](EventStream) ends
+/// once the thread has exited, even if the handler is still alive. The handler is still
+/// responsible for joining the thread, which then completes promptly.
+///
+/// Get one by [`EventStreamHandler::abort_token`](EventStreamHandler::abort_token).
+#[derive(Clone)]
+pub struct AbortToken {
+    inner: Arc<AbortTokenInner>,
+}
+
+struct AbortTokenInner {
+    runloop: CFRunLoop,
+    // The thread running `runloop`, which mustn't wait for itself.
+    thread: thread::ThreadId,
+    aborted: Mutex<bool>,
+    // Set once the thread has exited, even by panicking, so that nobody waits for it forever.
+    exited: Arc<AtomicBool>,
+}
+
+// Safety:
+// - According to the Apple documentation, it's safe to move `CFRef`s across threads.
+//   https://developer.apple.com/library/archive/documentation/Cocoa/Conceptual/Multithreading/ThreadSafetySummary/ThreadSafetySummary.html
+// - `CFRunLoopStop` and `CFRunLoopIsWaiting` can be called from any thread.
+unsafe impl Send for AbortTokenInner {}
+unsafe impl Sync for AbortTokenInner {}
+
+impl AbortToken {
+    fn new(runloop: CFRunLoop, thread: thread::ThreadId, exited: Arc<AtomicBool>) -> Self {
+        Self {
+            inner: Arc::new(AbortTokenInner {
+                runloop,
+                thread,
+                aborted: Mutex::new(false),
+                exited,
+            }),
+        }
+    }
+
+    /// Stop the [`EventStream`](EventStream) and terminate its backing `RunLoop`.
+    ///
+    /// Calling this method multiple times, or on multiple clones of the same token, has no extra
+    /// effect.
+    ///
+    /// It may be called from the `RunLoop` thread, e.g. in an [`EventHandler`](EventHandler), in
+    /// which case the `RunLoop` stops once the current callback returns.
+    pub fn abort(&self) {
+        {
+            let mut aborted = self
+                .inner
+                .aborted
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            if *aborted {
+                return;
+            }
+            // Mark the stream first, so that no more job is scheduled on the RunLoop, and the lock
+            // isn't held while waiting for it.
+            *aborted = true;
+        }
+
+        let runloop = &self.inner.runloop;
+        if self.is_runloop_thread() {
+            // The RunLoop is running this very call, so stopping it takes effect right away.
+            runloop.stop();
+            return;
+        }
+
+        let (tx, rx) = channel();
+        let observer = create_oneshot_observer(kCFRunLoopBeforeWaiting, tx);
+        runloop.add_observer(&observer, unsafe { kCFRunLoopDefaultMode });
+
+        if !runloop.is_waiting() {
+            // Wait the RunLoop to enter Waiting state. There's nothing to stop if the thread has
+            // exited meanwhile.
+            wait_result(&rx, &self.inner.exited);
+        }
+
+        runloop.remove_observer(&observer, unsafe { kCFRunLoopDefaultMode });
+        runloop.stop();
+    }
+
+    /// Ask the `RunLoop` to stop itself, without waiting for it.
+    ///
+    /// The stream is aborted once the `RunLoop` gets to it, i.e. after the current callback
+    /// returns, so this never blocks even if the callback waits for the caller.
+    pub(crate) fn abort_on_runloop(&self) {
+        let token = self.clone();
+        self.with_running_runloop(|runloop| perform(runloop, move || token.abort()));
+    }
+
+    /// Check whether the stream has been aborted.
+    #[must_use]
+    pub fn is_aborted(&self) -> bool {
+        *self
+            .inner
+            .aborted
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Run `f` with the `RunLoop` if it hasn't been aborted, preventing it from being aborted
+    /// meanwhile.
+    pub(crate) fn with_running_runloop<R>(&self, f: impl FnOnce(&CFRunLoop) -> R) -> Option<R> {
+        let aborted = self
+            .inner
+            .aborted
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        (!*aborted).then(|| f(&self.inner.runloop))
+    }
+
+    /// Run `f` on the `RunLoop` thread and wait for its result.
+    ///
+    /// `f` is run right away if called from the `RunLoop` thread, which would otherwise wait for
+    /// itself. Return `None` if the stream has been aborted, `f` panics, or the thread exits
+    /// before running it.
+    pub(crate) fn perform_sync<R: Send + 'static>(
+        &self,
+        f: impl FnOnce() -> R + Send + 'static,
+    ) -> Option<R> {
+        if self.is_runloop_thread() {
+            return if self.is_aborted() {
+                None
+            } else {
+                catch_unwind(AssertUnwindSafe(f)).ok()
+            };
+        }
+        // The lock isn't held while waiting, so that the RunLoop thread can abort meanwhile.
+        let rx = self.with_running_runloop(|runloop| perform_with_result(runloop, f))?;
+        wait_result(&rx, &self.inner.exited)
+    }
+
+    /// Wait for a value sent from the `RunLoop` thread, e.g. by an [`EventHandler`](EventHandler).
+    ///
+    /// Return `None` once the thread has exited without sending anything.
+    pub(crate) fn wait_from_runloop<R>(&self, rx: &Receiver<R>) -> Option<R> {
+        wait_result(rx, &self.inner.exited)
+    }
+
+    /// Whether the caller runs on the `RunLoop` thread, e.g. in an [`EventHandler`](EventHandler).
+    pub(crate) fn is_runloop_thread(&self) -> bool {
+        thread::current().id() == self.inner.thread
+    }
+
+    /// Whether the `RunLoop` thread has exited.
+    pub(crate) fn has_exited(&self) -> bool {
+        self.inner.exited.load(Ordering::SeqCst)
+    }
+}
+
+// Drops the sink of the handler and sets the flag once dropped, i.e. when the worker thread
+// exits, even by panicking.
+struct ExitGuard {
+    exited: Arc<AtomicBool>,
+    sink: Arc<Mutex<Option<EventSink>>>,
+}
+
+impl Drop for ExitGuard {
+    fn drop(&mut self) {
+        // Taken out of the lock first, as dropping an event handler runs arbitrary code.
+        let sink = self
+            .sink
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        drop(sink);
+        self.exited.store(true, Ordering::SeqCst);
+    }
+}
+
+/// The maximum latency accepted by [`EventStreamBuilder::latency`](EventStreamBuilder::latency).
+///
+/// With a larger latency, events would be held back for so long that they effectively never
+/// arrive.
+pub const MAX_LATENCY: Duration = Duration::from_secs(60);
+
+/// Latency set by [`EventStreamBuilder::interactive`](EventStreamBuilder::interactive).
+pub const INTERACTIVE_LATENCY: Duration = Duration::from_millis(100);
+
+/// The maximum number of paths accepted by
+/// [`EventStreamHandler::set_exclusion_paths`](EventStreamHandler::set_exclusion_paths).
+pub const MAX_EXCLUSION_PATHS: usize = 8;
+
+/// The default maximum number of paths a single stream may watch.
+///
+/// `FSEvents` doesn't document a limit, but creating a stream gets slow with a huge number of
+/// paths, and may eventually fail without a meaningful error. Raise it with
+/// [`EventStreamBuilder::max_paths`](EventStreamBuilder::max_paths) if needed.
+pub const DEFAULT_MAX_PATHS: usize = 4096;
+
+/// An error returned when a stream is asked to watch more paths than allowed.
+///
+/// It's wrapped in an [`io::Error`](io::Error) of kind `InvalidInput`, and can be retrieved with
+/// [`io::Error::get_ref`](io::Error::get_ref) and downcasting.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct TooManyPathsError {
+    /// Number of paths given.
+    pub count: usize,
+    /// Maximum number of paths allowed.
+    pub max: usize,
+}
+
+impl Display for TooManyPathsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "too many paths to watch: {} given, but at most {} are allowed; \
+             consider watching fewer, higher-level directories, or spreading them over a \
+             `WatcherPool`",
+            self.count, self.max
+        )
+    }
+}
+
+impl Error for TooManyPathsError {}
+
+/// A value in the extended data dictionary of an event.
+///
+/// See [`EventStreamBuilder::capture_extended_data`](EventStreamBuilder::capture_extended_data).
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ExtendedValue {
+    String(String),
+    Integer(i64),
+    /// Any other value, e.g. a floating point number, as described by `CFCopyDescription`.
+    Other(String),
+}
+
+/// An `FSEvents` API event.
+///
+/// With the `serde` feature, it can be serialized and deserialized. Flags are represented by their
+/// raw bits.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Event {
+    pub path: PathBuf,
+    pub inode: Option<u64>,
+    pub flags: StreamFlags,
+    pub raw_flags: FSEventStreamEventFlags,
+    pub id: FSEventStreamEventId,
+    /// The whole extended data dictionary, if enabled by
+    /// [`EventStreamBuilder::capture_extended_data`](EventStreamBuilder::capture_extended_data).
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub extended_data: Option<BTreeMap<String, ExtendedValue>>,
+    /// When the batch carrying this event was received from `FSEvents`, if enabled by
+    /// [`EventStreamBuilder::record_received_at`](EventStreamBuilder::record_received_at).
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub received_at: Option<SystemTime>,
+}
+
+impl Display for Event {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}] path: {:?}(", self.event_id(), self.path)?;
+        match self.inode {
+            Some(inode) => write!(f, "{}", inode)?,
+            None => write!(f, "-1")?,
+        }
+        write!(f, "), flags: {} ({:x})", self.flags, self.raw_flags)
+    }
+}
+
+impl Event {
+    /// The id of this event, formatted unambiguously in logs.
+    #[must_use]
+    pub const fn event_id(&self) -> EventId {
+        EventId(self.id)
+    }
+
+    /// The path of this event as raw bytes, e.g. to pass it over a C ABI.
+    ///
+    /// No conversion is involved, so the bytes are exactly those `FSEvents` reported, even if they
+    /// aren't valid UTF-8 and [`Path::to_str`](Path::to_str) fails on them. This only holds for
+    /// paths reported as C strings: with `kFSEventStreamCreateFlagUseCFTypes`, paths come as
+    /// `CFString`s, which can't hold bytes that aren't valid UTF-8 in the first place.
+    #[must_use]
+    pub fn path_bytes(&self) -> &[u8] {
+        self.path.as_os_str().as_bytes()
+    }
+
+    /// Whether this event is about one of `roots` itself, rather than something inside it.
+    ///
+    /// This tells e.g. "the watched folder has been renamed" from "something in it has been
+    /// renamed". Paths are compared as is, and `FSEvents` reports canonicalized paths, so pass
+    /// [`EventStream::roots`](EventStream::roots) rather than the paths given to the builder,
+    /// which may go through symlinks like `/tmp` or `/var`.
+    #[must_use]
+    pub fn is_root<P: AsRef<Path>>(&self, roots: &[P]) -> bool {
+        roots.iter().any(|root| self.path == root.as_ref())
+    }
+
+    /// How long ago this event was received from `FSEvents`, e.g. to drop stale events after a
+    /// backlog.
+    ///
+    /// Return `None` if [`received_at`](Self::received_at) isn't recorded. If the system clock has
+    /// gone backwards since, zero is returned.
+    #[must_use]
+    pub fn age(&self) -> Option<Duration> {
+        self.received_at
+            .map(|received_at| received_at.elapsed().unwrap_or_default())
+    }
+
+    /// Kinds of change reported by this event.
+    ///
+    /// See [`StreamFlags::kinds`](StreamFlags::kinds).
+    #[must_use]
+    pub fn kinds(&self) -> Vec<EventKind> {
+        self.flags.kinds()
+    }
+
+    /// Query the metadata of the file this event is about, following symlinks.
+    ///
+    /// The file may have changed again, or be gone, by the time this is called. A removed file
+    /// results in an error of kind `NotFound` rather than a panic, which is expected for events
+    /// flagged with `ITEM_REMOVED` or `ITEM_RENAMED`.
+    ///
+    /// # Errors
+    /// Return error when [`fs::metadata`](std::fs::metadata) fails.
+    pub fn metadata(&self) -> io::Result<std::fs::Metadata> {
+        std::fs::metadata(&self.path)
+    }
+
+    /// Query the metadata of the file this event is about without blocking the executor.
+    ///
+    /// See [`metadata`](Self::metadata) for details.
+    ///
+    /// # Errors
+    /// Return error when [`fs::metadata`](std::fs::metadata) fails.
+    pub async fn metadata_async(&self) -> io::Result<std::fs::Metadata> {
+        runtime::metadata(&self.path).await
+    }
+}
+
+impl AsRef<Path> for Event {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// An [`Event`](Event) with the target of the symlink it refers to.
+///
+/// Produced by [`EventStream::with_symlink_targets`](EventStream::with_symlink_targets).
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct SymlinkEvent {
+    /// The original event.
+    pub event: Event,
+    /// Target of the symlink, as stored in the link without being resolved.
+    ///
+    /// `None` if the event isn't about a created or modified symlink, or the link is no longer
+    /// there when the event is processed.
+    pub symlink_target: Option<PathBuf>,
+}
+
+impl AsRef<Path> for SymlinkEvent {
+    fn as_ref(&self) -> &Path {
+        &self.event.path
+    }
+}
+
+/// An [`Event`](Event) with the parent directory of its path.
+///
+/// Produced by [`EventStream::with_parents`](EventStream::with_parents).
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct EventWithParent {
+    /// The original event.
+    pub event: Event,
+    /// Parent of [`Event::path`](Event::path), as returned by [`Path::parent`](Path::parent).
+    ///
+    /// `None` for the file system root and empty paths, e.g. those of sentinel events. Items
+    /// right under the root have `/` as their parent.
+    pub parent: Option<PathBuf>,
+}
+
+impl AsRef<Path> for EventWithParent {
+    fn as_ref(&self) -> &Path {
+        &self.event.path
+    }
+}
+
+/// An item produced by [`EventStream::into_items`](EventStream::into_items), with volume
+/// lifecycle and sentinel notifications separated from file system events.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum StreamItem {
+    /// A file system event.
+    Event(Event),
+    /// The volume at `path` has been unmounted.
+    ///
+    /// No more event is produced for paths on this volume until it's mounted again.
+    Unmounted { path: PathBuf },
+    /// A volume has been mounted at `path`, which is the mount point.
+    ///
+    /// `FSEvents` only reports volumes mounted under a watched path. To detect new drives, watch
+    /// `/Volumes`, where they're mounted by default.
+    Mounted { path: PathBuf },
+    /// All historical events have been replayed, and events from now on are live.
+    ///
+    /// Only produced by streams started from a past event id.
+    HistoryDone { id: FSEventStreamEventId },
+    /// Event ids have wrapped around, so ids saved before aren't comparable to later ones.
+    IdsWrapped { id: FSEventStreamEventId },
+}
+
+impl From<Event> for StreamItem {
+    fn from(event: Event) -> Self {
+        if event.flags.contains(StreamFlags::HISTORY_DONE) {
+            Self::HistoryDone { id: event.id }
+        } else if event.flags.contains(StreamFlags::IDS_WRAPPED) {
+            Self::IdsWrapped { id: event.id }
+        } else if event.flags.contains(StreamFlags::UNMOUNT) {
+            Self::Unmounted { path: event.path }
+        } else if event.flags.contains(StreamFlags::MOUNT) {
+            Self::Mounted { path: event.path }
+        } else {
+            Self::Event(event)
+        }
+    }
+}
+
+/// What to do when a watched volume is unmounted.
+///
+/// An eject is often transient: the same volume may be mounted again at the same path later, and
+/// `FSEvents` reports it with a [`StreamItem::Mounted`](StreamItem::Mounted) notification. A
+/// permanent removal looks exactly the same, except that no such notification ever comes. Choose
+/// [`UnmountPolicy::End`](UnmountPolicy::End) if the stream is useless without the volume, and
+/// [`UnmountPolicy::KeepAlive`](UnmountPolicy::KeepAlive) if you want to wait for a remount.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum UnmountPolicy {
+    /// End the stream right after the first [`StreamItem::Unmounted`](StreamItem::Unmounted).
+    End,
+    /// Keep the stream alive to catch a remount.
+    KeepAlive,
+}
+
+/// How [`EventStream::drop_transient`](EventStream::drop_transient) tells whether an item is gone.
+///
+/// `FSEvents` accumulates flags on a path, so `ITEM_CREATED | ITEM_REMOVED` only says that both
+/// happened at some point, not in which order. A file replaced in place (`rm a; touch a`, or an
+/// editor's atomic save) carries the same flags as a temporary file which is gone for good.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Default)]
+pub enum TransientPolicy {
+    /// Only drop an item if it no longer exists on disk when its batch is processed.
+    #[default]
+    CheckExistence,
+    /// Trust the flags alone, without touching the file system.
+    ///
+    /// This is cheaper and works on replayed events, but items replaced in place are dropped too.
+    FlagsOnly,
+}
+
+/// A stream of `FSEvents` API event batches.
+///
+/// You may want a stream of [`Event`](Event) instead of a stream of batches of it.
+/// Call [`EventStream::into_flatten`](EventStream::into_flatten) to get one.
+///
+/// Call [`create_event_stream`](create_event_stream) to create it.
+pub struct EventStream {
+    stream: runtime::Receiver<RawBatch>,
+    roots: Vec<PathBuf>,
+    abort_token: Option<AbortToken>,
+}
+
+/// A batch of events delivered by one invocation of the `FSEvents` callback.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Batch {
+    /// Sequence number of the callback invocation.
+    ///
+    /// It starts from 0 and increases by one per invocation, so a gap means some batches failed
+    /// to be delivered, or are filtered out by
+    /// [`EventStreamBuilder::filter_flags`](EventStreamBuilder::filter_flags).
+    pub seq: u64,
+    /// Events in this batch.
+    pub events: Vec<Event>,
+}
+
+#[derive(Debug)]
+pub(crate) struct RawBatch {
+    pub seq: u64,
+    pub events: Vec<Result<Event, EventError>>,
+}
+
+pub(crate) type EventSender = runtime::Sender<RawBatch>;
+
+impl EventStream {
+    /// Create a bounded channel whose receiving half is an [`EventStream`](EventStream).
+    pub(crate) fn channel(capacity: usize) -> (EventSender, Self) {
+        let (tx, stream) = runtime::channel(capacity);
+        (
+            tx,
+            Self {
+                stream,
+                roots: vec![],
+                abort_token: None,
+            },
+        )
+    }
+
+    /// Set the watched roots reported by [`roots`](Self::roots).
+    pub(crate) fn with_roots(mut self, roots: Vec<PathBuf>) -> Self {
+        self.roots = roots;
+        self
+    }
+
+    /// Set the token to stop the backing `RunLoop` with once the stream is no longer needed.
+    pub(crate) fn with_abort_token(mut self, token: Option<AbortToken>) -> Self {
+        self.abort_token = token;
+        self
+    }
+
+    /// Take the token to stop the backing `RunLoop` with, if the stream owns one.
+    pub(crate) fn take_abort_token(&mut self) -> Option<AbortToken> {
+        self.abort_token.take()
+    }
+
+    /// Paths watched by this stream, canonicalized to match the paths reported by `FSEvents`.
+    ///
+    /// A path which can't be canonicalized, e.g. because it doesn't exist yet, is reported as
+    /// given. Paths of device-relative streams are reported as given too.
+    #[must_use]
+    pub fn roots(&self) -> &[PathBuf] {
+        &self.roots
+    }
+
+    /// Wait for the next batch of events for at most `timeout`, using the timer of the active
+    /// runtime.
+    ///
+    /// Return `Ok(None)` if the stream has completed.
+    ///
+    /// # Errors
+    /// Return error of kind `TimedOut` if no batch arrives within `timeout`. The stream can still
+    /// be polled afterwards.
+    pub async fn next_timeout(&mut self, timeout: Duration) -> io::Result<Option<Vec<Event>>> {
+        match select(self.next(), Box::pin(runtime::sleep(timeout))).await {
+            Either::Left((batch, _)) => Ok(batch),
+            Either::Right(((), _)) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("no event within {:?}", timeout),
+            )),
+        }
+    }
+
+    /// Flatten event batches and produce a stream of [`Event`](Event).
+    pub fn into_flatten(self) -> impl Stream<Item = Event> {
+        self.flat_map(iter)
+    }
+
+    /// Flatten event batches and produce a stream of [`Event`](Event), including events that
+    /// failed to be parsed.
+    ///
+    /// By default, such events are logged and skipped. Use this method if you want to decide how
+    /// to react to them instead.
+    pub fn try_into_flatten(self) -> impl Stream<Item = Result<Event, EventError>> {
+        self.stream.flat_map(|batch| iter(batch.events))
+    }
+
+    /// Produce a stream of [`Batch`](Batch), which tells the callback invocation each batch
+    /// originates from.
+    ///
+    /// This helps to correlate logs with the delivery cadence of `FSEvents`.
+    pub fn into_batches(self) -> impl Stream<Item = Batch> {
+        self.stream.map(|batch| Batch {
+            seq: batch.seq,
+            events: skip_errors(batch.events),
+        })
+    }
+}
+
+#[cfg(test)]
+impl EventStream {
+    /// Create a stream which yields the given batches and then completes.
+    pub(crate) fn from_batches(batches: Vec<Vec<Event>>) -> Self {
+        let (tx, stream) = Self::channel(batches.len().max(1));
+        for (seq, batch) in (0..).zip(batches) {
+            tx.try_send(RawBatch {
+                seq,
+                events: batch.into_iter().map(Ok).collect(),
+            })
+            .expect("channel to have enough capacity");
+        }
+        stream
+    }
+}
+
+impl Stream for EventStream {
+    type Item = Vec<Event>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.stream
+            .poll_next_unpin(cx)
+            .map(|batch| batch.map(|batch| skip_errors(batch.events)))
+    }
+}
+
+/// An [`EventStream`](EventStream) bundled with its [`EventStreamHandler`](EventStreamHandler),
+/// which aborts the stream when dropped.
+///
+/// Created by [`EventStreamBuilder::build_owned`](EventStreamBuilder::build_owned). Use the split
+/// pair returned by [`build`](EventStreamBuilder::build) to control the stream from elsewhere.
+///
+/// Dropping it waits for the `RunLoop` thread to shut down, which blocks the current thread
+/// shortly.
+pub struct OwnedEventStream {
+    stream: EventStream,
+    handler: EventStreamHandler,
+}
+
+impl OwnedEventStream {
+    /// The handler of the stream.
+    #[must_use]
+    pub const fn handler(&self) -> &EventStreamHandler {
+        &self.handler
+    }
+
+    /// The handler of the stream, e.g. to watch more paths.
+    pub fn handler_mut(&mut self) -> &mut EventStreamHandler {
+        &mut self.handler
+    }
+}
+
+impl Stream for OwnedEventStream {
+    type Item = Vec<Event>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.stream.poll_next_unpin(cx)
+    }
+}
+
+impl Drop for OwnedEventStream {
+    fn drop(&mut self) {
+        self.handler.abort();
+    }
+}
+
+/// An error occurred when parsing an event delivered by `FSEvents`.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum EventError {
+    /// The file id in the extended data can't be represented as an inode.
+    InvalidInode { id: FSEventStreamEventId },
+    /// The path can't be converted from `CFString`.
+    InvalidPath { id: FSEventStreamEventId },
+    /// The event flags can't be parsed.
+    InvalidFlags {
+        id: FSEventStreamEventId,
+        raw_flags: FSEventStreamEventFlags,
+    },
+    /// The extended data dictionary lacks an expected key.
+    MissingKey {
+        id: FSEventStreamEventId,
+        key: &'static str,
+    },
+}
+
+impl Display for EventError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidInode { id } => {
+                write!(f, "[{}] unable to read file id as inode", EventId(*id))
+            }
+            Self::InvalidPath { id } => {
+                write!(f, "[{}] unable to convert path", EventId(*id))
+            }
+            Self::InvalidFlags { id, raw_flags } => {
+                write!(
+                    f,
+                    "[{}] unable to parse flags ({:x})",
+                    EventId(*id),
+                    raw_flags
+                )
+            }
+            Self::MissingKey { id, key } => {
+                write!(f, "[{}] missing key {} in extended data", EventId(*id), key)
+            }
+        }
+    }
+}
+
+impl Error for EventError {}
+
+fn skip_errors(events: Vec<Result<Event, EventError>>) -> Vec<Event> {
+    events
+        .into_iter()
+        .filter_map(|event| match event {
+            Ok(event) => Some(event),
+            Err(e) => {
+                error!("{}", e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// A handler which receives event batches directly on the `RunLoop` thread.
+///
+/// Unlike [`EventStream`](EventStream), no channel sits between `FSEvents` and the handler, so
+/// events are dispatched synchronously and without buffering.
+///
+/// Call [`create_event_stream_with_handler`](create_event_stream_with_handler) to register one.
+pub trait EventHandler: Send + 'static {
+    /// Handle a batch of `FSEvents` API events.
+    fn handle_events(&mut self, events: Vec<Event>);
+}
+
+impl<F: FnMut(Vec<Event>) + Send + 'static> EventHandler for F {
+    fn handle_events(&mut self, events: Vec<Event>) {
+        self(events);
+    }
+}
+
+/// The sending half of a channel of event batches, owned by the caller.
+///
+/// It's a `tokio::sync::mpsc::Sender` if the `tokio` feature is enabled, and an
+/// `async_std::channel::Sender` otherwise. Pass it to
+/// [`create_event_stream_with_sender`](create_event_stream_with_sender).
+pub type BatchSender = runtime::Sender<Vec<Event>>;
+
+/// An `FSEvents` API event borrowing its path from the buffers passed to the callback.
+///
+/// Delivered to a [`RawEventHandler`](RawEventHandler). Unlike [`Event`](Event), constructing it
+/// doesn't allocate unless the path needs to be converted, and its flags aren't parsed.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct RawEventRef<'a> {
+    /// Path of the event, borrowed from `FSEvents` whenever possible.
+    pub path: Cow<'a, Path>,
+    /// Inode of the file, if the stream is file-granular and created with extended data.
+    pub inode: Option<u64>,
+    /// Raw `kFSEventStreamEventFlag*` flags.
+    pub flags: FSEventStreamEventFlags,
+    /// Event id.
+    pub id: FSEventStreamEventId,
+    /// The whole extended data dictionary, if enabled by
+    /// [`EventStreamBuilder::capture_extended_data`](EventStreamBuilder::capture_extended_data).
+    pub extended_data: Option<BTreeMap<String, ExtendedValue>>,
+}
+
+impl RawEventRef<'_> {
+    /// Copy this event into an owned [`Event`](Event).
+    ///
+    /// # Errors
+    /// Return error when the flags can't be parsed.
+    pub fn to_event(&self) -> Result<Event, EventError> {
+        self.clone().into_event()
+    }
+
+    pub(crate) fn into_event(self) -> Result<Event, EventError> {
+        Ok(Event {
+            flags: StreamFlags::from_bits(self.flags).ok_or(EventError::InvalidFlags {
+                id: self.id,
+                raw_flags: self.flags,
+            })?,
+            path: self.path.into_owned(),
+            inode: self.inode,
+            raw_flags: self.flags,
+            id: self.id,
+            extended_data: self.extended_data,
+            received_at: None,
+        })
+    }
+}
+
+/// A handler which receives borrowed event batches directly on the `RunLoop` thread.
+///
+/// This is the lowest-level way to consume events, for consumers which re-encode events right
+/// away and don't want [`Event`](Event)s to be allocated at all. Events only live for the duration
+/// of the call, and the `RunLoop` is blocked meanwhile, so copy out what's needed and return
+/// quickly.
+///
+/// Events whose path can't be converted are logged and skipped.
+/// [`EventStreamBuilder::filter_flags`](EventStreamBuilder::filter_flags) is honored, but
+/// [`ignore_watch_root_events`](EventStreamBuilder::ignore_watch_root_events) and
+/// [`log_events`](EventStreamBuilder::log_events) have no effect.
+///
+/// Call [`EventStreamBuilder::build_with_raw_handler`](EventStreamBuilder::build_with_raw_handler)
+/// to register one.
+pub trait RawEventHandler: Send + 'static {
+    /// Handle a batch of borrowed `FSEvents` API events.
+    fn handle_raw_events(&mut self, events: &[RawEventRef<'_>]);
+}
+
+impl<F: FnMut(&[RawEventRef<'_>]) + Send + 'static> RawEventHandler for F {
+    fn handle_raw_events(&mut self, events: &[RawEventRef<'_>]) {
+        self(events);
+    }
+}
+
+/// How to deliver event batches to an [`EventStream`](EventStream) whose buffer is full.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Hash)]
+pub enum DeliveryPolicy {
+    /// Drop the batch and log an error. This is the default.
+    #[default]
+    DropOnFull,
+    /// Block the `RunLoop` thread until there's room for the batch.
+    ///
+    /// No event is lost, but `FSEvents` is slowed down to the pace of the consumer. The stream
+    /// must be kept polled, or dropped, for the `RunLoop` to make progress. In particular,
+    /// [`EventStreamHandler::abort`](EventStreamHandler::abort) waits for the pending batch to be
+    /// delivered.
+    BlockOnFull,
+}
+
+#[derive(Clone)]
+enum EventSink {
+    Parsed(BatchSink),
+    Raw(Arc<Mutex<Box<dyn RawEventHandler>>>),
+}
+
+// Where batches of parsed events go. Raw handlers are called before events are parsed, so they
+// never receive a batch.
+#[derive(Clone)]
+enum BatchSink {
+    Channel(EventSender, DeliveryPolicy),
+    Handler(Arc<Mutex<Box<dyn EventHandler>>>),
+}
+
+impl BatchSink {
+    fn deliver(&self, seq: u64, events: Vec<Result<Event, EventError>>) {
+        match self {
+            Self::Channel(tx, DeliveryPolicy::DropOnFull) => {
+                #[cfg(feature = "tracing")]
+                let batch_size = events.len();
+                match tx.try_send(RawBatch { seq, events }) {
+                    Ok(()) => {}
+                    // The stream has been dropped before the handler is aborted.
+                    Err(TrySendError::Closed(_)) => {
+                        debug!("Event stream is closed, batch discarded")
+                    }
+                    Err(e) => {
+                        error!("Unable to send event from callback: {}", e);
+                        #[cfg(feature = "tracing")]
+                        tracing::warn!(seq, batch_size, "batch dropped");
+                    }
+                }
+            }
+            Self::Channel(tx, DeliveryPolicy::BlockOnFull) => {
+                // Only fails if the stream has been dropped, which also releases a blocked send.
+                if runtime::blocking_send(tx, RawBatch { seq, events }).is_err() {
+                    debug!("Event stream is closed, batch discarded");
+                }
+            }
+            Self::Handler(handler) => match handler.lock() {
+                Ok(mut handler) => handler.handle_events(skip_errors(events)),
+                Err(_) => error!("Event handler is poisoned by a previous panic"),
+            },
+        }
+    }
+}
+
+// Flags of events about watched roots which are delivered even if root events are ignored.
+const ROOT_EVENT_KEPT_FLAGS: StreamFlags = StreamFlags::from_bits_truncate(
+    StreamFlags::ROOT_CHANGED.bits()
+        | StreamFlags::MUST_SCAN_SUBDIRS.bits()
+        | StreamFlags::USER_DROPPED.bits()
+        | StreamFlags::KERNEL_DROPPED.bits()
+        | StreamFlags::IDS_WRAPPED.bits()
+        | StreamFlags::HISTORY_DONE.bits()
+        | StreamFlags::MOUNT.bits()
+        | StreamFlags::UNMOUNT.bits(),
+);
+
+/// Statistics about the batches delivered by `FSEvents`, as reported by
+/// [`EventStreamHandler::stats`](EventStreamHandler::stats).
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Hash)]
+pub struct HandlerStats {
+    /// Number of events in the most recent batch.
+    pub last_batch_size: usize,
+    /// Number of events in the largest of the last [`BATCH_SIZE_WINDOW`](BATCH_SIZE_WINDOW)
+    /// batches.
+    pub max_recent_batch_size: usize,
+}
+
+/// The active configuration and state of a stream, as returned by
+/// [`EventStreamHandler::describe`](EventStreamHandler::describe).
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct StreamDescription {
+    /// Paths being watched, as held by `FSEvents`.
+    pub paths: Vec<PathBuf>,
+    /// Flags the stream has been created with.
+    pub flags: FSEventStreamCreateFlags,
+    /// Latency the stream has been created with.
+    pub latency: Duration,
+    /// The device being watched if the stream is created relative to a device.
+    pub device: Option<dev_t>,
+    /// Id of the last event delivered to the callback, or `since_when` if none has been yet.
+    pub latest_event_id: FSEventStreamEventId,
+    /// Whether the backing thread is still alive.
+    pub running: bool,
+    /// The description `FSEvents` gives of the stream, for logging. Its format isn't stable and
+    /// shouldn't be parsed.
+    pub raw: String,
+}
+
+/// Number of recent batches [`HandlerStats::max_recent_batch_size`](HandlerStats) is taken over.
+pub const BATCH_SIZE_WINDOW: usize = 64;
+
+// Diagnostics recorded by the worker thread and the callback, and read by the handler.
+#[derive(Debug, Default)]
+struct Diagnostics {
+    // Sizes of the most recent batches.
+    batch_sizes: Mutex<VecDeque<usize>>,
+    started_at: OnceCell<Instant>,
+    first_batch_at: OnceCell<Instant>,
+}
+
+impl Diagnostics {
+    fn record_start(&self) {
+        let _ = self.started_at.set(Instant::now());
+    }
+
+    fn record_batch(&self, size: usize) {
+        let _ = self.first_batch_at.set(Instant::now());
+        let mut sizes = self
+            .batch_sizes
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if sizes.len() == BATCH_SIZE_WINDOW {
+            sizes.pop_front();
+        }
+        sizes.push_back(size);
+    }
+
+    fn time_to_first_event(&self) -> Option<Duration> {
+        let started_at = self.started_at.get()?;
+        let first_batch_at = self.first_batch_at.get()?;
+        Some(first_batch_at.saturating_duration_since(*started_at))
+    }
+
+    fn stats(&self) -> HandlerStats {
+        let sizes = self
+            .batch_sizes
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        HandlerStats {
+            last_batch_size: sizes.back().copied().unwrap_or_default(),
+            max_recent_batch_size: sizes.iter().copied().max().unwrap_or_default(),
+        }
+    }
+}
+
+/// A user-supplied function called with the payload of a panic caught in the `FSEvents` callback.
+#[derive(Clone)]
+struct PanicHandler(Arc<dyn Fn(Box<dyn Any + Send>) + Send + Sync>);
+
+impl Debug for PanicHandler {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PanicHandler")
+    }
+}
+
+// Extract the message of a panic payload, if it's a string as produced by `panic!`.
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("Box<dyn Any>")
+}
+
+pub(crate) struct StreamContextInfo {
+    sink: EventSink,
+    // Sequence number of the next callback invocation.
+    seq: AtomicU64,
+    log_level: Option<Level>,
+    flags_mask: Option<StreamFlags>,
+    // Watched roots whose own events are dropped. Empty if they're kept.
+    ignored_roots: Vec<PathBuf>,
+    capture_extended_data: bool,
+    record_received_at: bool,
+    panic_handler: Option<PanicHandler>,
+    diagnostics: Arc<Diagnostics>,
+}
+
+#[cfg(any(test, feature = "bench"))]
+impl StreamContextInfo {
+    /// Create a context which dispatches events to `handler`, to drive callbacks directly.
+    pub(crate) fn with_handler(handler: impl EventHandler) -> Self {
+        Self {
+            sink: EventSink::Parsed(BatchSink::Handler(Arc::new(Mutex::new(Box::new(handler))))),
+            seq: AtomicU64::new(0),
+            log_level: None,
+            flags_mask: None,
+            ignored_roots: vec![],
+            capture_extended_data: false,
+            record_received_at: false,
+            panic_handler: None,
+            diagnostics: Arc::default(),
+        }
+    }
+
+    /// Copy whole extended data dictionaries into events.
+    #[cfg(test)]
+    pub(crate) fn with_extended_data_capture(self) -> Self {
+        Self {
+            capture_extended_data: true,
+            ..self
+        }
+    }
+
+    /// Set the function called with panics caught in the callback.
+    #[cfg(test)]
+    pub(crate) fn with_panic_handler(
+        self,
+        handler: impl Fn(Box<dyn Any + Send>) + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            panic_handler: Some(PanicHandler(Arc::new(handler))),
+            ..self
+        }
+    }
+
+    /// Create a context which dispatches borrowed events to `handler`, to drive callbacks
+    /// directly.
+    #[cfg(test)]
+    pub(crate) fn with_raw_handler(handler: impl RawEventHandler) -> Self {
+        Self {
+            sink: EventSink::Raw(Arc::new(Mutex::new(Box::new(handler)))),
+            ..Self::with_handler(|_: Vec<Event>| {})
+        }
+    }
+}
+
+impl_release_callback!(release_context, StreamContextInfo);
+
+struct SendWrapper<T>(T);
+
+unsafe impl<T> Send for SendWrapper<T> {}
+
+impl<T> SendWrapper<T> {
+    const unsafe fn new(t: T) -> Self {
+        Self(t)
+    }
+}
+
+/// Paths converted to the representation accepted by `FSEvents`.
+///
+/// Converting paths involves resolving them on the file system, which is wasted work when
+/// streams are recreated over the same large set of paths. Prepare them once and create streams
+/// with [`EventStreamBuilder::from_prepared`](EventStreamBuilder::from_prepared) instead.
+pub struct PreparedPaths {
+    paths: Vec<PathBuf>,
+    cf_paths: CFArray<CFString>,
+}
+
+// Safety:
+// - Immutable `CFArray`s and `CFString`s are thread-safe according to the Apple documentation.
+//   https://developer.apple.com/library/archive/documentation/Cocoa/Conceptual/Multithreading/ThreadSafetySummary/ThreadSafetySummary.html
+unsafe impl Send for PreparedPaths {}
+unsafe impl Sync for PreparedPaths {}
+
+impl Clone for PreparedPaths {
+    fn clone(&self) -> Self {
+        Self {
+            paths: self.paths.clone(),
+            // `CFArray<T>` doesn't implement `Clone`, so retain the underlying array instead.
+            cf_paths: unsafe { CFArray::wrap_under_get_rule(self.cf_paths.as_concrete_TypeRef()) },
+        }
+    }
+}
+
+impl PreparedPaths {
+    /// Convert `paths` for later use.
+    ///
+    /// # Errors
+    /// Return error when there's any invalid path in `paths`.
+    pub fn new<P: AsRef<Path>>(paths: impl IntoIterator<Item = P>) -> io::Result<Self> {
+        let paths: Vec<_> = paths
+            .into_iter()
+            .map(|path| path.as_ref().to_path_buf())
+            .collect();
+        let cf_paths = paths_to_cf_array(&paths)?;
+        Ok(Self { paths, cf_paths })
+    }
+
+    /// The paths before conversion.
+    #[must_use]
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+}
+
+impl Debug for PreparedPaths {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("PreparedPaths").field(&self.paths).finish()
+    }
+}
+
+/// A builder to configure and create an [`EventStream`](EventStream).
+///
+/// By default, the stream watches events since now with zero latency and no create flags.
+///
+/// [`create_event_stream`](create_event_stream) is a shortcut for the common case.
+#[derive(Debug, Clone)]
+pub struct EventStreamBuilder {
+    paths_to_watch: Vec<PathBuf>,
+    since_when: FSEventStreamEventId,
+    pub(crate) latency: Duration,
+    pub(crate) flags: FSEventStreamCreateFlags,
+    exclusion_paths: Vec<PathBuf>,
+    device: Option<dev_t>,
+    log_level: Option<Level>,
+    flags_mask: Option<StreamFlags>,
+    ignore_watch_root_events: bool,
+    delivery_policy: DeliveryPolicy,
+    prepared_paths: Option<PreparedPaths>,
+    max_paths: usize,
+    capture_extended_data: bool,
+    record_received_at: bool,
+    panic_handler: Option<PanicHandler>,
+    attempts: u32,
+    backoff: Duration,
+}
+
+impl EventStreamBuilder {
+    /// Create a builder which watches `paths_to_watch`.
+    pub fn new<P: AsRef<Path>>(paths_to_watch: impl IntoIterator<Item = P>) -> Self {
+        Self {
+            paths_to_watch: paths_to_watch
+                .into_iter()
+                .map(|path| path.as_ref().to_path_buf())
+                .collect(),
+            since_when: kFSEventStreamEventIdSinceNow,
+            latency: Duration::ZERO,
+            flags: kFSEventStreamCreateFlagNone,
+            exclusion_paths: vec![],
+            device: None,
+            log_level: None,
+            flags_mask: None,
+            ignore_watch_root_events: false,
+            delivery_policy: DeliveryPolicy::DropOnFull,
+            prepared_paths: None,
+            max_paths: DEFAULT_MAX_PATHS,
+            capture_extended_data: false,
+            record_received_at: false,
+            panic_handler: None,
+            attempts: 1,
+            backoff: Duration::ZERO,
+        }
+    }
+
+    /// Create a builder which watches paths prepared by [`PreparedPaths::new`](PreparedPaths::new).
+    ///
+    /// Paths aren't converted again when the stream is built. This doesn't apply to
+    /// device-relative streams, whose paths are always converted.
+    #[must_use]
+    pub fn from_prepared(paths: PreparedPaths) -> Self {
+        Self {
+            prepared_paths: Some(paths.clone()),
+            ..Self::new(paths.paths)
+        }
+    }
+
+    /// Set the event id to start watching from.
+    #[must_use]
+    pub fn since_when(mut self, since_when: FSEventStreamEventId) -> Self {
+        self.since_when = since_when;
+        self
+    }
+
+    /// Set how long `FSEvents` should wait before delivering a batch of events.
+    ///
+    /// `Duration::ZERO` delivers events as soon as possible, which is typically paired with
+    /// `kFSEventStreamCreateFlagNoDefer`. Latencies above [`MAX_LATENCY`](MAX_LATENCY) are rejected
+    /// when the stream is built.
+    #[must_use]
+    pub fn latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Deliver events with low delay, for interactive tools like file browsers and dev servers.
+    ///
+    /// This sets `kFSEventStreamCreateFlagNoDefer` and a latency of
+    /// [`INTERACTIVE_LATENCY`](INTERACTIVE_LATENCY), so the first event after a quiet period is
+    /// delivered right away, and bursts following it are coalesced. Other flags are kept.
+    ///
+    /// How `kFSEventStreamCreateFlagNoDefer` and the latency interact:
+    ///
+    /// | | zero latency | nonzero latency |
+    /// |---|---|---|
+    /// | `NoDefer` set | Every event is delivered as soon as possible. | The first event after a quiet period is delivered right away, then events are held back until `latency` has passed since it. |
+    /// | `NoDefer` unset | Every event is delivered as soon as possible. | Events are held back until `latency` has passed since the first one, then delivered in one batch. |
+    #[must_use]
+    pub fn interactive(mut self) -> Self {
+        self.flags |= kFSEventStreamCreateFlagNoDefer;
+        self.latency = INTERACTIVE_LATENCY;
+        self
+    }
+
+    /// Deliver events in batches spanning `latency`, for throughput-oriented consumers like
+    /// indexers and sync tools.
+    ///
+    /// This clears `kFSEventStreamCreateFlagNoDefer` and sets the latency, so events are always
+    /// held back and coalesced. Other flags are kept. See [`interactive`](Self::interactive) for
+    /// how the two settings interact.
+    #[must_use]
+    pub fn batched(mut self, latency: Duration) -> Self {
+        self.flags &= !kFSEventStreamCreateFlagNoDefer;
+        self.latency = latency;
+        self
+    }
+
+    /// Set the `kFSEventStreamCreateFlag*` flags to create the stream with.
+    ///
+    /// This replaces all flags, including those set by other builder methods like
+    /// [`ignore_self`](Self::ignore_self).
+    ///
+    /// The flags decide how paths and inodes of events are read:
+    ///
+    /// | Flags                                         | Path           | Inode          |
+    /// |-----------------------------------------------|----------------|----------------|
+    /// | no `UseCFTypes`                               | C string       | `None`         |
+    /// | `UseCFTypes`                                  | `CFString`     | `None`         |
+    /// | `UseCFTypes \| UseExtendedData`               | `path` key     | `None`         |
+    /// | `UseCFTypes \| UseExtendedData \| FileEvents` | `path` key     | `fileID` key   |
+    ///
+    /// `FSEvents` only reports file ids for file-granular streams, so the `fileID` key isn't read,
+    /// and needn't be present, without `FileEvents`. `UseExtendedData` without `UseCFTypes` is
+    /// illegal.
+    #[must_use]
+    pub fn flags(mut self, flags: FSEventStreamCreateFlags) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Set whether to drop events triggered by the current process.
+    ///
+    /// This toggles `kFSEventStreamCreateFlagIgnoreSelf`. Events caused by file operations of any
+    /// thread in this process, not only the calling one, are dropped by `FSEvents` and never
+    /// delivered.
+    #[must_use]
+    pub fn ignore_self(mut self, ignore_self: bool) -> Self {
+        if ignore_self {
+            self.flags |= kFSEventStreamCreateFlagIgnoreSelf;
+        } else {
+            self.flags &= !kFSEventStreamCreateFlagIgnoreSelf;
+        }
+        self
+    }
+
+    /// Log each delivered event at the given level.
+    ///
+    /// Events are formatted with the `Display` impl of [`Event`](Event) and logged on the
+    /// `RunLoop` thread as soon as `FSEvents` delivers them, before they reach the stream or the
+    /// handler. Nothing is formatted if `level` is disabled in the logger.
+    #[must_use]
+    pub fn log_events(mut self, level: Level) -> Self {
+        self.log_level = Some(level);
+        self
+    }
+
+    /// Only deliver events whose flags intersect with `mask`.
+    ///
+    /// Unlike [`EventStream::filter_flags`](EventStream::filter_flags), filtering is done in the
+    /// `FSEvents` callback, so filtered-out events are never constructed, and batches with no
+    /// event left are not delivered at all.
+    #[must_use]
+    pub fn filter_flags(mut self, mask: StreamFlags) -> Self {
+        self.flags_mask = Some(mask);
+        self
+    }
+
+    /// Set whether to replay the full history when starting from a past event id.
+    ///
+    /// This toggles `kFSEventStreamCreateFlagFullHistory`. `FSEvents` stores history in chunks,
+    /// and by default may skip some events of the chunk containing
+    /// [`since_when`](Self::since_when). With the flag, every event of that chunk is delivered,
+    /// including some whose id precedes `since_when`, so filter by id if that matters. It has no
+    /// effect on streams starting from `kFSEventStreamEventIdSinceNow`.
+    ///
+    /// The flag is available since macOS 10.15. Older versions don't know it, and replay the
+    /// default history.
+    #[must_use]
+    pub fn full_history(mut self, full_history: bool) -> Self {
+        if full_history {
+            self.flags |= kFSEventStreamCreateFlagFullHistory;
+        } else {
+            self.flags &= !kFSEventStreamCreateFlagFullHistory;
+        }
+        self
+    }
+
+    /// Set whether to drop events about the watched paths themselves, as opposed to things under
+    /// them.
+    ///
+    /// Watched directories tend to produce events of their own, e.g. when their metadata is
+    /// modified by an entry being created inside, which are noise to many consumers. Events
+    /// flagged with `ROOT_CHANGED` (see `kFSEventStreamCreateFlagWatchRoot`), `MUST_SCAN_SUBDIRS`,
+    /// or any of the other stream-level flags like `MOUNT` and `USER_DROPPED` are still delivered.
+    ///
+    /// Watched paths are canonicalized when the stream is built to match the paths reported by
+    /// `FSEvents`. Paths of device-relative streams are compared as is.
+    #[must_use]
+    pub fn ignore_watch_root_events(mut self, ignore: bool) -> Self {
+        self.ignore_watch_root_events = ignore;
+        self
+    }
+
+    /// Set whether to copy the whole extended data dictionary into
+    /// [`Event::extended_data`](Event::extended_data).
+    ///
+    /// Only the path and file id are parsed by default. This captures any other key `FSEvents`
+    /// may report as well, at the cost of an allocation per entry. Requires
+    /// `kFSEventStreamCreateFlagUseExtendedData`.
+    #[must_use]
+    pub fn capture_extended_data(mut self, capture: bool) -> Self {
+        self.capture_extended_data = capture;
+        self
+    }
+
+    /// Set whether to record when events are received in
+    /// [`Event::received_at`](Event::received_at).
+    ///
+    /// All events of a batch share the same timestamp, taken once per callback. It tells when
+    /// `FSEvents` delivered the event, which may be up to the latency after the change happened.
+    #[must_use]
+    pub fn record_received_at(mut self, record: bool) -> Self {
+        self.record_received_at = record;
+        self
+    }
 
-use crate::ffi::{
-    kFSEventStreamCreateFlagFileEvents, kFSEventStreamCreateFlagUseCFTypes,
-    kFSEventStreamCreateFlagUseExtendedData, kFSEventStreamEventExtendedDataPathKey,
-    kFSEventStreamEventExtendedFileIDKey, CFRunLoopExt, FSEventStreamCreateFlags,
-    FSEventStreamEventFlags, FSEventStreamEventId, SysFSEventStream, SysFSEventStreamContext,
-    SysFSEventStreamRef,
-};
-pub use crate::flags::StreamFlags;
-use crate::impl_release_callback;
-use crate::observer::create_oneshot_observer;
-use crate::utils::FlagsExt;
+    /// Make up to `attempts` attempts to create and start the stream, e.g. for daemons starting
+    /// at boot, when volumes may not be mounted yet.
+    ///
+    /// The first retry waits for `backoff`, which doubles after every failed attempt. The error of
+    /// the last attempt is returned if all of them fail. Rejected configurations, i.e.
+    /// `InvalidInput` errors, aren't retried. Building blocks the calling thread while waiting.
+    ///
+    /// By default, a single attempt is made. Zero is treated as one.
+    #[must_use]
+    pub fn retry(mut self, attempts: u32, backoff: Duration) -> Self {
+        self.attempts = attempts.max(1);
+        self.backoff = backoff;
+        self
+    }
 
-#[cfg(test)]
-pub(crate) static TEST_RUNNING_RUNLOOP_COUNT: std::sync::atomic::AtomicUsize =
-    std::sync::atomic::AtomicUsize::new(0);
+    /// Call `handler` with the payload of any panic raised while processing events in the
+    /// `FSEvents` callback.
+    ///
+    /// Panics can't unwind into `FSEvents`, so they're caught and the offending batch is lost.
+    /// They're always logged at error level, and `handler` gives the app a chance to notice that
+    /// the stream is misbehaving, e.g. to restart it. `handler` runs on the `RunLoop` thread, and
+    /// panics raised by itself are ignored.
+    #[must_use]
+    pub fn panic_handler(
+        mut self,
+        handler: impl Fn(Box<dyn Any + Send>) + Send + Sync + 'static,
+    ) -> Self {
+        self.panic_handler = Some(PanicHandler(Arc::new(handler)));
+        self
+    }
 
-/// An owned permission to stop an [`EventStream`](EventStream) and terminate its backing `RunLoop`.
-///
-/// A `EventStreamHandler` *detaches* the associated Stream and `RunLoop` when it is dropped, which
-/// means that there is no longer any handle to them and no way to `abort` them.
-///
-/// Dropping the handler without first calling [`abort`](EventStreamHandler::abort) is not
-/// recommended because this leaves a spawned thread behind and causes memory leaks.
-pub struct EventStreamHandler {
-    runloop: Option<(CFRunLoop, thread::JoinHandle<()>)>,
-}
+    /// Set how to deliver event batches when the buffer of the [`EventStream`](EventStream) is
+    /// full.
+    ///
+    /// Defaults to [`DeliveryPolicy::DropOnFull`](DeliveryPolicy::DropOnFull). This has no effect
+    /// on streams built with [`build_with_handler`](Self::build_with_handler).
+    #[must_use]
+    pub fn delivery_policy(mut self, policy: DeliveryPolicy) -> Self {
+        self.delivery_policy = policy;
+        self
+    }
 
-// Safety:
-// - According to the Apple documentation, it's safe to move `CFRef`s across threads.
-//   https://developer.apple.com/library/archive/documentation/Cocoa/Conceptual/Multithreading/ThreadSafetySummary/ThreadSafetySummary.html
-unsafe impl Send for EventStreamHandler {}
+    /// Set directories to be filtered from the stream.
+    ///
+    /// `FSEventStreamSetExclusionPaths` is applied after the stream is created but before it's
+    /// started, so no event under these directories is ever delivered. At most
+    /// [`MAX_EXCLUSION_PATHS`](MAX_EXCLUSION_PATHS) paths are allowed.
+    #[must_use]
+    pub fn exclusion_paths(mut self, exclusion_paths: Vec<PathBuf>) -> Self {
+        self.exclusion_paths = exclusion_paths;
+        self
+    }
 
-impl EventStreamHandler {
-    /// Stop an [`EventStream`](EventStream) and terminate its backing `RunLoop`.
+    /// Set the maximum number of paths this stream may watch.
     ///
-    /// Calling this method multiple times has no extra effect and won't cause any panic, error,
-    /// or undefined behavior.
-    pub fn abort(&mut self) {
-        if let Some((runloop, thread_handle)) = self.runloop.take() {
-            let (tx, rx) = channel();
-            let observer = create_oneshot_observer(kCFRunLoopBeforeWaiting, tx);
-            runloop.add_observer(&observer, unsafe { kCFRunLoopDefaultMode });
-
-            if !runloop.is_waiting() {
-                // Wait the RunLoop to enter Waiting state.
-                rx.recv().expect("channel to receive BeforeWaiting signal");
-            }
+    /// Defaults to [`DEFAULT_MAX_PATHS`](DEFAULT_MAX_PATHS). Building a stream with more paths
+    /// fails with a [`TooManyPathsError`](TooManyPathsError).
+    #[must_use]
+    pub fn max_paths(mut self, max_paths: usize) -> Self {
+        self.max_paths = max_paths;
+        self
+    }
 
-            runloop.remove_observer(&observer, unsafe { kCFRunLoopDefaultMode });
-            runloop.stop();
+    /// Watch paths on the given device rather than absolute paths.
+    ///
+    /// `paths_to_watch` are then interpreted relative to the root of `device`, and so are the
+    /// paths of delivered events. This is the most efficient way to watch a whole volume.
+    #[must_use]
+    pub fn device(mut self, device: dev_t) -> Self {
+        self.device = Some(device);
+        self
+    }
 
-            // Wait for the thread to shut down.
-            thread_handle.join().expect("thread to shut down");
-        }
+    /// Create a new [`EventStream`](EventStream) and [`EventStreamHandler`](EventStreamHandler) pair.
+    ///
+    /// # Errors
+    /// Return error when there's any invalid path in `paths_to_watch`, the configuration is
+    /// rejected, or `FSEvents` fails to create or start the stream.
+    ///
+    /// # Panics
+    /// Panic when the given flags combination is illegal.
+    pub fn build(self) -> io::Result<(EventStream, EventStreamHandler)> {
+        let (event_tx, stream) = EventStream::channel(1024);
+        let handler = self.spawn(EventSink::Parsed(BatchSink::Channel(
+            event_tx,
+            self.delivery_policy,
+        )))?;
+        let stream = stream
+            .with_roots(self.watch_roots())
+            .with_abort_token(handler.abort_token());
+        Ok((stream, handler))
     }
-}
 
-/// An `FSEvents` API event.
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
-pub struct Event {
-    pub path: PathBuf,
-    pub inode: Option<i64>,
-    pub flags: StreamFlags,
-    pub raw_flags: FSEventStreamEventFlags,
-    pub id: FSEventStreamEventId,
-}
+    /// Create a new [`OwnedEventStream`](OwnedEventStream), which aborts itself when dropped.
+    ///
+    /// # Errors
+    /// Return error when there's any invalid path in `paths_to_watch`, the configuration is
+    /// rejected, or `FSEvents` fails to create or start the stream.
+    ///
+    /// # Panics
+    /// Panic when the given flags combination is illegal.
+    pub fn build_owned(self) -> io::Result<OwnedEventStream> {
+        let (stream, handler) = self.build()?;
+        Ok(OwnedEventStream { stream, handler })
+    }
 
-impl Display for Event {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "[{}] path: {:?}({}), flags: {} ({:x})",
-            self.id,
-            self.path,
-            self.inode.unwrap_or(-1),
-            self.flags,
-            self.raw_flags
-        )
+    /// Create a new [`EventStreamHandler`](EventStreamHandler) which dispatches event batches to
+    /// `handler` on the `RunLoop` thread.
+    ///
+    /// See [`create_event_stream_with_handler`](create_event_stream_with_handler) for details.
+    ///
+    /// # Errors
+    /// Return error when there's any invalid path in `paths_to_watch`, the configuration is
+    /// rejected, or `FSEvents` fails to create or start the stream.
+    ///
+    /// # Panics
+    /// Panic when the given flags combination is illegal.
+    pub fn build_with_handler(self, handler: impl EventHandler) -> io::Result<EventStreamHandler> {
+        self.spawn(EventSink::Parsed(BatchSink::Handler(Arc::new(Mutex::new(
+            Box::new(handler),
+        )))))
     }
-}
 
-/// A stream of `FSEvents` API event batches.
-///
-/// You may want a stream of [`Event`](Event) instead of a stream of batches of it.
-/// Call [`EventStream::into_flatten`](EventStream::into_flatten) to get one.
-///
-/// Call [`create_event_stream`](create_event_stream) to create it.
-pub struct EventStream {
-    #[cfg(feature = "tokio")]
-    stream: ReceiverStream<Vec<Event>>,
-    #[cfg(feature = "async-std")]
-    stream: async_std::channel::Receiver<Vec<Event>>,
-}
+    /// Create a new [`EventStreamHandler`](EventStreamHandler) which sends event batches through
+    /// `sender`.
+    ///
+    /// See [`create_event_stream_with_sender`](create_event_stream_with_sender) for details.
+    ///
+    /// # Errors
+    /// Return error when there's any invalid path in `paths_to_watch`, the configuration is
+    /// rejected, or `FSEvents` fails to create or start the stream.
+    ///
+    /// # Panics
+    /// Panic when the given flags combination is illegal.
+    pub fn build_with_sender(self, sender: BatchSender) -> io::Result<EventStreamHandler> {
+        let policy = self.delivery_policy;
+        self.build_with_handler(move |events: Vec<Event>| match policy {
+            DeliveryPolicy::DropOnFull => match sender.try_send(events) {
+                Ok(()) => {}
+                Err(TrySendError::Closed(_)) => debug!("Event channel is closed, batch discarded"),
+                Err(e) => error!("Unable to send event from callback: {}", e),
+            },
+            DeliveryPolicy::BlockOnFull => {
+                if runtime::blocking_send(&sender, events).is_err() {
+                    debug!("Event channel is closed, batch discarded");
+                }
+            }
+        })
+    }
 
-impl EventStream {
-    /// Flatten event batches and produce a stream of [`Event`](Event).
-    pub fn into_flatten(self) -> impl Stream<Item = Event> {
-        self.flat_map(iter)
+    /// Create a new [`EventStreamHandler`](EventStreamHandler) which dispatches borrowed event
+    /// batches to `handler` on the `RunLoop` thread.
+    ///
+    /// See [`RawEventHandler`](RawEventHandler) for details.
+    ///
+    /// # Errors
+    /// Return error when there's any invalid path in `paths_to_watch`, the configuration is
+    /// rejected, or `FSEvents` fails to create or start the stream.
+    ///
+    /// # Panics
+    /// Panic when the given flags combination is illegal.
+    pub fn build_with_raw_handler(
+        self,
+        handler: impl RawEventHandler,
+    ) -> io::Result<EventStreamHandler> {
+        self.spawn(EventSink::Raw(Arc::new(Mutex::new(Box::new(handler)))))
     }
-}
 
-impl Stream for EventStream {
-    type Item = Vec<Event>;
+    fn validate(&self) -> io::Result<()> {
+        if self.latency > MAX_LATENCY {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "latency too large: {:?} given, but at most {:?} is allowed",
+                    self.latency, MAX_LATENCY
+                ),
+            ));
+        }
+        if self.paths_to_watch.len() > self.max_paths {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                TooManyPathsError {
+                    count: self.paths_to_watch.len(),
+                    max: self.max_paths,
+                },
+            ));
+        }
+        if self.exclusion_paths.len() > MAX_EXCLUSION_PATHS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "too many exclusion paths: {} given, but at most {} are allowed",
+                    self.exclusion_paths.len(),
+                    MAX_EXCLUSION_PATHS
+                ),
+            ));
+        }
+        if self.capture_extended_data
+            && !self.flags.contains(kFSEventStreamCreateFlagUseExtendedData)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "capturing extended data requires kFSEventStreamCreateFlagUseExtendedData",
+            ));
+        }
+        Ok(())
+    }
 
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        self.stream.poll_next_unpin(cx)
+    fn watch_roots(&self) -> Vec<PathBuf> {
+        if self.device.is_some() {
+            return self.paths_to_watch.clone();
+        }
+        // A path which can't be canonicalized, e.g. because it doesn't exist yet, is kept as is.
+        self.paths_to_watch
+            .iter()
+            .map(|path| path.canonicalize().unwrap_or_else(|_| path.clone()))
+            .collect()
     }
-}
 
-pub(crate) struct StreamContextInfo {
-    #[cfg(feature = "tokio")]
-    event_handler: tokio::sync::mpsc::Sender<Vec<Event>>,
-    #[cfg(feature = "async-std")]
-    event_handler: async_std::channel::Sender<Vec<Event>>,
-}
+    fn spawn(&self, sink: EventSink) -> io::Result<EventStreamHandler> {
+        if self.flags.contains(kFSEventStreamCreateFlagUseExtendedData)
+            && !self.flags.contains(kFSEventStreamCreateFlagUseCFTypes)
+        {
+            panic!("UseExtendedData requires UseCFTypes");
+        }
+        self.validate()?;
 
-impl_release_callback!(release_context, StreamContextInfo);
+        let mut backoff = self.backoff;
+        let mut attempt = 1;
+        loop {
+            match self.try_spawn(sink.clone()) {
+                Err(e) if attempt < self.attempts && e.kind() != io::ErrorKind::InvalidInput => {
+                    debug!(
+                        "Attempt {} to create stream failed, retrying in {:?}: {}",
+                        attempt, backoff, e
+                    );
+                    thread::sleep(backoff);
+                    backoff = backoff.saturating_mul(2);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
 
-struct SendWrapper<T>(T);
+    fn try_spawn(&self, sink: EventSink) -> io::Result<EventStreamHandler> {
+        let flags = self.flags;
 
-unsafe impl<T> Send for SendWrapper<T> {}
+        // We need to associate the stream context with our callback in order to propagate events
+        // to the rest of the system. This will be owned by the stream, and will be freed when the
+        // stream is closed. If the stream can't be created, it's released right away. This means
+        // we will leak the context if we panic before reacing `FSEventStreamRelease`.
+        let diagnostics = Arc::new(Diagnostics::default());
+        let context = StreamContextInfo {
+            sink: sink.clone(),
+            seq: AtomicU64::new(0),
+            log_level: self.log_level,
+            flags_mask: self.flags_mask,
+            ignored_roots: if self.ignore_watch_root_events {
+                self.watch_roots()
+            } else {
+                vec![]
+            },
+            capture_extended_data: self.capture_extended_data,
+            record_received_at: self.record_received_at,
+            panic_handler: self.panic_handler.clone(),
+            diagnostics: diagnostics.clone(),
+        };
 
-impl<T> SendWrapper<T> {
-    const unsafe fn new(t: T) -> Self {
-        Self(t)
+        let stream_context = SysFSEventStreamContext::new(context, release_context);
+
+        let callback = if flags.contains(kFSEventStreamCreateFlagUseCFTypes) {
+            if flags.contains(kFSEventStreamCreateFlagUseExtendedData) {
+                if flags.contains(kFSEventStreamCreateFlagFileEvents) {
+                    cf_ext_with_id_callback
+                } else {
+                    cf_ext_callback
+                }
+            } else {
+                cf_callback
+            }
+        } else {
+            normal_callback
+        };
+
+        let created = if let Some(device) = self.device {
+            SysFSEventStream::new_relative_to_device(
+                callback,
+                &stream_context,
+                device,
+                &self.paths_to_watch,
+                self.since_when,
+                self.latency,
+                flags,
+            )
+        } else if let Some(prepared) = &self.prepared_paths {
+            SysFSEventStream::new_with_cf_paths(
+                callback,
+                &stream_context,
+                &prepared.cf_paths,
+                self.since_when,
+                self.latency,
+                flags,
+            )
+        } else {
+            SysFSEventStream::new(
+                callback,
+                &stream_context,
+                &self.paths_to_watch,
+                self.since_when,
+                self.latency,
+                flags,
+            )
+        };
+        let mut stream = match created {
+            Ok(stream) => stream,
+            Err(e) => {
+                // No stream owns the context, which holds a sender and would keep the channel
+                // open forever.
+                unsafe { stream_context.release() };
+                return Err(e);
+            }
+        };
+        if !self.exclusion_paths.is_empty() && !stream.set_exclusion_paths(&self.exclusion_paths)? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "exclusion paths are rejected by FSEvents",
+            ));
+        }
+        let stream = Arc::new(Mutex::new(Some(stream)));
+        let worker_stream = stream.clone();
+        let worker_diagnostics = diagnostics.clone();
+
+        // channel to pass runloop around
+        let (runloop_tx, runloop_rx) = channel();
+        let exited = Arc::new(AtomicBool::new(false));
+        let worker_exited = exited.clone();
+        let sink = Arc::new(Mutex::new(Some(sink)));
+        let worker_sink = sink.clone();
+
+        // The span is created here so that it's a child of the caller's current span, and entered
+        // in the worker thread so that it also covers the FSEvents callback.
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!(
+            "fsevent_stream_worker",
+            path_count = self.paths_to_watch.len(),
+            flags = flags,
+            latency = ?self.latency,
+        );
+
+        let thread_handle = thread::spawn(move || {
+            let _exit_guard = ExitGuard {
+                exited: worker_exited,
+                sink: worker_sink,
+            };
+            #[cfg(feature = "tracing")]
+            let _entered = span.enter();
+
+            #[cfg(test)]
+            TEST_RUNNING_RUNLOOP_COUNT.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+
+            let current_runloop = CFRunLoop::get_current();
+
+            let started = worker_stream
+                .lock()
+                .expect("stream lock not to be poisoned")
+                .as_mut()
+                .is_some_and(|stream| {
+                    stream.schedule(&current_runloop, unsafe { kCFRunLoopDefaultMode });
+                    stream.start()
+                });
+            if !started {
+                release_worker_stream(&worker_stream, false);
+                // The constructor may be gone already, in which case there's no one to report to.
+                drop(runloop_tx.send(Err(io::Error::other("FSEvents failed to start the stream"))));
+                return;
+            }
+            worker_diagnostics.record_start();
+
+            // the calling to CFRunLoopRun will be terminated by CFRunLoopStop call in drop()
+            // Safety:
+            // - According to the Apple documentation, it's safe to move `CFRef`s across threads.
+            //   https://developer.apple.com/library/archive/documentation/Cocoa/Conceptual/Multithreading/ThreadSafetySummary/ThreadSafetySummary.html
+            if runloop_tx
+                .send(Ok(unsafe { SendWrapper::new(current_runloop) }))
+                .is_err()
+            {
+                // The constructor has unwound before receiving the RunLoop, so no handler can
+                // ever stop it. Shut down instead of running forever.
+                error!("Stream constructor is gone, shutting down the worker");
+                release_worker_stream(&worker_stream, true);
+                return;
+            }
+
+            CFRunLoop::run_current();
+            release_worker_stream(&worker_stream, true);
+        });
+
+        // The sender is only dropped without sending if the worker thread has panicked.
+        let received = runloop_rx
+            .recv()
+            .unwrap_or_else(|_| Err(io::Error::other("worker thread exited before starting")));
+        let runloop = match received {
+            Ok(runloop) => runloop,
+            Err(e) => {
+                drop(thread_handle.join());
+                return Err(e);
+            }
+        };
+        Ok(EventStreamHandler {
+            flags,
+            worker: Some(Worker {
+                token: AbortToken::new(runloop.0, thread_handle.thread().id(), exited),
+                thread_handle,
+                diagnostics,
+                stream,
+                builder: self.clone(),
+                sink,
+            }),
+        })
+    }
+}
+
+// Stop and release the stream of a worker thread about to exit, so that its context, and the
+// event sender along with it, is freed. The channel is closed once the exit guard drops the
+// sender of the handler. `started` tells whether the stream has been started, and must be stopped.
+fn release_worker_stream(worker_stream: &Mutex<Option<SysFSEventStream>>, started: bool) {
+    let stream = worker_stream
+        .lock()
+        .expect("stream lock not to be poisoned")
+        .take();
+    if let Some(mut stream) = stream {
+        if started {
+            stream.stop();
+        }
+        // Invalidating the stream unschedules it from the RunLoop.
+        stream.invalidate();
+        drop(stream);
     }
+
+    #[cfg(test)]
+    TEST_RUNNING_RUNLOOP_COUNT.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
 }
 
 /// Create a new [`EventStream`](EventStream) and [`EventStreamHandler`](EventStreamHandler) pair.
 ///
+/// Use [`EventStreamBuilder`](EventStreamBuilder) for more options.
+///
 /// # Errors
-/// Return error when there's any invalid path in `paths_to_watch`.
+/// Return error when there's any invalid path in `paths_to_watch`, `latency` exceeds
+/// [`MAX_LATENCY`](MAX_LATENCY), or `FSEvents` fails to create or start the stream.
 ///
 /// # Panics
 /// Panic when the given flags combination is illegal.
@@ -176,101 +2183,253 @@ pub fn create_event_stream<P: AsRef<Path>>(
     latency: Duration,
     flags: FSEventStreamCreateFlags,
 ) -> io::Result<(EventStream, EventStreamHandler)> {
-    if flags.contains(kFSEventStreamCreateFlagUseExtendedData)
-        && !flags.contains(kFSEventStreamCreateFlagUseCFTypes)
-    {
-        panic!("UseExtendedData requires UseCFTypes");
-    }
-
-    #[cfg(feature = "tokio")]
-    let (event_tx, event_rx) = tokio::sync::mpsc::channel(1024);
-    #[cfg(feature = "async-std")]
-    let (event_tx, event_rx) = async_std::channel::bounded(1024);
-
-    // We need to associate the stream context with our callback in order to propagate events
-    // to the rest of the system. This will be owned by the stream, and will be freed when the
-    // stream is closed. This means we will leak the context if we panic before reacing
-    // `FSEventStreamRelease`.
-    let context = StreamContextInfo {
-        event_handler: event_tx,
-    };
+    EventStreamBuilder::new(paths_to_watch)
+        .since_when(since_when)
+        .latency(latency)
+        .flags(flags)
+        .build()
+}
 
-    let stream_context = SysFSEventStreamContext::new(context, release_context);
+/// Create a new [`EventStream`](EventStream) and [`EventStreamHandler`](EventStreamHandler) pair
+/// which watches paths on `device`.
+///
+/// Unlike [`create_event_stream`](create_event_stream), `paths_to_watch_relative_to_device` are
+/// relative to the root of `device`, and so are the paths of delivered events.
+///
+/// # Errors
+/// Return error when there's any non UTF-8 path in `paths_to_watch_relative_to_device`, or
+/// `latency` exceeds [`MAX_LATENCY`](MAX_LATENCY).
+///
+/// # Panics
+/// Panic when the given flags combination is illegal.
+pub fn create_event_stream_relative_to_device<P: AsRef<Path>>(
+    device: dev_t,
+    paths_to_watch_relative_to_device: impl IntoIterator<Item = P>,
+    since_when: FSEventStreamEventId,
+    latency: Duration,
+    flags: FSEventStreamCreateFlags,
+) -> io::Result<(EventStream, EventStreamHandler)> {
+    EventStreamBuilder::new(paths_to_watch_relative_to_device)
+        .device(device)
+        .since_when(since_when)
+        .latency(latency)
+        .flags(flags)
+        .build()
+}
 
-    let callback = if flags.contains(kFSEventStreamCreateFlagUseCFTypes) {
-        if flags.contains(kFSEventStreamCreateFlagUseExtendedData) {
-            if flags.contains(kFSEventStreamCreateFlagFileEvents) {
-                cf_ext_with_id_callback
-            } else {
-                cf_ext_callback
-            }
-        } else {
-            cf_callback
-        }
-    } else {
-        normal_callback
-    };
+/// Watch the given paths and dispatch event batches to `handler` on the `RunLoop` thread.
+///
+/// This is a push-based alternative to [`create_event_stream`](create_event_stream) for those who
+/// want zero-buffering synchronous dispatch. No [`EventStream`](EventStream) is created.
+///
+/// # Errors
+/// Return error when there's any invalid path in `paths_to_watch`.
+///
+/// # Panics
+/// Panic when the given flags combination is illegal.
+pub fn create_event_stream_with_handler<P: AsRef<Path>>(
+    paths_to_watch: impl IntoIterator<Item = P>,
+    since_when: FSEventStreamEventId,
+    latency: Duration,
+    flags: FSEventStreamCreateFlags,
+    handler: impl EventHandler,
+) -> io::Result<EventStreamHandler> {
+    EventStreamBuilder::new(paths_to_watch)
+        .since_when(since_when)
+        .latency(latency)
+        .flags(flags)
+        .build_with_handler(handler)
+}
 
-    let mut stream = SysFSEventStream::new(
-        callback,
-        &stream_context,
-        paths_to_watch,
-        since_when,
-        latency,
-        flags,
-    )?;
-
-    // channel to pass runloop around
-    let (runloop_tx, runloop_rx) = channel();
-
-    let thread_handle = thread::spawn(move || {
-        #[cfg(test)]
-        TEST_RUNNING_RUNLOOP_COUNT.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-
-        let current_runloop = CFRunLoop::get_current();
-
-        stream.schedule(&current_runloop, unsafe { kCFRunLoopDefaultMode });
-        stream.start();
-
-        // the calling to CFRunLoopRun will be terminated by CFRunLoopStop call in drop()
-        // Safety:
-        // - According to the Apple documentation, it's safe to move `CFRef`s across threads.
-        //   https://developer.apple.com/library/archive/documentation/Cocoa/Conceptual/Multithreading/ThreadSafetySummary/ThreadSafetySummary.html
-        runloop_tx
-            .send(unsafe { SendWrapper::new(current_runloop) })
-            .expect("send runloop to stream");
-
-        CFRunLoop::run_current();
-        stream.stop();
-        stream.invalidate();
+/// Watch the given paths and send event batches through `sender`, the sending half of a channel
+/// owned by the caller.
+///
+/// Events flow into an existing pipeline without a forwarding task, and the capacity of the
+/// channel is up to the caller. Batches are dropped when the channel is full, unless
+/// [`DeliveryPolicy::BlockOnFull`](DeliveryPolicy::BlockOnFull) is set with
+/// [`EventStreamBuilder::build_with_sender`](EventStreamBuilder::build_with_sender). Dropping the
+/// receiver doesn't stop the stream, so call
+/// [`EventStreamHandler::abort`](EventStreamHandler::abort) when done.
+///
+/// # Errors
+/// Return error when there's any invalid path in `paths_to_watch`.
+///
+/// # Panics
+/// Panic when the given flags combination is illegal.
+pub fn create_event_stream_with_sender<P: AsRef<Path>>(
+    paths_to_watch: impl IntoIterator<Item = P>,
+    since_when: FSEventStreamEventId,
+    latency: Duration,
+    flags: FSEventStreamCreateFlags,
+    sender: BatchSender,
+) -> io::Result<EventStreamHandler> {
+    EventStreamBuilder::new(paths_to_watch)
+        .since_when(since_when)
+        .latency(latency)
+        .flags(flags)
+        .build_with_sender(sender)
+}
 
-        #[cfg(test)]
-        TEST_RUNNING_RUNLOOP_COUNT.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
-    });
-
-    #[cfg(feature = "tokio")]
-    let stream = ReceiverStream::new(event_rx);
-    #[cfg(feature = "async-std")]
-    let stream = event_rx;
-    Ok((
-        EventStream { stream },
-        EventStreamHandler {
-            runloop: Some((
-                runloop_rx.recv().expect("receive runloop from worker").0,
-                thread_handle,
-            )),
-        },
-    ))
+/// Watch the given paths and call `handler` with every event batch.
+///
+/// This is a shortcut of [`create_event_stream_with_handler`](create_event_stream_with_handler)
+/// for closures. No channel is allocated, so there's no buffering and no backpressure: each batch
+/// is handed to `handler` as soon as `FSEvents` delivers it.
+///
+/// # Threading
+/// `handler` is called on the `RunLoop` thread. While it runs, no further events can be
+/// delivered and the stream can't be aborted, so it must not block for long. Offload expensive
+/// work to another thread if needed.
+///
+/// # Errors
+/// Return error when there's any invalid path in `paths_to_watch`.
+///
+/// # Panics
+/// Panic when the given flags combination is illegal.
+pub fn watch<P: AsRef<Path>>(
+    paths_to_watch: impl IntoIterator<Item = P>,
+    since_when: FSEventStreamEventId,
+    latency: Duration,
+    flags: FSEventStreamCreateFlags,
+    handler: impl FnMut(Vec<Event>) + Send + 'static,
+) -> io::Result<EventStreamHandler> {
+    create_event_stream_with_handler(paths_to_watch, since_when, latency, flags, handler)
+}
+
+/// Latency used by [`watch_home_tree`](watch_home_tree).
+pub const HOME_TREE_LATENCY: Duration = Duration::from_secs(1);
+
+/// Watch the home directory of the current user with defaults suitable for desktop apps.
+///
+/// The stream is created with file-level events, extended data (so events carry inodes), and
+/// events caused by this process ignored. Events are delivered with a latency of
+/// [`HOME_TREE_LATENCY`](HOME_TREE_LATENCY), which keeps the overhead of watching a large tree
+/// low.
+///
+/// `exclusions` are filtered out by `FSEvents` itself, and relative paths are resolved against
+/// the home directory. At most [`MAX_EXCLUSION_PATHS`](MAX_EXCLUSION_PATHS) paths are allowed.
+///
+/// # Errors
+/// Return error when `$HOME` isn't set, or there are too many exclusion paths.
+pub fn watch_home_tree(exclusions: Vec<PathBuf>) -> io::Result<(EventStream, EventStreamHandler)> {
+    let home = std::env::var_os("HOME")
+        .map(PathBuf::from)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "$HOME is not set"))?;
+    let exclusions = exclusions.iter().map(|path| home.join(path)).collect();
+    EventStreamBuilder::new([&home])
+        .latency(HOME_TREE_LATENCY)
+        .flags(
+            kFSEventStreamCreateFlagFileEvents
+                | kFSEventStreamCreateFlagUseCFTypes
+                | kFSEventStreamCreateFlagUseExtendedData,
+        )
+        .ignore_self(true)
+        .exclusion_paths(exclusions)
+        .build()
+}
+
+/// Read the file id in the extended data as an inode.
+///
+/// `FSEvents` stores file ids as `SInt64`, but inodes are unsigned 64-bit integers and may exceed
+/// `i64::MAX` on APFS. The bits are reinterpreted rather than converted, so such ids aren't lost.
+#[allow(clippy::cast_sign_loss)]
+pub(crate) fn file_id_to_inode(file_id: &CFNumber) -> Option<u64> {
+    file_id.to_i64().map(|file_id| file_id as u64)
+}
+
+/// Copy all entries of an extended data dictionary, keyed by their string keys.
+pub(crate) fn extended_data_to_map(
+    dict: &CFDictionary<CFString>,
+) -> BTreeMap<String, ExtendedValue> {
+    let (keys, values) = dict.get_keys_and_values();
+    keys.into_iter()
+        .zip(values)
+        .map(|(key, value)| {
+            let key = unsafe { CFString::wrap_under_get_rule(key as CFStringRef) }.to_string();
+            let value = unsafe { CFType::wrap_under_get_rule(value as CFTypeRef) };
+            let value = if let Some(value) = value.downcast::<CFString>() {
+                ExtendedValue::String(value.to_string())
+            } else if let Some(value) = value
+                .downcast::<CFNumber>()
+                .filter(|value| !cf_number_is_float(value))
+                .and_then(|value| value.to_i64())
+            {
+                ExtendedValue::Integer(value)
+            } else {
+                ExtendedValue::Other(format!("{:?}", value))
+            };
+            (key, value)
+        })
+        .collect()
+}
+
+/// Convert a path reported as `CFString` into a [`PathBuf`](PathBuf) with a single allocation.
+///
+/// The UTF-8 bytes of the string are taken as is, without normalization, so for valid UTF-8
+/// paths the result is identical to the path `FSEvents` reports as a C string without
+/// `kFSEventStreamCreateFlagUseCFTypes`. This isn't lossless in general: a `CFString` can't hold
+/// bytes which aren't valid UTF-8, so such paths are only kept as is by the C string callback.
+/// `None` is returned if the string itself is malformed, e.g. because it contains an unpaired
+/// surrogate.
+#[cfg(test)]
+pub(crate) fn cf_string_to_path(path: &CFString) -> Option<PathBuf> {
+    unsafe { cf_string_as_path(path) }.map(Cow::into_owned)
 }
 
-enum CallbackError {
-    ToI64,
-    ParseFlags,
+/// Borrow the path in a `CFString` if it's stored as UTF-8, or convert it to UTF-8 otherwise.
+///
+/// The UTF-8 bytes of the string are taken as is, without normalization. `None` is returned only
+/// if the string can't be encoded as UTF-8.
+///
+/// # Safety
+/// The returned path must not outlive the underlying string.
+#[allow(clippy::cast_sign_loss)]
+unsafe fn cf_string_as_path<'a>(path: &CFString) -> Option<Cow<'a, Path>> {
+    let path_ref = path.as_concrete_TypeRef();
+    // Fast path: borrow the UTF-8 buffer if the string is stored that way.
+    let c_str = CFStringGetCStringPtr(path_ref, kCFStringEncodingUTF8);
+    if !c_str.is_null() {
+        let bytes = CStr::from_ptr(c_str).to_bytes();
+        return Some(Cow::Borrowed(Path::new(OsStr::from_bytes(bytes))));
+    }
+
+    let range = CFRange {
+        location: 0,
+        length: path.char_len(),
+    };
+    // Ask for the size of the buffer first, then copy the bytes into it.
+    let mut len: CFIndex = 0;
+    let converted = CFStringGetBytes(
+        path_ref,
+        range,
+        kCFStringEncodingUTF8,
+        0,
+        false as Boolean,
+        ptr::null_mut(),
+        0,
+        &mut len,
+    );
+    if converted != range.length {
+        return None;
+    }
+    let mut buffer = vec![0; len as usize];
+    CFStringGetBytes(
+        path_ref,
+        range,
+        kCFStringEncodingUTF8,
+        0,
+        false as Boolean,
+        buffer.as_mut_ptr(),
+        len,
+        &mut len,
+    );
+    buffer.truncate(len as usize);
+    Some(Cow::Owned(PathBuf::from(OsString::from_vec(buffer))))
 }
 
 macro_rules! define_callback {
-    ($name: ident, ($num: ident, $paths: ident, $flags: ident, $ids: ident)$body: block) => {
-        extern "C" fn $name(
+    ($name: ident, ($indices: ident, $paths: ident, $flags: ident, $ids: ident, $extended: ident)$body: block) => {
+        pub(crate) extern "C" fn $name(
             stream_ref: SysFSEventStreamRef,
             info: *mut c_void,
             num_events: usize,                           // size_t numEvents
@@ -286,40 +2445,92 @@ macro_rules! define_callback {
                 event_flags: *const FSEventStreamEventFlags, // const FSEventStreamEventFlags eventFlags[]
                 event_ids: *const FSEventStreamEventId, // const FSEventStreamEventId eventIds[]
             ) {
-                fn event_iter(
-                    $num: usize,
+                fn event_iter<'a>(
+                    $indices: impl Iterator<Item = usize>,
                     $paths: *mut c_void,
                     $flags: *const FSEventStreamEventFlags,
                     $ids: *const FSEventStreamEventId,
-                ) -> impl Iterator<Item = Result<Event, CallbackError>> {
+                    $extended: bool,
+                ) -> impl Iterator<Item = Result<RawEventRef<'a>, EventError>> {
                     $body
                 }
 
                 debug!("Received {} event(s)", num_events);
 
-                let info = info as *const StreamContextInfo;
-                let event_handler = unsafe { &(*info).event_handler };
-
-                let events = event_iter(num_events, event_paths, event_flags, event_ids)
-                    .filter_map(|event| {
-                        if let Err(e) = &event {
-                            match e {
-                                CallbackError::ToI64 => {
-                                    error!("Unable to convert inode field to i64")
-                                }
-                                CallbackError::ParseFlags => error!("Unable to parse flags"),
+                let info = unsafe { &*(info as *const StreamContextInfo) };
+                let seq = info.seq.fetch_add(1, Ordering::Relaxed);
+                info.diagnostics.record_batch(num_events);
+
+                // Skip events not matching the mask before constructing them.
+                let mask = info.flags_mask;
+                let indices = (0..num_events).filter(move |&idx| {
+                    mask.map_or(true, |mask| {
+                        StreamFlags::from_bits_truncate(unsafe { *event_flags.add(idx) })
+                            .intersects(mask)
+                    })
+                });
+                let raw_events = event_iter(
+                    indices,
+                    event_paths,
+                    event_flags,
+                    event_ids,
+                    info.capture_extended_data,
+                );
+                let sink = match &info.sink {
+                    EventSink::Parsed(sink) => sink,
+                    EventSink::Raw(handler) => {
+                        // The filtered iterator has no size hint, so allocate once for the whole
+                        // batch instead of growing the vector per event. Batches can hold
+                        // thousands of events during bulk operations.
+                        let mut events = Vec::with_capacity(num_events);
+                        events.extend(raw_events.filter_map(|event| match event {
+                            Ok(event) => Some(event),
+                            Err(e) => {
+                                error!("{}", e);
+                                None
+                            }
+                        }));
+                        if mask.is_none() || !events.is_empty() {
+                            match handler.lock() {
+                                Ok(mut handler) => handler.handle_raw_events(&events),
+                                Err(_) => error!("Event handler is poisoned by a previous panic"),
                             }
                         }
-                        event.ok()
-                    })
-                    .collect();
-
-                if let Err(e) = event_handler.try_send(events) {
-                    error!("Unable to send event from callback: {}", e);
+                        return;
+                    }
+                };
+                // Allocate once for the whole batch, as for raw events.
+                let mut events = Vec::with_capacity(num_events);
+                events.extend(raw_events.map(|event| event.and_then(RawEventRef::into_event)));
+                if info.record_received_at {
+                    let now = SystemTime::now();
+                    for event in events.iter_mut().flatten() {
+                        event.received_at = Some(now);
+                    }
+                }
+                if !info.ignored_roots.is_empty() {
+                    events.retain(|event| {
+                        event.as_ref().map_or(true, |event| {
+                            event.flags.intersects(ROOT_EVENT_KEPT_FLAGS)
+                                || !info.ignored_roots.contains(&event.path)
+                        })
+                    });
+                }
+                if (mask.is_some() || !info.ignored_roots.is_empty()) && events.is_empty() {
+                    return;
+                }
+                #[cfg(feature = "tracing")]
+                tracing::debug!(seq, batch_size = events.len(), "delivering batch");
+                if let Some(level) = info.log_level {
+                    for event in events.iter().flatten() {
+                        log!(level, "{}", event);
+                    }
                 }
+
+                sink.deliver(seq, events);
             }
 
-            drop(catch_unwind(move || {
+            if let Err(payload) = catch_unwind(move || {
                 callback_impl(
                     stream_ref,
                     info,
@@ -328,105 +2539,90 @@ macro_rules! define_callback {
                     event_flags,
                     event_ids,
                 );
-            }));
+            }) {
+                error!(
+                    "Panicked while processing events: {}",
+                    panic_message(&*payload)
+                );
+                let info = unsafe { &*(info as *const StreamContextInfo) };
+                if let Some(handler) = &info.panic_handler {
+                    drop(catch_unwind(AssertUnwindSafe(|| (handler.0)(payload))));
+                }
+            }
         }
     };
 }
 
-define_callback!(cf_ext_with_id_callback, (num, paths, flags, ids){
+define_callback!(cf_ext_with_id_callback, (indices, paths, flags, ids, extended){
     let paths = unsafe { CFArray::<CFDictionary<CFString>>::from_void(paths) };
-    (0..num).map(move |idx| {
-        Ok((
-            unsafe { paths.get_unchecked(idx as CFIndex) },
-            unsafe { *flags.add(idx) },
-            unsafe { *ids.add(idx) },
-        ))
-        .and_then(|(dict, flags, id)| {
-            Ok(Event {
-                path: PathBuf::from(
-                    (*unsafe {
-                        CFString::from_void(*dict.get(&*kFSEventStreamEventExtendedDataPathKey),)
-                    })
-                        .to_string(),
-                ),
-                inode: Some(
-                    unsafe {CFNumber::from_void(*dict.get(&*kFSEventStreamEventExtendedFileIDKey))}
-                        .to_i64()
-                        .ok_or(CallbackError::ToI64)?,
-                ),
-                flags: StreamFlags::from_bits(flags).ok_or(CallbackError::ParseFlags)?,
-                raw_flags: flags,
-                id,
-            })
+    indices.map(move |idx| {
+        let dict = unsafe { paths.get_unchecked(idx as CFIndex) };
+        let id = unsafe { *ids.add(idx) };
+        let path = dict
+            .find(&*kFSEventStreamEventExtendedDataPathKey)
+            .ok_or(EventError::MissingKey { id, key: "path" })?;
+        let file_id = dict
+            .find(&*kFSEventStreamEventExtendedFileIDKey)
+            .ok_or(EventError::MissingKey { id, key: "fileID" })?;
+        let file_id = unsafe { CFNumber::from_void(*file_id) };
+        Ok(RawEventRef {
+            path: unsafe { cf_string_as_path(&CFString::from_void(*path)) }
+                .ok_or(EventError::InvalidPath { id })?,
+            inode: Some(
+                file_id_to_inode(&file_id)
+                    .ok_or(EventError::InvalidInode { id })?,
+            ),
+            flags: unsafe { *flags.add(idx) },
+            id,
+            extended_data: extended.then(|| extended_data_to_map(&dict)),
         })
     })
 });
 
-define_callback!(cf_ext_callback, (num, paths, flags, ids){
+define_callback!(cf_ext_callback, (indices, paths, flags, ids, extended){
     let paths = unsafe { CFArray::<CFDictionary<CFString>>::from_void(paths) };
-    (0..num).map(move |idx| {
-        Ok((
-            unsafe { paths.get_unchecked(idx as CFIndex) },
-            unsafe { *flags.add(idx) },
-            unsafe { *ids.add(idx) },
-        ))
-        .and_then(|(dict, flags, id)| {
-            Ok(Event {
-                path: PathBuf::from(
-                    (*unsafe {
-                        CFString::from_void(*dict.get(&*kFSEventStreamEventExtendedDataPathKey),)
-                    })
-                        .to_string(),
-                ),
-                inode: None,
-                flags: StreamFlags::from_bits(flags).ok_or(CallbackError::ParseFlags)?,
-                raw_flags: flags,
-                id,
-            })
+    indices.map(move |idx| {
+        let dict = unsafe { paths.get_unchecked(idx as CFIndex) };
+        let id = unsafe { *ids.add(idx) };
+        let path = dict
+            .find(&*kFSEventStreamEventExtendedDataPathKey)
+            .ok_or(EventError::MissingKey { id, key: "path" })?;
+        Ok(RawEventRef {
+            path: unsafe { cf_string_as_path(&CFString::from_void(*path)) }
+                .ok_or(EventError::InvalidPath { id })?,
+            inode: None,
+            flags: unsafe { *flags.add(idx) },
+            id,
+            extended_data: extended.then(|| extended_data_to_map(&dict)),
         })
     })
 });
 
-define_callback!(cf_callback, (num, paths, flags, ids){
+define_callback!(cf_callback, (indices, paths, flags, ids, _extended){
     let paths = unsafe { CFArray::<CFString>::from_void(paths) };
-    (0..num).map(move |idx| {
-        Ok((
-            unsafe { paths.get_unchecked(idx as CFIndex) },
-            unsafe { *flags.add(idx) },
-            unsafe { *ids.add(idx) },
-        ))
-            .and_then(|(path, flags, id)| {
-                Ok(Event {
-                    path: PathBuf::from((*path).to_string()),
-                    inode: None,
-                    flags: StreamFlags::from_bits(flags)
-                        .ok_or(CallbackError::ParseFlags)?,
-                    raw_flags: flags,
-                    id,
-                })
-            })
+    indices.map(move |idx| {
+        let id = unsafe { *ids.add(idx) };
+        Ok(RawEventRef {
+            path: unsafe { cf_string_as_path(&paths.get_unchecked(idx as CFIndex)) }
+                .ok_or(EventError::InvalidPath { id })?,
+            inode: None,
+            flags: unsafe { *flags.add(idx) },
+            id,
+            extended_data: None,
+        })
     })
 });
 
-define_callback!(normal_callback, (num, paths, flags, ids){
+define_callback!(normal_callback, (indices, paths, flags, ids, _extended){
     let paths = paths as *const *const c_char;
-    (0..num).map(move |idx| {
-        Ok((
-            unsafe { *paths.add(idx) },
-            unsafe { *flags.add(idx) },
-            unsafe { *ids.add(idx) },
-        ))
-        .and_then(|(path, flags, id)| {
-            Ok(Event {
-                path: PathBuf::from(
-                    OsStr::from_bytes(unsafe { CStr::from_ptr(path) }.to_bytes())
-                        .to_os_string(),
-                ),
-                inode: None,
-                flags: StreamFlags::from_bits(flags).ok_or(CallbackError::ParseFlags)?,
-                raw_flags: flags,
-                id,
-            })
+    indices.map(move |idx| {
+        let path = unsafe { CStr::from_ptr(*paths.add(idx)) };
+        Ok(RawEventRef {
+            path: Cow::Borrowed(Path::new(OsStr::from_bytes(path.to_bytes()))),
+            inode: None,
+            flags: unsafe { *flags.add(idx) },
+            id: unsafe { *ids.add(idx) },
+            extended_data: None,
         })
     })
 });
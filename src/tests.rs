@@ -1,35 +1,72 @@
 #![allow(clippy::borrow_interior_mutable_const, clippy::cast_possible_wrap)]
 
+use std::borrow::Cow;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{c_void, CString, OsStr};
 use std::fs;
 use std::fs::File;
-use std::os::unix::fs::MetadataExt;
-use std::sync::atomic::Ordering;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
 #[cfg(feature = "async-std")]
 use async_std1 as async_std;
+use core_foundation::array::CFArray;
+use core_foundation::base::{kCFAllocatorDefault, CFIndex, TCFType};
+use core_foundation::dictionary::CFDictionary;
+use core_foundation::number::CFNumber;
+use core_foundation::string::{CFString, CFStringCreateWithCharacters, CFStringRef};
 use futures_util::stream::{FuturesUnordered, StreamExt};
+use futures_util::FutureExt;
 use once_cell::sync::Lazy;
 use tempfile::tempdir;
 #[cfg(feature = "tokio")]
 use tokio1 as tokio;
 
+use crate::bookmark::{
+    create_event_stream_from_bookmarks, BookmarkData, BookmarkError, CreateFromBookmarksError,
+};
+use crate::debounce::DebouncedEvent;
 use crate::ffi::{
-    kFSEventStreamCreateFlagFileEvents, kFSEventStreamCreateFlagNoDefer,
-    kFSEventStreamCreateFlagNone, kFSEventStreamCreateFlagUseCFTypes,
-    kFSEventStreamCreateFlagUseExtendedData, kFSEventStreamEventIdSinceNow,
-    FSEventStreamCreateFlags,
+    dev_t, device_uuid, kFSEventStreamCreateFlagFileEvents, kFSEventStreamCreateFlagFullHistory,
+    kFSEventStreamCreateFlagNoDefer, kFSEventStreamCreateFlagNone,
+    kFSEventStreamCreateFlagUseCFTypes, kFSEventStreamCreateFlagUseExtendedData,
+    kFSEventStreamEventExtendedDataPathKey, kFSEventStreamEventExtendedFileIDKey,
+    kFSEventStreamEventFlagItemCreated, kFSEventStreamEventIdSinceNow, FSEventStreamCreateFlags,
+    FSEventStreamEventFlags, FSEventStreamEventId, FSEventsGetCurrentEventId,
+    SysFSEventStreamContext, SysFSEventStreamRef,
+};
+use crate::history::{
+    changed_paths_since, compare_ids, fold_net_changes, history_events, is_since_now,
+    last_event_id_before, net_changes_since, resume_point, resume_point_with, EventId, IdTimeline,
+    NetChange,
 };
+use crate::pool::{watch_groups, watch_latency_groups, WatcherPool};
+use crate::runtime;
 use crate::stream::{
-    create_event_stream, StreamContextInfo, StreamFlags, TEST_RUNNING_RUNLOOP_COUNT,
+    cf_callback, cf_ext_callback, cf_ext_with_id_callback, cf_string_to_path, create_event_stream,
+    create_event_stream_relative_to_device, create_event_stream_with_handler,
+    create_event_stream_with_sender, file_id_to_inode, normal_callback, watch, watch_home_tree,
+    Batch, DeliveryPolicy, Event, EventError, EventHandler, EventKind, EventStream,
+    EventStreamBuilder, EventStreamHandler, EventWithParent, ExtendedValue, HandlerStats,
+    PreparedPaths, RawBatch, RawEventRef, StreamContextInfo, StreamDescription, StreamFlags,
+    StreamItem, SymlinkEvent, TooManyPathsError, TransientPolicy, UnmountPolicy,
+    INTERACTIVE_LATENCY, MAX_EXCLUSION_PATHS, MAX_LATENCY, TEST_RUNNING_RUNLOOP_COUNT,
 };
 
 #[cfg(feature = "tokio")]
 static TEST_PARALLEL_LOCK: Lazy<tokio::sync::Mutex<()>> = Lazy::new(|| tokio::sync::Mutex::new(()));
-#[cfg(feature = "async-std")]
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
 static TEST_PARALLEL_LOCK: Lazy<async_std::sync::Mutex<()>> =
     Lazy::new(|| async_std::sync::Mutex::new(()));
 
@@ -45,7 +82,7 @@ async fn must_abort_stream_tokio() {
     must_abort_stream().await;
 }
 
-#[cfg(feature = "async-std")]
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
 #[async_std::test]
 async fn must_abort_stream_async_std() {
     must_abort_stream().await;
@@ -81,7 +118,7 @@ async fn must_abort_stream() {
         .await
         .expect("to complete"),
     );
-    #[cfg(feature = "async-std")]
+    #[cfg(all(feature = "async-std", not(feature = "tokio")))]
     drop(
         async_std::future::timeout(
             Duration::from_secs(1),
@@ -97,13 +134,149 @@ async fn must_abort_stream() {
     abort_thread.join().expect("to join");
 }
 
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_drop_stream_and_abort_in_any_order_tokio() {
+    must_drop_stream_and_abort_in_any_order().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_drop_stream_and_abort_in_any_order_async_std() {
+    must_drop_stream_and_abort_in_any_order().await;
+}
+
+async fn must_drop_stream_and_abort_in_any_order() {
+    // Acquire the lock so that no other runloop can be created during this test.
+    let _guard = TEST_PARALLEL_LOCK.lock().await;
+
+    for policy in [DeliveryPolicy::DropOnFull, DeliveryPolicy::BlockOnFull] {
+        must_drop_stream_before_abort(policy);
+        must_abort_before_dropping_stream(policy).await;
+    }
+}
+
+fn must_drop_stream_before_abort(policy: DeliveryPolicy) {
+    let dir = tempdir().expect("to be created");
+    let (stream, mut handler) = EventStreamBuilder::new([dir.path()])
+        .latency(Duration::ZERO)
+        .flags(kFSEventStreamCreateFlagFileEvents | kFSEventStreamCreateFlagNoDefer)
+        .delivery_policy(policy)
+        .build()
+        .expect("to be created");
+    drop(stream);
+
+    // Batches delivered to the closed channel are discarded.
+    File::create(dir.path().join("test_file")).expect("to be created");
+    unsafe { libc::sync() };
+    sleep(Duration::from_millis(500));
+    assert!(handler.is_running());
+
+    handler.abort();
+    assert!(!handler.is_running());
+    assert_eq!(TEST_RUNNING_RUNLOOP_COUNT.load(Ordering::SeqCst), 0);
+}
+
+async fn must_abort_before_dropping_stream(policy: DeliveryPolicy) {
+    let dir = tempdir().expect("to be created");
+    let (stream, mut handler) = EventStreamBuilder::new([dir.path()])
+        .latency(Duration::ZERO)
+        .flags(kFSEventStreamCreateFlagFileEvents | kFSEventStreamCreateFlagNoDefer)
+        .delivery_policy(policy)
+        .build()
+        .expect("to be created");
+    File::create(dir.path().join("test_file")).expect("to be created");
+    unsafe { libc::sync() };
+
+    handler.abort();
+    assert_eq!(TEST_RUNNING_RUNLOOP_COUNT.load(Ordering::SeqCst), 0);
+    File::create(dir.path().join("after_abort")).expect("to be created");
+
+    // Buffered batches are yielded, then the stream ends.
+    #[cfg(feature = "tokio")]
+    let events = tokio::time::timeout(
+        Duration::from_secs(1),
+        stream.into_flatten().collect::<Vec<_>>(),
+    )
+    .await
+    .expect("to complete");
+    #[cfg(all(feature = "async-std", not(feature = "tokio")))]
+    let events = async_std::future::timeout(
+        Duration::from_secs(1),
+        stream.into_flatten().collect::<Vec<_>>(),
+    )
+    .await
+    .expect("to complete");
+    assert!(events
+        .iter()
+        .all(|event| !event.path.ends_with("after_abort")));
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_not_leak_threads_on_repeated_abort_tokio() {
+    must_not_leak_threads_on_repeated_abort().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_not_leak_threads_on_repeated_abort_async_std() {
+    must_not_leak_threads_on_repeated_abort().await;
+}
+
+async fn must_not_leak_threads_on_repeated_abort() {
+    const CYCLES: usize = 100;
+
+    // Acquire the lock so that no other runloop can be created during this test.
+    let _guard = TEST_PARALLEL_LOCK.lock().await;
+
+    let dir = tempdir().expect("to be created");
+    let baseline = thread_count();
+    for _ in 0..CYCLES {
+        let (stream, mut handler) = create_event_stream(
+            [dir.path()],
+            kFSEventStreamEventIdSinceNow,
+            Duration::ZERO,
+            kFSEventStreamCreateFlagNone,
+        )
+        .expect("to be created");
+        assert_eq!(TEST_RUNNING_RUNLOOP_COUNT.load(Ordering::SeqCst), 1);
+
+        handler.abort();
+        assert!(!handler.is_running());
+        assert_eq!(TEST_RUNNING_RUNLOOP_COUNT.load(Ordering::SeqCst), 0);
+        drop(stream);
+    }
+
+    // Tests running in parallel spawn threads too, so only a leak of one thread per cycle can be
+    // told apart from noise.
+    assert!(thread_count() < baseline + CYCLES / 2);
+}
+
+// Number of threads in this process.
+fn thread_count() -> usize {
+    let mut info = std::mem::MaybeUninit::<libc::proc_taskinfo>::uninit();
+    let size = i32::try_from(std::mem::size_of::<libc::proc_taskinfo>()).expect("to fit");
+    let written = unsafe {
+        libc::proc_pidinfo(
+            libc::getpid(),
+            libc::PROC_PIDTASKINFO,
+            0,
+            info.as_mut_ptr().cast(),
+            size,
+        )
+    };
+    assert_eq!(written, size, "proc_pidinfo to succeed");
+    usize::try_from(unsafe { info.assume_init() }.pti_threadnum).expect("to be non-negative")
+}
+
 #[cfg(feature = "tokio")]
 #[tokio::test]
 async fn must_receive_fs_events_tokio() {
     must_receive_fs_events().await;
 }
 
-#[cfg(feature = "async-std")]
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
 #[async_std::test]
 async fn must_receive_fs_events_async_std() {
     must_receive_fs_events().await;
@@ -168,14 +341,14 @@ async fn must_receive_fs_events_impl(
     let abort_thread = thread::spawn(move || {
         // Once fs operations are completed, abort the stream.
         rx.recv().expect("to be signaled");
-        // Tolerance time
-        sleep(Duration::from_secs(1));
+        // Make sure all events are delivered before aborting.
+        handler.flush_sync();
         handler.abort();
     });
 
     // First we create a file.
     let f = File::create(&test_file).expect("to be created");
-    let inode = f.metadata().expect("to be fetched").ino() as i64;
+    let inode = f.metadata().expect("to be fetched").ino();
     // Sync so that ITEM_CREATE and ITEM_DELETE events won't be squashed into one.
     f.sync_all().expect("to succeed");
     drop(f);
@@ -192,7 +365,7 @@ async fn must_receive_fs_events_impl(
         tokio::time::timeout(Duration::from_secs(6), stream.into_flatten().collect())
             .await
             .expect("to complete");
-    #[cfg(feature = "async-std")]
+    #[cfg(all(feature = "async-std", not(feature = "tokio")))]
     let events: Vec<_> =
         async_std::future::timeout(Duration::from_secs(6), stream.into_flatten().collect())
             .await
@@ -227,3 +400,3877 @@ async fn must_receive_fs_events_impl(
 
     abort_thread.join().expect("to join");
 }
+
+struct ChannelHandler(std::sync::mpsc::Sender<Vec<Event>>);
+
+impl EventHandler for ChannelHandler {
+    fn handle_events(&mut self, events: Vec<Event>) {
+        let _ = self.0.send(events);
+    }
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_dispatch_events_to_handler_tokio() {
+    must_dispatch_events_to_handler().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_dispatch_events_to_handler_async_std() {
+    must_dispatch_events_to_handler().await;
+}
+
+async fn must_dispatch_events_to_handler() {
+    // Acquire the lock so that runloop created in this test won't affect others.
+    let _guard = TEST_PARALLEL_LOCK.lock().await;
+
+    let dir = tempdir().expect("to be created");
+    let test_file = dir
+        .path()
+        .canonicalize()
+        .expect("to succeed")
+        .join("test_file");
+
+    let (tx, rx) = channel();
+    let mut handler = create_event_stream_with_handler(
+        [dir.path()],
+        kFSEventStreamEventIdSinceNow,
+        Duration::ZERO,
+        kFSEventStreamCreateFlagFileEvents | kFSEventStreamCreateFlagNoDefer,
+        ChannelHandler(tx),
+    )
+    .expect("to be created");
+
+    File::create(&test_file).expect("to be created");
+    unsafe { libc::sync() };
+
+    // Events are dispatched on the RunLoop thread, so they arrive without polling any stream.
+    let events = rx
+        .recv_timeout(Duration::from_secs(6))
+        .expect("to receive events");
+    assert!(!events.is_empty());
+
+    handler.abort();
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_dispatch_events_to_closure_tokio() {
+    must_dispatch_events_to_closure().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_dispatch_events_to_closure_async_std() {
+    must_dispatch_events_to_closure().await;
+}
+
+async fn must_dispatch_events_to_closure() {
+    // Acquire the lock so that runloop created in this test won't affect others.
+    let _guard = TEST_PARALLEL_LOCK.lock().await;
+
+    let dir = tempdir().expect("to be created");
+    let test_file = dir
+        .path()
+        .canonicalize()
+        .expect("to succeed")
+        .join("test_file");
+
+    let (tx, rx) = channel();
+    let mut handler = watch(
+        [dir.path()],
+        kFSEventStreamEventIdSinceNow,
+        Duration::ZERO,
+        kFSEventStreamCreateFlagFileEvents | kFSEventStreamCreateFlagNoDefer,
+        move |events| {
+            let _ = tx.send(events);
+        },
+    )
+    .expect("to be created");
+
+    File::create(&test_file).expect("to be created");
+    unsafe { libc::sync() };
+
+    // Events are dispatched on the RunLoop thread, so they arrive without polling any stream.
+    let events = rx
+        .recv_timeout(Duration::from_secs(6))
+        .expect("to receive events");
+    assert!(!events.is_empty());
+
+    handler.abort();
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_send_events_to_owned_channel_tokio() {
+    must_send_events_to_owned_channel().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_send_events_to_owned_channel_async_std() {
+    must_send_events_to_owned_channel().await;
+}
+
+async fn must_send_events_to_owned_channel() {
+    // Acquire the lock so that runloop created in this test won't affect others.
+    let _guard = TEST_PARALLEL_LOCK.lock().await;
+
+    let dir = tempdir().expect("to be created");
+    let test_file = dir
+        .path()
+        .canonicalize()
+        .expect("to succeed")
+        .join("test_file");
+
+    let (tx, mut rx) = runtime::channel(16);
+    let mut handler = create_event_stream_with_sender(
+        [dir.path()],
+        kFSEventStreamEventIdSinceNow,
+        Duration::ZERO,
+        kFSEventStreamCreateFlagFileEvents | kFSEventStreamCreateFlagNoDefer,
+        tx,
+    )
+    .expect("to be created");
+
+    File::create(&test_file).expect("to be created");
+    unsafe { libc::sync() };
+
+    let received = async {
+        while let Some(events) = rx.next().await {
+            if events.iter().any(|event: &Event| event.path == test_file) {
+                return;
+            }
+        }
+        panic!("channel closed before receiving the event");
+    };
+    #[cfg(feature = "tokio")]
+    tokio::time::timeout(Duration::from_secs(6), received)
+        .await
+        .expect("to receive events");
+    #[cfg(all(feature = "async-std", not(feature = "tokio")))]
+    async_std::future::timeout(Duration::from_secs(6), received)
+        .await
+        .expect("to receive events");
+
+    handler.abort();
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_report_batch_sizes_tokio() {
+    must_report_batch_sizes().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_report_batch_sizes_async_std() {
+    must_report_batch_sizes().await;
+}
+
+async fn must_report_batch_sizes() {
+    // Acquire the lock so that runloop created in this test won't affect others.
+    let _guard = TEST_PARALLEL_LOCK.lock().await;
+
+    let dir = tempdir().expect("to be created");
+    let (tx, rx) = channel();
+    let mut handler = watch(
+        [dir.path()],
+        kFSEventStreamEventIdSinceNow,
+        Duration::ZERO,
+        kFSEventStreamCreateFlagFileEvents | kFSEventStreamCreateFlagNoDefer,
+        move |events: Vec<Event>| {
+            let _ = tx.send(events.len());
+        },
+    )
+    .expect("to be created");
+    assert_eq!(handler.stats(), HandlerStats::default());
+
+    File::create(dir.path().join("test_file")).expect("to be created");
+    unsafe { libc::sync() };
+
+    let size = rx
+        .recv_timeout(Duration::from_secs(6))
+        .expect("to receive events");
+    let stats = handler.stats();
+    assert!(stats.last_batch_size > 0);
+    assert!(stats.max_recent_batch_size >= cmp::max(size, stats.last_batch_size));
+
+    handler.abort();
+    assert_eq!(handler.stats(), HandlerStats::default());
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_wait_until_ready_tokio() {
+    must_wait_until_ready().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_wait_until_ready_async_std() {
+    must_wait_until_ready().await;
+}
+
+async fn must_wait_until_ready() {
+    // Acquire the lock so that runloop created in this test won't affect others.
+    let _guard = TEST_PARALLEL_LOCK.lock().await;
+
+    let dir = tempdir().expect("to be created");
+    let (_stream, mut handler) = create_event_stream(
+        [dir.path()],
+        kFSEventStreamEventIdSinceNow,
+        Duration::ZERO,
+        kFSEventStreamCreateFlagFileEvents | kFSEventStreamCreateFlagNoDefer,
+    )
+    .expect("to be created");
+    let ready = handler.wait_ready_async();
+    assert!(ready.await);
+    assert!(handler.wait_ready());
+
+    handler.abort();
+    assert!(!handler.wait_ready());
+    assert!(!handler.wait_ready_async().await);
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_describe_stream_tokio() {
+    must_describe_stream().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_describe_stream_async_std() {
+    must_describe_stream().await;
+}
+
+async fn must_describe_stream() {
+    // Acquire the lock so that runloop created in this test won't affect others.
+    let _guard = TEST_PARALLEL_LOCK.lock().await;
+
+    let dir = tempdir().expect("to be created");
+    let flags = kFSEventStreamCreateFlagFileEvents | kFSEventStreamCreateFlagNoDefer;
+    let latency = Duration::from_millis(100);
+    let (_stream, mut handler) =
+        create_event_stream([dir.path()], kFSEventStreamEventIdSinceNow, latency, flags)
+            .expect("to be created");
+
+    let StreamDescription {
+        paths,
+        flags: described_flags,
+        latency: described_latency,
+        device,
+        running,
+        raw,
+        ..
+    } = handler.describe().expect("to be described");
+    assert_eq!(paths.len(), 1);
+    assert!(paths[0].ends_with(dir.path().file_name().expect("to have a name")));
+    assert_eq!(described_flags, flags);
+    assert_eq!(described_latency, latency);
+    assert_eq!(device, None);
+    assert!(running);
+    assert!(!raw.is_empty());
+
+    handler.abort();
+    assert_eq!(handler.describe(), None);
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_report_time_to_first_event_tokio() {
+    must_report_time_to_first_event().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_report_time_to_first_event_async_std() {
+    must_report_time_to_first_event().await;
+}
+
+async fn must_report_time_to_first_event() {
+    // Acquire the lock so that runloop created in this test won't affect others.
+    let _guard = TEST_PARALLEL_LOCK.lock().await;
+
+    let dir = tempdir().expect("to be created");
+    let (tx, rx) = channel();
+    let mut handler = watch(
+        [dir.path()],
+        kFSEventStreamEventIdSinceNow,
+        Duration::ZERO,
+        kFSEventStreamCreateFlagFileEvents | kFSEventStreamCreateFlagNoDefer,
+        move |events: Vec<Event>| {
+            let _ = tx.send(events.len());
+        },
+    )
+    .expect("to be created");
+    assert_eq!(handler.time_to_first_event(), None);
+
+    File::create(dir.path().join("test_file")).expect("to be created");
+    unsafe { libc::sync() };
+
+    rx.recv_timeout(Duration::from_secs(6))
+        .expect("to receive events");
+    let elapsed = handler
+        .time_to_first_event()
+        .expect("to be recorded after the first batch");
+    assert!(elapsed < Duration::from_secs(6));
+
+    handler.abort();
+    assert_eq!(handler.time_to_first_event(), None);
+}
+
+#[test]
+fn must_reject_too_many_exclusion_paths() {
+    let err = EventStreamBuilder::new(["."])
+        .exclusion_paths(vec![PathBuf::from("."); 9])
+        .build()
+        .err()
+        .expect("to be rejected");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+#[cfg(feature = "notify")]
+fn must_translate_notify_kinds() {
+    use notify::event::{
+        AccessKind, CreateKind, DataChange, MetadataKind, ModifyKind, RemoveKind, RenameMode,
+    };
+    use notify::EventKind as NotifyKind;
+
+    for (kind, flags) in [
+        (
+            NotifyKind::Create(CreateKind::File),
+            StreamFlags::ITEM_CREATED | StreamFlags::IS_FILE,
+        ),
+        (
+            NotifyKind::Create(CreateKind::Folder),
+            StreamFlags::ITEM_CREATED | StreamFlags::IS_DIR,
+        ),
+        (
+            NotifyKind::Modify(ModifyKind::Data(DataChange::Content)),
+            StreamFlags::ITEM_MODIFIED,
+        ),
+        (
+            NotifyKind::Modify(ModifyKind::Metadata(MetadataKind::Permissions)),
+            StreamFlags::INODE_META_MOD,
+        ),
+        (
+            NotifyKind::Modify(ModifyKind::Metadata(MetadataKind::Ownership)),
+            StreamFlags::ITEM_CHANGE_OWNER,
+        ),
+        (
+            NotifyKind::Modify(ModifyKind::Metadata(MetadataKind::Extended)),
+            StreamFlags::ITEM_XATTR_MOD,
+        ),
+        (
+            NotifyKind::Modify(ModifyKind::Name(RenameMode::From)),
+            StreamFlags::ITEM_RENAMED,
+        ),
+        (
+            NotifyKind::Remove(RemoveKind::Any),
+            StreamFlags::ITEM_REMOVED,
+        ),
+        (NotifyKind::Access(AccessKind::Any), StreamFlags::NONE),
+        (NotifyKind::Any, StreamFlags::NONE),
+    ] {
+        assert_eq!(StreamFlags::from_notify_kind(&kind), flags, "{:?}", kind);
+    }
+}
+
+#[test]
+fn must_derive_event_kinds() {
+    let event = test_event(
+        "/a",
+        StreamFlags::ITEM_CREATED
+            | StreamFlags::ITEM_MODIFIED
+            | StreamFlags::FINDER_INFO_MOD
+            | StreamFlags::IS_FILE,
+    );
+    assert_eq!(
+        event.kinds(),
+        vec![
+            EventKind::Created,
+            EventKind::Modified,
+            EventKind::MetadataChanged
+        ]
+    );
+    assert_eq!(
+        StreamFlags::MUST_SCAN_SUBDIRS.kinds(),
+        vec![EventKind::Rescan]
+    );
+    assert!(StreamFlags::IS_DIR.kinds().is_empty());
+}
+
+#[test]
+fn must_classify_content_and_metadata_changes() {
+    let modified = StreamFlags::ITEM_MODIFIED | StreamFlags::IS_FILE;
+    assert!(modified.is_content_change());
+    assert!(!modified.is_metadata_change());
+
+    for flags in [
+        StreamFlags::INODE_META_MOD,
+        StreamFlags::FINDER_INFO_MOD,
+        StreamFlags::ITEM_CHANGE_OWNER,
+        StreamFlags::ITEM_XATTR_MOD,
+    ] {
+        assert!(!(flags | StreamFlags::IS_FILE).is_content_change());
+        assert!((flags | StreamFlags::IS_FILE).is_metadata_change());
+    }
+
+    let coalesced = StreamFlags::ITEM_MODIFIED | StreamFlags::ITEM_XATTR_MOD;
+    assert!(coalesced.is_content_change());
+    assert!(coalesced.is_metadata_change());
+
+    let created = StreamFlags::ITEM_CREATED | StreamFlags::IS_FILE;
+    assert!(!created.is_content_change());
+    assert!(!created.is_metadata_change());
+}
+
+#[test]
+fn must_test_flag_overlap() {
+    assert!(StreamFlags::NONE.is_empty());
+    assert!(StreamFlags::from_bits_truncate(0).is_empty());
+    // Unknown bits are dropped, leaving no flag set.
+    assert!(StreamFlags::from_bits_truncate(0x8000_0000).is_empty());
+    assert!(!StreamFlags::IS_FILE.is_empty());
+
+    let flags = StreamFlags::ITEM_CREATED | StreamFlags::IS_FILE;
+    assert!(flags.intersects(StreamFlags::ITEM_CREATED | StreamFlags::ITEM_REMOVED));
+    assert!(!flags.intersects(StreamFlags::ITEM_REMOVED | StreamFlags::IS_DIR));
+    // Nothing intersects the empty mask, while every set of flags contains it.
+    assert!(!flags.intersects(StreamFlags::NONE));
+    assert!(flags.contains(StreamFlags::NONE));
+}
+
+#[test]
+fn must_retain_unknown_flag_bits() {
+    let bits = 0x8000_0000 | StreamFlags::ITEM_CREATED.bits();
+    let flags = StreamFlags::from_bits_retain(bits);
+    assert_eq!(flags.bits(), bits);
+    assert!(flags.contains(StreamFlags::ITEM_CREATED));
+    assert!(!flags.contains(StreamFlags::ITEM_REMOVED));
+    assert_eq!(flags.to_string(), StreamFlags::ITEM_CREATED.to_string());
+    assert_eq!(StreamFlags::from_bits(bits), None);
+    assert_eq!(
+        StreamFlags::from_bits_truncate(bits),
+        StreamFlags::ITEM_CREATED
+    );
+}
+
+#[test]
+fn must_apply_delivery_presets() {
+    let builder = EventStreamBuilder::new(["."])
+        .flags(kFSEventStreamCreateFlagFileEvents)
+        .interactive();
+    assert_eq!(
+        builder.flags,
+        kFSEventStreamCreateFlagFileEvents | kFSEventStreamCreateFlagNoDefer
+    );
+    assert_eq!(builder.latency, INTERACTIVE_LATENCY);
+
+    let builder = builder.batched(Duration::from_secs(2));
+    assert_eq!(builder.flags, kFSEventStreamCreateFlagFileEvents);
+    assert_eq!(builder.latency, Duration::from_secs(2));
+}
+
+#[test]
+fn must_reject_too_many_paths() {
+    let err = EventStreamBuilder::new(vec![PathBuf::from("."); 3])
+        .max_paths(2)
+        .build()
+        .err()
+        .expect("to be rejected");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    let err = err
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<TooManyPathsError>())
+        .expect("to be a TooManyPathsError");
+    assert_eq!(err, &TooManyPathsError { count: 3, max: 2 });
+    assert!(err.to_string().contains("3 given"));
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_handle_non_utf8_paths_tokio() {
+    must_handle_non_utf8_paths().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_handle_non_utf8_paths_async_std() {
+    must_handle_non_utf8_paths().await;
+}
+
+async fn must_handle_non_utf8_paths() {
+    // Acquire the lock so that runloop created in this test won't affect others.
+    let _guard = TEST_PARALLEL_LOCK.lock().await;
+
+    let dir = tempdir().expect("to be created");
+    let root = dir.path().canonicalize().expect("to succeed");
+    let path = root.join(OsStr::from_bytes(b"invalid-\xff"));
+
+    // The path can't be passed to FSEvents as is, and isn't replaced by a similar one.
+    let err = create_event_stream(
+        [&path],
+        kFSEventStreamEventIdSinceNow,
+        Duration::ZERO,
+        kFSEventStreamCreateFlagNone,
+    )
+    .err()
+    .expect("to be rejected");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+    // Events under such a directory are reported with its exact bytes by the parent's stream.
+    let (stream, mut handler) = create_event_stream(
+        [&root],
+        kFSEventStreamEventIdSinceNow,
+        Duration::ZERO,
+        kFSEventStreamCreateFlagFileEvents | kFSEventStreamCreateFlagNoDefer,
+    )
+    .expect("to be created");
+    if let Err(e) = fs::create_dir(&path) {
+        // APFS and HFS+ require names to be valid UTF-8, so nothing can be reported there.
+        assert_eq!(e.raw_os_error(), Some(libc::EILSEQ));
+        handler.abort();
+        return;
+    }
+    File::create(path.join("a")).expect("to be created");
+    unsafe { libc::sync() };
+
+    let mut expected = path.as_os_str().as_bytes().to_vec();
+    expected.extend_from_slice(b"/a");
+    let events = stream
+        .take_until_event({
+            let expected = expected.clone();
+            move |event| event.path_bytes() == expected.as_slice()
+        })
+        .collect::<Vec<_>>();
+    #[cfg(feature = "tokio")]
+    let events = tokio::time::timeout(Duration::from_secs(6), events)
+        .await
+        .expect("to complete");
+    #[cfg(all(feature = "async-std", not(feature = "tokio")))]
+    let events = async_std::future::timeout(Duration::from_secs(6), events)
+        .await
+        .expect("to complete");
+    assert_eq!(
+        events.last().expect("to be reported").path_bytes(),
+        expected.as_slice()
+    );
+    handler.abort();
+}
+
+#[test]
+fn must_fail_cleanly_when_stream_creation_fails() {
+    // FSEvents refuses to create a stream without any path to watch.
+    let err = EventStreamBuilder::new(Vec::<PathBuf>::new())
+        .build()
+        .err()
+        .expect("to be rejected");
+    assert_eq!(err.kind(), io::ErrorKind::Other);
+}
+
+#[test]
+#[allow(clippy::cast_possible_truncation)]
+fn must_retry_failed_stream_creation() {
+    let backoff = Duration::from_millis(50);
+    let start = Instant::now();
+    let err = EventStreamBuilder::new(Vec::<PathBuf>::new())
+        .retry(3, backoff)
+        .build()
+        .err()
+        .expect("to be rejected");
+    assert_eq!(err.kind(), io::ErrorKind::Other);
+    // Waited for the backoff, then twice as long.
+    assert!(start.elapsed() >= backoff * 3);
+
+    // Rejected configurations aren't retried.
+    let dir = tempdir().expect("to be created");
+    let device = fs::metadata(dir.path()).expect("to be read").dev() as dev_t;
+    let start = Instant::now();
+    let err = EventStreamBuilder::new([OsStr::from_bytes(b"invalid-\xff")])
+        .device(device)
+        .retry(3, Duration::from_secs(10))
+        .build()
+        .err()
+        .expect("to be rejected");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert!(start.elapsed() < Duration::from_secs(10));
+}
+
+#[test]
+fn must_release_context_of_failed_attempts() {
+    let alive = Arc::new(());
+    let handler_alive = alive.clone();
+    let err = EventStreamBuilder::new(Vec::<PathBuf>::new())
+        .retry(3, Duration::from_millis(10))
+        .build_with_handler(move |_: Vec<Event>| {
+            let _alive = &handler_alive;
+        })
+        .err()
+        .expect("to be rejected");
+    assert_eq!(err.kind(), io::ErrorKind::Other);
+    // The handler is only referenced by the contexts of failed attempts, so it's gone if they
+    // are all released. A leaked channel sender would likewise keep an event stream open.
+    assert_eq!(Arc::strong_count(&alive), 1);
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_exclude_paths_tokio() {
+    must_exclude_paths().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_exclude_paths_async_std() {
+    must_exclude_paths().await;
+}
+
+async fn must_exclude_paths() {
+    // Acquire the lock so that runloop created in this test won't affect others.
+    let _guard = TEST_PARALLEL_LOCK.lock().await;
+
+    let dir = tempdir().expect("to be created");
+    let root = dir.path().canonicalize().expect("to succeed");
+    let excluded = root.join("excluded");
+    fs::create_dir(&excluded).expect("to be created");
+
+    let (stream, mut handler) = EventStreamBuilder::new([&root])
+        .flags(kFSEventStreamCreateFlagFileEvents | kFSEventStreamCreateFlagNoDefer)
+        .exclusion_paths(vec![excluded.clone()])
+        .build()
+        .expect("to be created");
+    let (tx, rx) = channel();
+    let abort_thread = thread::spawn(move || {
+        rx.recv().expect("to be signaled");
+        handler.flush_sync();
+        handler.abort();
+    });
+
+    File::create(excluded.join("ignored")).expect("to be created");
+    File::create(root.join("watched")).expect("to be created");
+    unsafe { libc::sync() };
+    tx.send(()).expect("to signal");
+
+    #[cfg(feature = "tokio")]
+    let events: Vec<_> =
+        tokio::time::timeout(Duration::from_secs(6), stream.into_flatten().collect())
+            .await
+            .expect("to complete");
+    #[cfg(all(feature = "async-std", not(feature = "tokio")))]
+    let events: Vec<_> =
+        async_std::future::timeout(Duration::from_secs(6), stream.into_flatten().collect())
+            .await
+            .expect("to complete");
+
+    assert!(events
+        .iter()
+        .any(|event| event.path == root.join("watched")));
+    assert!(events
+        .iter()
+        .all(|event| !event.path.starts_with(&excluded)));
+
+    abort_thread.join().expect("to join");
+}
+
+fn test_event(path: &str, flags: StreamFlags) -> Event {
+    Event {
+        path: PathBuf::from(path),
+        inode: None,
+        flags,
+        raw_flags: flags.bits(),
+        id: 0,
+        extended_data: None,
+        received_at: None,
+    }
+}
+
+#[test]
+fn must_borrow_event_path() {
+    fn path_of(path: impl AsRef<Path>) -> PathBuf {
+        path.as_ref().to_path_buf()
+    }
+
+    let event = test_event("/a/b", StreamFlags::ITEM_CREATED);
+    assert_eq!(path_of(&event), PathBuf::from("/a/b"));
+    let symlink_event = SymlinkEvent {
+        event,
+        symlink_target: Some(PathBuf::from("/c")),
+    };
+    assert_eq!(path_of(&symlink_event), PathBuf::from("/a/b"));
+}
+
+#[test]
+fn must_expose_path_bytes() {
+    let event = Event {
+        path: PathBuf::from(OsStr::from_bytes(b"/a/invalid-\xff")),
+        ..test_event("", StreamFlags::ITEM_CREATED)
+    };
+    assert_eq!(event.path_bytes(), b"/a/invalid-\xff");
+
+    // Bytes reported by the C string callback are kept even if they can't be a `str`.
+    let c_path = CString::new(b"/a/invalid-\xff".to_vec()).expect("no nul");
+    let c_paths = [c_path.as_ptr()];
+    let events = drive_callback(normal_callback, c_paths.as_ptr() as *mut c_void);
+    assert_eq!(events[0].path.to_str(), None);
+    assert_eq!(events[0].path_bytes(), b"/a/invalid-\xff");
+}
+
+#[test]
+fn must_tell_root_events() {
+    let roots = [Path::new("/a"), Path::new("/b/c")];
+    assert!(test_event("/a", StreamFlags::ITEM_RENAMED).is_root(&roots));
+    assert!(test_event("/b/c", StreamFlags::ITEM_REMOVED).is_root(&roots));
+    assert!(!test_event("/a/d", StreamFlags::ITEM_RENAMED).is_root(&roots));
+    assert!(!test_event("/b", StreamFlags::ITEM_MODIFIED).is_root(&roots));
+    assert!(!test_event("/a", StreamFlags::ITEM_RENAMED).is_root::<PathBuf>(&[]));
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_attach_parents_tokio() {
+    must_attach_parents().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_attach_parents_async_std() {
+    must_attach_parents().await;
+}
+
+async fn must_attach_parents() {
+    let events: Vec<_> = ["/a/b", "/a", "/", ""]
+        .iter()
+        .map(|path| test_event(path, StreamFlags::ITEM_MODIFIED))
+        .collect();
+
+    let parents: Vec<_> = EventStream::from_batches(vec![events.clone()])
+        .with_parents()
+        .map(|event| event.parent)
+        .collect()
+        .await;
+    assert_eq!(
+        parents,
+        vec![
+            Some(PathBuf::from("/a")),
+            Some(PathBuf::from("/")),
+            None,
+            None
+        ]
+    );
+
+    let event = EventWithParent {
+        event: events[0].clone(),
+        parent: Some(PathBuf::from("/a")),
+    };
+    assert_eq!(event.as_ref(), Path::new("/a/b"));
+}
+
+#[test]
+fn must_report_event_age() {
+    let event = test_event("/a", StreamFlags::ITEM_MODIFIED);
+    assert_eq!(event.age(), None);
+
+    let received_at = SystemTime::now() - Duration::from_secs(5);
+    let event = Event {
+        received_at: Some(received_at),
+        ..event
+    };
+    assert!(event.age().expect("to be recorded") >= Duration::from_secs(5));
+
+    // A timestamp in the future, e.g. after the clock went backwards, isn't an error.
+    let event = Event {
+        received_at: Some(SystemTime::now() + Duration::from_secs(60)),
+        ..event
+    };
+    assert_eq!(event.age(), Some(Duration::ZERO));
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_record_received_at_tokio() {
+    must_record_received_at().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_record_received_at_async_std() {
+    must_record_received_at().await;
+}
+
+async fn must_record_received_at() {
+    // Acquire the lock so that runloop created in this test won't affect others.
+    let _guard = TEST_PARALLEL_LOCK.lock().await;
+
+    let dir = tempdir().expect("to be created");
+    let (mut stream, mut handler) = EventStreamBuilder::new([dir.path()])
+        .latency(Duration::ZERO)
+        .flags(kFSEventStreamCreateFlagFileEvents | kFSEventStreamCreateFlagNoDefer)
+        .record_received_at(true)
+        .build()
+        .expect("to be created");
+
+    let before = SystemTime::now();
+    File::create(dir.path().join("test_file")).expect("to be created");
+    unsafe { libc::sync() };
+    let events = stream
+        .next_timeout(Duration::from_secs(6))
+        .await
+        .expect("to receive events")
+        .expect("stream not to end");
+    handler.abort();
+
+    for event in events {
+        let received_at = event.received_at.expect("to be recorded");
+        assert!(received_at >= before && received_at <= SystemTime::now());
+    }
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_query_event_metadata_tokio() {
+    must_query_event_metadata().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_query_event_metadata_async_std() {
+    must_query_event_metadata().await;
+}
+
+async fn must_query_event_metadata() {
+    let dir = tempdir().expect("to be created");
+    let path = dir.path().join("a");
+    fs::write(&path, b"abc").expect("to be written");
+
+    let event = test_event(
+        path.to_str().expect("to be utf-8"),
+        StreamFlags::ITEM_CREATED,
+    );
+    assert_eq!(event.metadata().expect("to be queried").len(), 3);
+    assert_eq!(
+        event.metadata_async().await.expect("to be queried").len(),
+        3
+    );
+
+    fs::remove_file(&path).expect("to be removed");
+    assert_eq!(
+        event.metadata().expect_err("to be removed").kind(),
+        io::ErrorKind::NotFound
+    );
+    assert_eq!(
+        event
+            .metadata_async()
+            .await
+            .expect_err("to be removed")
+            .kind(),
+        io::ErrorKind::NotFound
+    );
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_abort_owned_stream_on_drop_tokio() {
+    must_abort_owned_stream_on_drop().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_abort_owned_stream_on_drop_async_std() {
+    must_abort_owned_stream_on_drop().await;
+}
+
+async fn must_abort_owned_stream_on_drop() {
+    let _guard = TEST_PARALLEL_LOCK.lock().await;
+
+    let dir = tempdir().expect("to be created");
+    let path = dir.path().canonicalize().expect("to be canonicalized");
+    let mut stream = EventStreamBuilder::new([&path])
+        .flags(kFSEventStreamCreateFlagFileEvents | kFSEventStreamCreateFlagNoDefer)
+        .build_owned()
+        .expect("to be created");
+    assert_eq!(TEST_RUNNING_RUNLOOP_COUNT.load(Ordering::SeqCst), 1);
+    assert!(stream.handler().is_running());
+
+    File::create(path.join("a")).expect("to be created");
+    let created = async {
+        while let Some(batch) = stream.next().await {
+            if batch.iter().any(|event| event.path == path.join("a")) {
+                return true;
+            }
+        }
+        false
+    };
+    #[cfg(feature = "tokio")]
+    let created = tokio::time::timeout(Duration::from_secs(6), created).await;
+    #[cfg(all(feature = "async-std", not(feature = "tokio")))]
+    let created = async_std::future::timeout(Duration::from_secs(6), created).await;
+    assert!(created.expect("to be delivered"));
+
+    drop(stream);
+    assert_eq!(TEST_RUNNING_RUNLOOP_COUNT.load(Ordering::SeqCst), 0);
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_rebatch_events_tokio() {
+    must_rebatch_events().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_rebatch_events_async_std() {
+    must_rebatch_events().await;
+}
+
+async fn must_rebatch_events() {
+    let events: Vec<_> = ["/a", "/b", "/c", "/d"]
+        .iter()
+        .map(|path| test_event(path, StreamFlags::ITEM_MODIFIED))
+        .collect();
+    let stream = EventStream::from_batches(vec![events[..3].to_vec(), events[3..].to_vec()]);
+
+    let batches: Vec<_> = stream.rebatch(2, Duration::from_secs(60)).collect().await;
+    assert_eq!(batches, vec![events[..2].to_vec(), events[2..].to_vec()]);
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_chunk_batches_tokio() {
+    must_chunk_batches().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_chunk_batches_async_std() {
+    must_chunk_batches().await;
+}
+
+async fn must_chunk_batches() {
+    let events: Vec<_> = ["/a", "/b", "/c", "/d", "/e", "/f"]
+        .iter()
+        .map(|path| test_event(path, StreamFlags::ITEM_MODIFIED))
+        .collect();
+    let stream = EventStream::from_batches(vec![events[..5].to_vec(), events[5..].to_vec()]);
+
+    // Batches are split but never merged.
+    let batches: Vec<_> = stream.chunked(2).collect().await;
+    assert_eq!(
+        batches,
+        vec![
+            events[..2].to_vec(),
+            events[2..4].to_vec(),
+            events[4..5].to_vec(),
+            events[5..].to_vec(),
+        ]
+    );
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_handle_events_concurrently_tokio() {
+    must_handle_events_concurrently().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_handle_events_concurrently_async_std() {
+    must_handle_events_concurrently().await;
+}
+
+async fn must_handle_events_concurrently() {
+    let events: Vec<_> = ["/a", "/b", "/c", "/d", "/e"]
+        .iter()
+        .map(|path| test_event(path, StreamFlags::ITEM_MODIFIED))
+        .collect();
+    let stream = EventStream::from_batches(vec![events[..3].to_vec(), events[3..].to_vec()]);
+
+    let in_flight = Arc::new(AtomicUsize::new(0));
+    let max_in_flight = Arc::new(AtomicUsize::new(0));
+    let handled = Arc::new(Mutex::new(vec![]));
+    stream
+        .for_each_events(2, |event| {
+            let in_flight = in_flight.clone();
+            let max_in_flight = max_in_flight.clone();
+            let handled = handled.clone();
+            async move {
+                let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                max_in_flight.fetch_max(current, Ordering::SeqCst);
+                runtime::sleep(Duration::from_millis(10)).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                handled.lock().expect("to lock").push(event);
+            }
+        })
+        .await;
+
+    // All handlers have completed once the stream ends.
+    assert_eq!(in_flight.load(Ordering::SeqCst), 0);
+    assert_eq!(max_in_flight.load(Ordering::SeqCst), 2);
+    let mut handled = handled.lock().expect("to lock").clone();
+    handled.sort_by(|a, b| a.path.cmp(&b.path));
+    assert_eq!(handled, events);
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_wait_for_next_batch_tokio() {
+    must_wait_for_next_batch().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_wait_for_next_batch_async_std() {
+    must_wait_for_next_batch().await;
+}
+
+async fn must_wait_for_next_batch() {
+    let events = vec![test_event("/a", StreamFlags::ITEM_MODIFIED)];
+    let (tx, mut stream) = EventStream::channel(1);
+
+    let err = stream
+        .next_timeout(Duration::from_millis(100))
+        .await
+        .expect_err("to time out");
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+    tx.try_send(RawBatch {
+        seq: 0,
+        events: events.iter().cloned().map(Ok).collect(),
+    })
+    .expect("to be sent");
+    drop(tx);
+    assert_eq!(
+        stream
+            .next_timeout(Duration::from_secs(1))
+            .await
+            .expect("to be delivered"),
+        Some(events)
+    );
+    assert_eq!(
+        stream
+            .next_timeout(Duration::from_secs(1))
+            .await
+            .expect("to complete"),
+        None
+    );
+}
+
+#[cfg(all(feature = "tokio", feature = "async-std"))]
+#[tokio::test]
+async fn must_pick_tokio_primitives_in_tokio() {
+    assert!(runtime::in_tokio());
+    must_use_runtime_primitives().await;
+}
+
+// Timers and file system operations of tokio panic outside a tokio runtime, so this only passes
+// if async-std's are picked.
+#[cfg(all(feature = "tokio", feature = "async-std"))]
+#[async_std::test]
+async fn must_pick_async_std_primitives_outside_tokio() {
+    assert!(!runtime::in_tokio());
+    must_use_runtime_primitives().await;
+}
+
+#[cfg(all(feature = "tokio", feature = "async-std"))]
+async fn must_use_runtime_primitives() {
+    let started = Instant::now();
+    runtime::sleep(Duration::from_millis(50)).await;
+    assert!(started.elapsed() >= Duration::from_millis(50));
+
+    let dir = tempdir().expect("to be created");
+    assert!(runtime::metadata(dir.path())
+        .await
+        .expect("to be queried")
+        .is_dir());
+    assert_eq!(
+        runtime::metadata(&dir.path().join("missing"))
+            .await
+            .expect_err("to be missing")
+            .kind(),
+        io::ErrorKind::NotFound
+    );
+
+    // Timers of adaptors go through the same path.
+    let (_tx, mut stream) = EventStream::channel(1);
+    assert_eq!(
+        stream
+            .next_timeout(Duration::from_millis(50))
+            .await
+            .expect_err("to time out")
+            .kind(),
+        io::ErrorKind::TimedOut
+    );
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_throttle_batches_tokio() {
+    must_throttle_batches().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_throttle_batches_async_std() {
+    must_throttle_batches().await;
+}
+
+async fn must_throttle_batches() {
+    let events: Vec<_> = ["/a", "/b", "/c", "/d"]
+        .iter()
+        .map(|path| test_event(path, StreamFlags::ITEM_MODIFIED))
+        .collect();
+    let stream = EventStream::from_batches(vec![
+        events[..1].to_vec(),
+        events[1..2].to_vec(),
+        events[2..].to_vec(),
+    ]);
+
+    // The first batch is emitted right away, and the rest arrive within the next interval.
+    let batches: Vec<_> = stream.throttle(1).collect().await;
+    assert_eq!(batches, vec![events[..1].to_vec(), events[1..].to_vec()]);
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_group_by_root_tokio() {
+    must_group_by_root().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_group_by_root_async_std() {
+    must_group_by_root().await;
+}
+
+async fn must_group_by_root() {
+    let events: Vec<_> = ["/a/1", "/b/1", "/a/2", "/a/nested/1", "/c/1"]
+        .iter()
+        .map(|path| test_event(path, StreamFlags::ITEM_MODIFIED))
+        .collect();
+    let stream =
+        EventStream::from_batches(vec![events.clone(), events[..1].to_vec()]).with_roots(vec![
+            PathBuf::from("/a"),
+            PathBuf::from("/b"),
+            PathBuf::from("/a/nested"),
+        ]);
+
+    let groups: Vec<_> = stream.group_by_root().collect().await;
+    assert_eq!(
+        groups,
+        vec![
+            (
+                Some(PathBuf::from("/a")),
+                vec![events[0].clone(), events[2].clone()]
+            ),
+            (Some(PathBuf::from("/b")), vec![events[1].clone()]),
+            (Some(PathBuf::from("/a/nested")), vec![events[3].clone()]),
+            (None, vec![events[4].clone()]),
+            (Some(PathBuf::from("/a")), vec![events[0].clone()]),
+        ]
+    );
+}
+
+#[cfg(all(feature = "jsonl", feature = "tokio"))]
+#[tokio::test]
+async fn must_write_jsonl_tokio() {
+    must_write_jsonl().await;
+}
+
+#[cfg(all(feature = "jsonl", feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_write_jsonl_async_std() {
+    must_write_jsonl().await;
+}
+
+#[cfg(feature = "jsonl")]
+async fn must_write_jsonl() {
+    let events: Vec<_> = ["/a", "/b", "/c"]
+        .iter()
+        .map(|path| test_event(path, StreamFlags::ITEM_MODIFIED))
+        .collect();
+    let stream = EventStream::from_batches(vec![events[..2].to_vec(), events[2..].to_vec()]);
+
+    let mut output = vec![];
+    stream.write_jsonl(&mut output).await.expect("to write");
+    let parsed: Vec<Event> = String::from_utf8(output)
+        .expect("to be utf-8")
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("to be valid json"))
+        .collect();
+    assert_eq!(parsed, events);
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_partition_file_dir_tokio() {
+    must_partition_file_dir().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_partition_file_dir_async_std() {
+    must_partition_file_dir().await;
+}
+
+async fn must_partition_file_dir() {
+    let file = test_event("/file", StreamFlags::ITEM_CREATED | StreamFlags::IS_FILE);
+    let symlink = test_event(
+        "/symlink",
+        StreamFlags::ITEM_CREATED | StreamFlags::IS_SYMLINK,
+    );
+    let dir = test_event("/dir", StreamFlags::ITEM_CREATED | StreamFlags::IS_DIR);
+    let dir_granular = test_event("/dir_granular", StreamFlags::NONE);
+    let stream = EventStream::from_batches(vec![
+        vec![file.clone(), dir.clone()],
+        vec![dir_granular.clone(), symlink.clone()],
+    ]);
+
+    let (files, dirs) = stream.partition_file_dir(16);
+    let files: Vec<_> = files.collect().await;
+    let dirs: Vec<_> = dirs.collect().await;
+    assert_eq!(files, vec![file, symlink]);
+    assert_eq!(dirs, vec![dir, dir_granular]);
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_bound_partition_buffer_tokio() {
+    must_bound_partition_buffer().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_bound_partition_buffer_async_std() {
+    must_bound_partition_buffer().await;
+}
+
+async fn must_bound_partition_buffer() {
+    let dir_a = test_event("/a", StreamFlags::ITEM_CREATED | StreamFlags::IS_DIR);
+    let dir_b = test_event("/b", StreamFlags::ITEM_CREATED | StreamFlags::IS_DIR);
+    let file = test_event("/file", StreamFlags::ITEM_CREATED | StreamFlags::IS_FILE);
+    let stream = EventStream::from_batches(vec![vec![dir_a.clone(), dir_b.clone(), file.clone()]]);
+
+    let (mut files, mut dirs) = stream.partition_file_dir(1);
+    // `dir_a` fills the buffer of `dirs`, so `files` can't go further until it's consumed.
+    assert_eq!(files.next().now_or_never(), None);
+    assert_eq!(dirs.next().await, Some(dir_a));
+    assert_eq!(dirs.next().await, Some(dir_b));
+    assert_eq!(files.next().await, Some(file));
+    assert_eq!(files.next().await, None);
+    assert_eq!(dirs.next().await, None);
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_ignore_self_tokio() {
+    must_ignore_self().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_ignore_self_async_std() {
+    must_ignore_self().await;
+}
+
+async fn must_ignore_self() {
+    // Acquire the lock so that runloop created in this test won't affect others.
+    let _guard = TEST_PARALLEL_LOCK.lock().await;
+
+    let futs: FuturesUnordered<_> = [must_ignore_self_impl(true), must_ignore_self_impl(false)]
+        .into_iter()
+        .collect();
+
+    assert_eq!(futs.collect::<Vec<_>>().await.len(), 2);
+}
+
+async fn must_ignore_self_impl(ignore_self: bool) {
+    let dir = tempdir().expect("to be created");
+    let test_file = dir
+        .path()
+        .canonicalize()
+        .expect("to succeed")
+        .join("test_file");
+
+    let (stream, mut handler) = EventStreamBuilder::new([dir.path()])
+        .flags(kFSEventStreamCreateFlagFileEvents | kFSEventStreamCreateFlagNoDefer)
+        .ignore_self(ignore_self)
+        .build()
+        .expect("to be created");
+    let (tx, rx) = channel();
+    let abort_thread = thread::spawn(move || {
+        rx.recv().expect("to be signaled");
+        handler.flush_sync();
+        handler.abort();
+    });
+
+    // This file is written by the test process itself.
+    File::create(&test_file).expect("to be created");
+    unsafe { libc::sync() };
+    tx.send(()).expect("to signal");
+
+    #[cfg(feature = "tokio")]
+    let events: Vec<_> =
+        tokio::time::timeout(Duration::from_secs(6), stream.into_flatten().collect())
+            .await
+            .expect("to complete");
+    #[cfg(all(feature = "async-std", not(feature = "tokio")))]
+    let events: Vec<_> =
+        async_std::future::timeout(Duration::from_secs(6), stream.into_flatten().collect())
+            .await
+            .expect("to complete");
+
+    let delivered = events.iter().any(|event| event.path == test_file);
+    assert_eq!(delivered, !ignore_self);
+
+    abort_thread.join().expect("to join");
+}
+
+#[test]
+fn must_reject_too_large_latency() {
+    for latency in [MAX_LATENCY + Duration::from_nanos(1), Duration::MAX] {
+        let err = EventStreamBuilder::new(["."])
+            .latency(latency)
+            .build()
+            .err()
+            .expect("to be rejected");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_accept_max_latency_tokio() {
+    must_accept_max_latency().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_accept_max_latency_async_std() {
+    must_accept_max_latency().await;
+}
+
+async fn must_accept_max_latency() {
+    // Acquire the lock so that runloop created in this test won't affect others.
+    let _guard = TEST_PARALLEL_LOCK.lock().await;
+
+    let (_stream, mut handler) = EventStreamBuilder::new(["."])
+        .latency(MAX_LATENCY)
+        .build()
+        .expect("to be created");
+    handler.abort();
+}
+
+#[test]
+fn must_read_file_id_above_i64_max() {
+    assert_eq!(file_id_to_inode(&CFNumber::from(42_i64)), Some(42));
+    assert_eq!(file_id_to_inode(&CFNumber::from(-1_i64)), Some(u64::MAX));
+    assert_eq!(
+        file_id_to_inode(&CFNumber::from(i64::MIN)),
+        Some(i64::MAX as u64 + 1)
+    );
+    assert_eq!(file_id_to_inode(&CFNumber::from(0.5_f64)), None);
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_report_device_being_watched_tokio() {
+    must_report_device_being_watched().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_report_device_being_watched_async_std() {
+    must_report_device_being_watched().await;
+}
+
+#[allow(clippy::cast_possible_truncation)]
+async fn must_report_device_being_watched() {
+    // Acquire the lock so that runloop created in this test won't affect others.
+    let _guard = TEST_PARALLEL_LOCK.lock().await;
+
+    let dir = tempdir().expect("to be created");
+    let device = fs::metadata(dir.path()).expect("to be read").dev() as dev_t;
+
+    let (_stream, mut handler) = create_event_stream_relative_to_device(
+        device,
+        [""],
+        kFSEventStreamEventIdSinceNow,
+        Duration::ZERO,
+        kFSEventStreamCreateFlagNone,
+    )
+    .expect("to be created");
+    assert_eq!(handler.device_being_watched(), Some(device));
+    handler.abort();
+    assert_eq!(handler.device_being_watched(), None);
+
+    let (_stream, mut handler) = create_event_stream(
+        [dir.path()],
+        kFSEventStreamEventIdSinceNow,
+        Duration::ZERO,
+        kFSEventStreamCreateFlagNone,
+    )
+    .expect("to be created");
+    assert_eq!(handler.device_being_watched(), None);
+    handler.abort();
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_route_pool_events_tokio() {
+    must_route_pool_events().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_route_pool_events_async_std() {
+    must_route_pool_events().await;
+}
+
+async fn must_route_pool_events() {
+    // Acquire the lock so that runloop created in this test won't affect others.
+    let _guard = TEST_PARALLEL_LOCK.lock().await;
+
+    let dir_a = tempdir().expect("to be created");
+    let dir_b = tempdir().expect("to be created");
+    let root_a = dir_a.path().canonicalize().expect("to succeed");
+    let root_b = dir_b.path().canonicalize().expect("to succeed");
+
+    let flags = kFSEventStreamCreateFlagFileEvents | kFSEventStreamCreateFlagNoDefer;
+    let mut pool = WatcherPool::new(Duration::ZERO);
+    let stream_a = pool.register([&root_a], flags).expect("to be registered");
+    let stream_b = pool.register([&root_b], flags).expect("to be registered");
+    assert_eq!(pool.stream_count(), 1);
+
+    let (tx, rx) = channel();
+    let abort_thread = thread::spawn(move || {
+        rx.recv().expect("to be signaled");
+        pool.flush_sync();
+        pool.abort();
+    });
+
+    File::create(root_a.join("a")).expect("to be created");
+    File::create(root_b.join("b")).expect("to be created");
+    unsafe { libc::sync() };
+    tx.send(()).expect("to signal");
+
+    let collect_both = futures_util::future::join(
+        stream_a.into_flatten().collect::<Vec<_>>(),
+        stream_b.into_flatten().collect::<Vec<_>>(),
+    );
+    #[cfg(feature = "tokio")]
+    let (events_a, events_b) = tokio::time::timeout(Duration::from_secs(6), collect_both)
+        .await
+        .expect("to complete");
+    #[cfg(all(feature = "async-std", not(feature = "tokio")))]
+    let (events_a, events_b) = async_std::future::timeout(Duration::from_secs(6), collect_both)
+        .await
+        .expect("to complete");
+
+    assert!(events_a.iter().any(|event| event.path == root_a.join("a")));
+    assert!(events_a.iter().all(|event| event.path.starts_with(&root_a)));
+    assert!(events_b.iter().any(|event| event.path == root_b.join("b")));
+    assert!(events_b.iter().all(|event| event.path.starts_with(&root_b)));
+
+    abort_thread.join().expect("to join");
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_prune_dropped_pool_registrations_tokio() {
+    must_prune_dropped_pool_registrations().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_prune_dropped_pool_registrations_async_std() {
+    must_prune_dropped_pool_registrations().await;
+}
+
+async fn must_prune_dropped_pool_registrations() {
+    // Acquire the lock so that runloop created in this test won't affect others.
+    let _guard = TEST_PARALLEL_LOCK.lock().await;
+
+    let dir_a = tempdir().expect("to be created");
+    let dir_b = tempdir().expect("to be created");
+    let root_a = dir_a.path().canonicalize().expect("to succeed");
+    let root_b = dir_b.path().canonicalize().expect("to succeed");
+
+    let flags = kFSEventStreamCreateFlagFileEvents | kFSEventStreamCreateFlagNoDefer;
+    let mut pool = WatcherPool::new(Duration::ZERO);
+    let stream_a = pool.register([&root_a], flags).expect("to be registered");
+    let stream_b = pool.register([&root_b], flags).expect("to be registered");
+
+    // Nothing to prune while both registrations are alive.
+    pool.prune().expect("to be pruned");
+    assert_eq!(pool.stream_count(), 1);
+
+    // The shared stream is replaced by one watching the remaining paths only.
+    drop(stream_a);
+    pool.prune().expect("to be pruned");
+    assert_eq!(pool.stream_count(), 1);
+    assert_eq!(TEST_RUNNING_RUNLOOP_COUNT.load(Ordering::SeqCst), 1);
+
+    File::create(root_b.join("b")).expect("to be created");
+    unsafe { libc::sync() };
+    pool.flush_sync();
+
+    let mut stream_b = stream_b.into_flatten();
+    #[cfg(feature = "tokio")]
+    let event = tokio::time::timeout(Duration::from_secs(6), stream_b.next())
+        .await
+        .expect("to complete");
+    #[cfg(all(feature = "async-std", not(feature = "tokio")))]
+    let event = async_std::future::timeout(Duration::from_secs(6), stream_b.next())
+        .await
+        .expect("to complete");
+    assert!(event.is_some_and(|event| event.path.starts_with(&root_b)));
+
+    // The last registration is gone, so its stream is stopped.
+    drop(stream_b);
+    pool.prune().expect("to be pruned");
+    assert_eq!(pool.stream_count(), 0);
+    assert_eq!(TEST_RUNNING_RUNLOOP_COUNT.load(Ordering::SeqCst), 0);
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_tag_grouped_events_tokio() {
+    must_tag_grouped_events().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_tag_grouped_events_async_std() {
+    must_tag_grouped_events().await;
+}
+
+async fn must_tag_grouped_events() {
+    // Acquire the lock so that runloop created in this test won't affect others.
+    let _guard = TEST_PARALLEL_LOCK.lock().await;
+
+    let dir = tempdir().expect("to be created");
+    let root = dir.path().canonicalize().expect("to succeed");
+    let sub = root.join("sub");
+    fs::create_dir(&sub).expect("to be created");
+
+    let (stream, mut handlers) = watch_groups(
+        &[
+            (vec![root.clone()], kFSEventStreamCreateFlagNoDefer),
+            (
+                vec![sub.clone()],
+                kFSEventStreamCreateFlagFileEvents | kFSEventStreamCreateFlagNoDefer,
+            ),
+        ],
+        Duration::ZERO,
+    )
+    .expect("to be created");
+    assert_eq!(handlers.len(), 2);
+
+    let (tx, rx) = channel();
+    let abort_thread = thread::spawn(move || {
+        rx.recv().expect("to be signaled");
+        for handler in &mut handlers {
+            handler.flush_sync();
+            handler.abort();
+        }
+    });
+
+    File::create(sub.join("a")).expect("to be created");
+    unsafe { libc::sync() };
+    tx.send(()).expect("to signal");
+
+    #[cfg(feature = "tokio")]
+    let events: Vec<_> = tokio::time::timeout(Duration::from_secs(6), stream.collect())
+        .await
+        .expect("to complete");
+    #[cfg(all(feature = "async-std", not(feature = "tokio")))]
+    let events: Vec<_> = async_std::future::timeout(Duration::from_secs(6), stream.collect())
+        .await
+        .expect("to complete");
+
+    // Directory-granular events are reported on the parent directory.
+    assert!(events
+        .iter()
+        .any(|grouped| grouped.group == 0 && grouped.event.path.starts_with(&sub)));
+    assert!(events
+        .iter()
+        .any(|grouped| grouped.group == 1 && grouped.event.path == sub.join("a")));
+    assert!(events
+        .iter()
+        .filter(|grouped| grouped.group == 1)
+        .all(|grouped| grouped.event.path.starts_with(&sub)));
+
+    abort_thread.join().expect("to join");
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_watch_groups_by_latency_tokio() {
+    must_watch_groups_by_latency().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_watch_groups_by_latency_async_std() {
+    must_watch_groups_by_latency().await;
+}
+
+async fn must_watch_groups_by_latency() {
+    // Acquire the lock so that runloop created in this test won't affect others.
+    let _guard = TEST_PARALLEL_LOCK.lock().await;
+
+    let dir = tempdir().expect("to be created");
+    let root = dir.path().canonicalize().expect("to succeed");
+    let [hot, cold, other_hot] = ["hot", "cold", "other_hot"].map(|name| root.join(name));
+    for dir in [&hot, &cold, &other_hot] {
+        fs::create_dir(dir).expect("to be created");
+    }
+
+    let (stream, mut handlers) = watch_latency_groups(
+        &[
+            (vec![hot.clone()], Duration::ZERO),
+            (vec![cold.clone()], Duration::from_secs(1)),
+            (vec![other_hot.clone()], Duration::ZERO),
+        ],
+        kFSEventStreamCreateFlagFileEvents | kFSEventStreamCreateFlagNoDefer,
+    )
+    .expect("to be created");
+    // Groups sharing a latency share a stream.
+    assert_eq!(handlers.len(), 2);
+    assert_eq!(TEST_RUNNING_RUNLOOP_COUNT.load(Ordering::SeqCst), 2);
+
+    let (tx, rx) = channel();
+    let abort_thread = thread::spawn(move || {
+        rx.recv().expect("to be signaled");
+        for handler in &mut handlers {
+            handler.flush_sync();
+            handler.abort();
+        }
+    });
+
+    for dir in [&hot, &cold, &other_hot] {
+        File::create(dir.join("a")).expect("to be created");
+    }
+    unsafe { libc::sync() };
+    tx.send(()).expect("to signal");
+
+    #[cfg(feature = "tokio")]
+    let events: Vec<_> = tokio::time::timeout(Duration::from_secs(6), stream.collect())
+        .await
+        .expect("to complete");
+    #[cfg(all(feature = "async-std", not(feature = "tokio")))]
+    let events: Vec<_> = async_std::future::timeout(Duration::from_secs(6), stream.collect())
+        .await
+        .expect("to complete");
+
+    for (group, dir) in [&hot, &cold, &other_hot].into_iter().enumerate() {
+        assert!(events
+            .iter()
+            .any(|grouped| grouped.group == group && grouped.event.path == dir.join("a")));
+        assert!(events
+            .iter()
+            .filter(|grouped| grouped.group == group)
+            .all(|grouped| grouped.event.path.starts_with(dir)));
+    }
+
+    abort_thread.join().expect("to join");
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_add_path_incrementally_tokio() {
+    must_add_path_incrementally().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_add_path_incrementally_async_std() {
+    must_add_path_incrementally().await;
+}
+
+async fn must_add_path_incrementally() {
+    // Acquire the lock so that runloop created in this test won't affect others.
+    let _guard = TEST_PARALLEL_LOCK.lock().await;
+
+    let dir_a = tempdir().expect("to be created");
+    let dir_b = tempdir().expect("to be created");
+    let root_a = dir_a.path().canonicalize().expect("to succeed");
+    let root_b = dir_b.path().canonicalize().expect("to succeed");
+
+    let (stream, mut handler) = EventStreamBuilder::new([&root_a])
+        .flags(kFSEventStreamCreateFlagFileEvents | kFSEventStreamCreateFlagNoDefer)
+        .build()
+        .expect("to be created");
+    File::create(root_a.join("a")).expect("to be created");
+    unsafe { libc::sync() };
+    handler.flush_sync();
+
+    handler.add_path(&root_b).expect("to be added");
+    assert_eq!(handler.paths(), [root_a.clone(), root_b.clone()]);
+    // The replaced stream is stopped.
+    assert_eq!(TEST_RUNNING_RUNLOOP_COUNT.load(Ordering::SeqCst), 1);
+
+    let (tx, rx) = channel();
+    let abort_thread = thread::spawn(move || {
+        rx.recv().expect("to be signaled");
+        handler.flush_sync();
+        handler.abort();
+        assert!(handler.paths().is_empty());
+        assert!(handler.add_path(".").is_err());
+    });
+
+    File::create(root_b.join("b")).expect("to be created");
+    unsafe { libc::sync() };
+    tx.send(()).expect("to signal");
+
+    #[cfg(feature = "tokio")]
+    let events: Vec<_> =
+        tokio::time::timeout(Duration::from_secs(6), stream.into_flatten().collect())
+            .await
+            .expect("to complete");
+    #[cfg(all(feature = "async-std", not(feature = "tokio")))]
+    let events: Vec<_> =
+        async_std::future::timeout(Duration::from_secs(6), stream.into_flatten().collect())
+            .await
+            .expect("to complete");
+
+    assert!(events.iter().any(|event| event.path == root_a.join("a")));
+    assert!(events.iter().any(|event| event.path == root_b.join("b")));
+
+    abort_thread.join().expect("to join");
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_end_stream_after_failed_add_path_tokio() {
+    must_end_stream_after_failed_add_path().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_end_stream_after_failed_add_path_async_std() {
+    must_end_stream_after_failed_add_path().await;
+}
+
+#[allow(clippy::cast_possible_truncation)]
+async fn must_end_stream_after_failed_add_path() {
+    // Acquire the lock so that runloop created in this test won't affect others.
+    let _guard = TEST_PARALLEL_LOCK.lock().await;
+
+    let dir = tempdir().expect("to be created");
+    let device = fs::metadata(dir.path()).expect("to be read").dev() as dev_t;
+
+    let (stream, mut handler) = create_event_stream_relative_to_device(
+        device,
+        [""],
+        kFSEventStreamEventIdSinceNow,
+        Duration::ZERO,
+        kFSEventStreamCreateFlagNone,
+    )
+    .expect("to be created");
+    // Paths relative to a device must be valid UTF-8, which is only checked once the context of
+    // the new stream is created.
+    let err = handler
+        .add_path(OsStr::from_bytes(b"invalid-\xff"))
+        .expect_err("to be rejected");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    handler.abort();
+
+    #[cfg(feature = "tokio")]
+    let events: Vec<_> = tokio::time::timeout(Duration::from_secs(6), stream.collect())
+        .await
+        .expect("to complete");
+    #[cfg(all(feature = "async-std", not(feature = "tokio")))]
+    let events: Vec<_> = async_std::future::timeout(Duration::from_secs(6), stream.collect())
+        .await
+        .expect("to complete");
+    assert!(events.is_empty());
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_handle_unmount_tokio() {
+    must_handle_unmount().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_handle_unmount_async_std() {
+    must_handle_unmount().await;
+}
+
+async fn must_handle_unmount() {
+    let batches = || {
+        vec![
+            vec![test_event("/Volumes/usb/a", StreamFlags::IS_FILE)],
+            vec![test_event("/Volumes/usb", StreamFlags::UNMOUNT)],
+            vec![test_event("/Volumes/usb", StreamFlags::MOUNT)],
+        ]
+    };
+
+    let items: Vec<_> = EventStream::from_batches(batches())
+        .into_items(UnmountPolicy::End)
+        .collect()
+        .await;
+    assert_eq!(
+        items,
+        vec![
+            StreamItem::Event(test_event("/Volumes/usb/a", StreamFlags::IS_FILE)),
+            StreamItem::Unmounted {
+                path: PathBuf::from("/Volumes/usb")
+            },
+        ]
+    );
+
+    let items: Vec<_> = EventStream::from_batches(batches())
+        .into_items(UnmountPolicy::KeepAlive)
+        .collect()
+        .await;
+    assert_eq!(
+        items,
+        vec![
+            StreamItem::Event(test_event("/Volumes/usb/a", StreamFlags::IS_FILE)),
+            StreamItem::Unmounted {
+                path: PathBuf::from("/Volumes/usb")
+            },
+            StreamItem::Mounted {
+                path: PathBuf::from("/Volumes/usb")
+            },
+        ]
+    );
+
+    // The stream ends right after the unmount, even though the channel is still open.
+    let (tx, stream) = EventStream::channel(1);
+    tx.try_send(RawBatch {
+        seq: 0,
+        events: vec![Ok(test_event("/Volumes/usb", StreamFlags::UNMOUNT))],
+    })
+    .expect("to be sent");
+    let mut items = stream.into_items(UnmountPolicy::End);
+    assert_eq!(
+        items.next().await,
+        Some(StreamItem::Unmounted {
+            path: PathBuf::from("/Volumes/usb")
+        })
+    );
+    assert_eq!(items.next().now_or_never(), Some(None));
+    drop(tx);
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_report_rescans_as_events_tokio() {
+    must_report_rescans_as_events().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_report_rescans_as_events_async_std() {
+    must_report_rescans_as_events().await;
+}
+
+async fn must_report_rescans_as_events() {
+    let event = |path: &str, flags: StreamFlags, id| Event {
+        id,
+        inode: Some(id),
+        ..test_event(path, flags)
+    };
+    let rescan = |path: &str, raw_flags: StreamFlags, id| Event {
+        id,
+        raw_flags: raw_flags.bits(),
+        ..test_event(path, StreamFlags::MUST_SCAN_SUBDIRS)
+    };
+
+    let batches: Vec<_> = EventStream::from_batches(vec![
+        vec![
+            event("/a/b", StreamFlags::ITEM_CREATED | StreamFlags::IS_FILE, 1),
+            event(
+                "/a",
+                StreamFlags::MUST_SCAN_SUBDIRS | StreamFlags::USER_DROPPED,
+                2,
+            ),
+            event("/a", StreamFlags::MUST_SCAN_SUBDIRS, 3),
+            event("/c", StreamFlags::KERNEL_DROPPED, 4),
+        ],
+        vec![event("/a", StreamFlags::MUST_SCAN_SUBDIRS, 5)],
+    ])
+    .rescans_as_events()
+    .collect()
+    .await;
+    assert_eq!(
+        batches,
+        vec![
+            vec![
+                event("/a/b", StreamFlags::ITEM_CREATED | StreamFlags::IS_FILE, 1),
+                rescan(
+                    "/a",
+                    StreamFlags::MUST_SCAN_SUBDIRS | StreamFlags::USER_DROPPED,
+                    2
+                ),
+                rescan("/c", StreamFlags::KERNEL_DROPPED, 4),
+            ],
+            vec![rescan("/a", StreamFlags::MUST_SCAN_SUBDIRS, 5)],
+        ]
+    );
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_separate_sentinel_events_tokio() {
+    must_separate_sentinel_events().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_separate_sentinel_events_async_std() {
+    must_separate_sentinel_events().await;
+}
+
+async fn must_separate_sentinel_events() {
+    let sentinel = |flags: StreamFlags, id| Event {
+        id,
+        ..test_event("", flags)
+    };
+    let batches = || {
+        vec![
+            vec![
+                test_event("/a", StreamFlags::IS_FILE),
+                sentinel(StreamFlags::HISTORY_DONE, 1),
+            ],
+            vec![sentinel(StreamFlags::IDS_WRAPPED, 2)],
+            vec![test_event("/b", StreamFlags::IS_FILE)],
+        ]
+    };
+
+    let items: Vec<_> = EventStream::from_batches(batches())
+        .into_items(UnmountPolicy::End)
+        .collect()
+        .await;
+    assert_eq!(
+        items,
+        vec![
+            StreamItem::Event(test_event("/a", StreamFlags::IS_FILE)),
+            StreamItem::HistoryDone { id: 1 },
+            StreamItem::IdsWrapped { id: 2 },
+            StreamItem::Event(test_event("/b", StreamFlags::IS_FILE)),
+        ]
+    );
+
+    let batches: Vec<_> = EventStream::from_batches(batches())
+        .skip_sentinels()
+        .collect()
+        .await;
+    assert_eq!(
+        batches,
+        vec![
+            vec![test_event("/a", StreamFlags::IS_FILE)],
+            vec![test_event("/b", StreamFlags::IS_FILE)],
+        ]
+    );
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_not_leak_empty_paths_when_replaying_tokio() {
+    must_not_leak_empty_paths_when_replaying().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_not_leak_empty_paths_when_replaying_async_std() {
+    must_not_leak_empty_paths_when_replaying().await;
+}
+
+async fn must_not_leak_empty_paths_when_replaying() {
+    // Acquire the lock so that runloop created in this test won't affect others.
+    let _guard = TEST_PARALLEL_LOCK.lock().await;
+
+    let dir = tempdir().expect("to be created");
+    let root = dir.path().canonicalize().expect("to succeed");
+    let from_id = unsafe { FSEventsGetCurrentEventId() };
+    File::create(root.join("a")).expect("to be created");
+    unsafe { libc::sync() };
+    // Let FSEvents record the event before replaying.
+    sleep(Duration::from_secs(1));
+
+    let (stream, mut handler) = EventStreamBuilder::new([&root])
+        .since_when(from_id)
+        .latency(Duration::ZERO)
+        .flags(kFSEventStreamCreateFlagFileEvents | kFSEventStreamCreateFlagNoDefer)
+        .build()
+        .expect("to be created");
+    let mut items = stream.into_items(UnmountPolicy::End);
+    let replay = async {
+        let mut replayed = vec![];
+        while let Some(item) = items.next().await {
+            if matches!(item, StreamItem::HistoryDone { .. }) {
+                return replayed;
+            }
+            replayed.push(item);
+        }
+        panic!("stream ended before history is done");
+    };
+    #[cfg(feature = "tokio")]
+    let replayed = tokio::time::timeout(Duration::from_secs(6), replay)
+        .await
+        .expect("to complete");
+    #[cfg(all(feature = "async-std", not(feature = "tokio")))]
+    let replayed = async_std::future::timeout(Duration::from_secs(6), replay)
+        .await
+        .expect("to complete");
+    handler.abort();
+
+    assert!(replayed
+        .iter()
+        .any(|item| matches!(item, StreamItem::Event(event) if event.path == root.join("a"))));
+    assert!(replayed.iter().all(|item| match item {
+        StreamItem::Event(event) => !event.path.as_os_str().is_empty(),
+        _ => true,
+    }));
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_replay_full_history_tokio() {
+    must_replay_full_history().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_replay_full_history_async_std() {
+    must_replay_full_history().await;
+}
+
+async fn must_replay_full_history() {
+    // Acquire the lock so that runloop created in this test won't affect others.
+    let _guard = TEST_PARALLEL_LOCK.lock().await;
+
+    let dir = tempdir().expect("to be created");
+    let root = dir.path().canonicalize().expect("to succeed");
+    let from_id = unsafe { FSEventsGetCurrentEventId() };
+    File::create(root.join("a")).expect("to be created");
+    unsafe { libc::sync() };
+    // Let FSEvents record the event before replaying.
+    sleep(Duration::from_secs(1));
+
+    let builder = EventStreamBuilder::new([&root])
+        .since_when(from_id)
+        .latency(Duration::ZERO)
+        .flags(kFSEventStreamCreateFlagFileEvents | kFSEventStreamCreateFlagNoDefer);
+    let toggled = builder.clone().full_history(true).full_history(false);
+    assert_eq!(toggled.flags & kFSEventStreamCreateFlagFullHistory, 0);
+    let (stream, mut handler) = builder.full_history(true).build().expect("to be created");
+
+    let events = stream.take_until_event(|event| event.flags.contains(StreamFlags::HISTORY_DONE));
+    #[cfg(feature = "tokio")]
+    let events: Vec<_> = tokio::time::timeout(Duration::from_secs(6), events.collect())
+        .await
+        .expect("to complete");
+    #[cfg(all(feature = "async-std", not(feature = "tokio")))]
+    let events: Vec<_> = async_std::future::timeout(Duration::from_secs(6), events.collect())
+        .await
+        .expect("to complete");
+    handler.abort();
+
+    assert!(events.iter().any(|event| event.path == root.join("a")));
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_report_mount_events_tokio() {
+    must_report_mount_events().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_report_mount_events_async_std() {
+    must_report_mount_events().await;
+}
+
+async fn must_report_mount_events() {
+    // Acquire the lock so that runloop created in this test won't affect others.
+    let _guard = TEST_PARALLEL_LOCK.lock().await;
+
+    let dir = tempdir().expect("to be created");
+    let root = dir.path().canonicalize().expect("to succeed");
+    let image = root.join("volume.dmg");
+    let mount_point = root.join("mnt");
+    fs::create_dir(&mount_point).expect("to be created");
+
+    let hdiutil = |args: &[&OsStr]| {
+        let status = Command::new("hdiutil")
+            .args(args)
+            .stdout(Stdio::null())
+            .status()
+            .expect("hdiutil to be available");
+        assert!(status.success(), "hdiutil {:?} failed", args);
+    };
+    hdiutil(&[
+        "create".as_ref(),
+        "-size".as_ref(),
+        "1m".as_ref(),
+        "-fs".as_ref(),
+        "HFS+".as_ref(),
+        "-volname".as_ref(),
+        "fsevent-stream-test".as_ref(),
+        image.as_os_str(),
+    ]);
+
+    let (stream, mut handler) = EventStreamBuilder::new([&root])
+        .flags(kFSEventStreamCreateFlagFileEvents | kFSEventStreamCreateFlagNoDefer)
+        .build()
+        .expect("to be created");
+    let (tx, rx) = channel();
+    let abort_thread = thread::spawn(move || {
+        rx.recv().expect("to be signaled");
+        handler.flush_sync();
+        handler.abort();
+    });
+
+    hdiutil(&[
+        "attach".as_ref(),
+        "-nobrowse".as_ref(),
+        "-mountpoint".as_ref(),
+        mount_point.as_os_str(),
+        image.as_os_str(),
+    ]);
+    hdiutil(&["detach".as_ref(), mount_point.as_os_str()]);
+    tx.send(()).expect("to signal");
+
+    #[cfg(feature = "tokio")]
+    let items: Vec<_> = tokio::time::timeout(
+        Duration::from_secs(6),
+        stream.into_items(UnmountPolicy::KeepAlive).collect(),
+    )
+    .await
+    .expect("to complete");
+    #[cfg(all(feature = "async-std", not(feature = "tokio")))]
+    let items: Vec<_> = async_std::future::timeout(
+        Duration::from_secs(6),
+        stream.into_items(UnmountPolicy::KeepAlive).collect(),
+    )
+    .await
+    .expect("to complete");
+
+    assert!(items.contains(&StreamItem::Mounted {
+        path: mount_point.clone()
+    }));
+    assert!(items.contains(&StreamItem::Unmounted { path: mount_point }));
+
+    abort_thread.join().expect("to join");
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_number_batches_tokio() {
+    must_number_batches().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_number_batches_async_std() {
+    must_number_batches().await;
+}
+
+async fn must_number_batches() {
+    let batches: Vec<_> = EventStream::from_batches(vec![
+        vec![test_event("/a", StreamFlags::IS_FILE)],
+        vec![],
+        vec![test_event("/b", StreamFlags::IS_DIR)],
+    ])
+    .into_batches()
+    .collect()
+    .await;
+    assert_eq!(
+        batches,
+        vec![
+            Batch {
+                seq: 0,
+                events: vec![test_event("/a", StreamFlags::IS_FILE)]
+            },
+            Batch {
+                seq: 1,
+                events: vec![]
+            },
+            Batch {
+                seq: 2,
+                events: vec![test_event("/b", StreamFlags::IS_DIR)]
+            },
+        ]
+    );
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_abort_stream_by_token_tokio() {
+    must_abort_stream_by_token().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_abort_stream_by_token_async_std() {
+    must_abort_stream_by_token().await;
+}
+
+async fn must_abort_stream_by_token() {
+    // Acquire the lock so that no other runloop can be created during this test.
+    let _guard = TEST_PARALLEL_LOCK.lock().await;
+
+    let (stream, mut handler) = create_event_stream(
+        ["."],
+        kFSEventStreamEventIdSinceNow,
+        Duration::ZERO,
+        kFSEventStreamCreateFlagNone,
+    )
+    .expect("to be created");
+    let token = handler.abort_token().expect("to be available");
+    let cloned_token = token.clone();
+    assert!(!token.is_aborted());
+
+    // Abort the stream from a clone of the token.
+    let abort_thread = thread::spawn(move || {
+        cloned_token.abort();
+    });
+
+    // The stream should complete soon without the handler being involved.
+    #[cfg(feature = "tokio")]
+    drop(
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            stream.into_flatten().collect::<Vec<_>>(),
+        )
+        .await
+        .expect("to complete"),
+    );
+    #[cfg(all(feature = "async-std", not(feature = "tokio")))]
+    drop(
+        async_std::future::timeout(
+            Duration::from_secs(1),
+            stream.into_flatten().collect::<Vec<_>>(),
+        )
+        .await
+        .expect("to complete"),
+    );
+    abort_thread.join().expect("to join");
+    assert!(token.is_aborted());
+    // The stream has ended while the handler still holds the worker.
+    assert!(handler.abort_token().is_some());
+
+    // Aborting again has no extra effect, and the handler joins the thread.
+    token.abort();
+    handler.abort();
+    assert!(handler.abort_token().is_none());
+    assert_eq!(TEST_RUNNING_RUNLOOP_COUNT.load(Ordering::SeqCst), 0);
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_end_stream_once_worker_exits_tokio() {
+    must_end_stream_once_worker_exits().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_end_stream_once_worker_exits_async_std() {
+    must_end_stream_once_worker_exits().await;
+}
+
+async fn must_end_stream_once_worker_exits() {
+    // Acquire the lock so that runloop created in this test won't affect others.
+    let _guard = TEST_PARALLEL_LOCK.lock().await;
+
+    let dir = tempdir().expect("to be created");
+    let (stream, mut handler) = create_event_stream(
+        [dir.path()],
+        kFSEventStreamEventIdSinceNow,
+        Duration::ZERO,
+        kFSEventStreamCreateFlagNone,
+    )
+    .expect("to be created");
+
+    // The handler is kept alive, and must not keep the channel open once the worker has exited.
+    handler.abort_token().expect("to be available").abort();
+    #[cfg(feature = "tokio")]
+    drop(
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            stream.into_flatten().collect::<Vec<_>>(),
+        )
+        .await
+        .expect("to complete"),
+    );
+    #[cfg(all(feature = "async-std", not(feature = "tokio")))]
+    drop(
+        async_std::future::timeout(
+            Duration::from_secs(1),
+            stream.into_flatten().collect::<Vec<_>>(),
+        )
+        .await
+        .expect("to complete"),
+    );
+
+    // There's no sink left to recreate the stream with.
+    assert_eq!(
+        handler
+            .add_path(dir.path().join("other"))
+            .expect_err("to be rejected")
+            .kind(),
+        io::ErrorKind::NotConnected
+    );
+    handler.abort();
+    assert_eq!(TEST_RUNNING_RUNLOOP_COUNT.load(Ordering::SeqCst), 0);
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_report_running_state_tokio() {
+    must_report_running_state().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_report_running_state_async_std() {
+    must_report_running_state().await;
+}
+
+async fn must_report_running_state() {
+    // Acquire the lock so that runloop created in this test won't affect others.
+    let _guard = TEST_PARALLEL_LOCK.lock().await;
+
+    let (_stream, mut handler) = create_event_stream(
+        ["."],
+        kFSEventStreamEventIdSinceNow,
+        Duration::ZERO,
+        kFSEventStreamCreateFlagNone,
+    )
+    .expect("to be created");
+    assert!(handler.is_running());
+
+    // Stop the thread behind the handler's back.
+    handler.abort_token().expect("to be available").abort();
+    for _ in 0..100 {
+        if !handler.is_running() {
+            break;
+        }
+        sleep(Duration::from_millis(10));
+    }
+    assert!(!handler.is_running());
+
+    handler.abort();
+    assert!(!handler.is_running());
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_control_stream_from_runloop_thread_tokio() {
+    must_control_stream_from_runloop_thread().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_control_stream_from_runloop_thread_async_std() {
+    must_control_stream_from_runloop_thread().await;
+}
+
+async fn must_control_stream_from_runloop_thread() {
+    // Acquire the lock so that runloop created in this test won't affect others.
+    let _guard = TEST_PARALLEL_LOCK.lock().await;
+
+    let dir = tempdir().expect("to be created");
+    let root = dir.path().canonicalize().expect("to succeed");
+
+    // These calls used to wait for the RunLoop, which is busy running the handler calling them.
+    let shared: Arc<Mutex<Option<EventStreamHandler>>> = Arc::default();
+    let (tx, rx) = channel();
+    let handler = watch(
+        [&root],
+        kFSEventStreamEventIdSinceNow,
+        Duration::ZERO,
+        kFSEventStreamCreateFlagFileEvents | kFSEventStreamCreateFlagNoDefer,
+        {
+            let shared = shared.clone();
+            let excluded = root.join("excluded");
+            move |_: Vec<Event>| {
+                let shared = shared.lock().expect("to lock");
+                if let Some(handler) = shared.as_ref() {
+                    let ready = handler.wait_ready();
+                    handler.set_exclusion_paths(std::slice::from_ref(&excluded));
+                    handler.flush_sync();
+                    handler.abort_token().expect("to be available").abort();
+                    let _ = tx.send(ready);
+                }
+            }
+        },
+    )
+    .expect("to be created");
+    *shared.lock().expect("to lock") = Some(handler);
+
+    File::create(root.join("a")).expect("to be created");
+    let ready = rx
+        .recv_timeout(Duration::from_secs(6))
+        .expect("handler to return");
+    assert!(ready);
+
+    // The RunLoop stops once the callback returns.
+    let mut handler = shared.lock().expect("to lock").take().expect("to be set");
+    for _ in 0..100 {
+        if !handler.is_running() {
+            break;
+        }
+        sleep(Duration::from_millis(10));
+    }
+    assert!(!handler.is_running());
+    assert!(!handler.wait_ready());
+    assert!(!handler.set_exclusion_paths(&[]));
+    handler.abort();
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_take_until_event_tokio() {
+    must_take_until_event().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_take_until_event_async_std() {
+    must_take_until_event().await;
+}
+
+async fn must_take_until_event() {
+    // Acquire the lock so that runloop created in this test won't affect others.
+    let _guard = TEST_PARALLEL_LOCK.lock().await;
+
+    let dir = tempdir().expect("to be created");
+    let root = dir.path().canonicalize().expect("to succeed");
+
+    let (stream, mut handler) = EventStreamBuilder::new([&root])
+        .flags(kFSEventStreamCreateFlagFileEvents | kFSEventStreamCreateFlagNoDefer)
+        .build()
+        .expect("to be created");
+
+    File::create(root.join("a")).expect("to be created");
+    File::create(root.join("stop")).expect("to be created");
+    unsafe { libc::sync() };
+
+    let stop = root.join("stop");
+    let events = stream.take_until_event(move |event| event.path == stop);
+    #[cfg(feature = "tokio")]
+    let events: Vec<_> = tokio::time::timeout(Duration::from_secs(6), events.collect())
+        .await
+        .expect("to complete");
+    #[cfg(all(feature = "async-std", not(feature = "tokio")))]
+    let events: Vec<_> = async_std::future::timeout(Duration::from_secs(6), events.collect())
+        .await
+        .expect("to complete");
+    assert_eq!(events.last().expect("to match").path, root.join("stop"));
+
+    // The RunLoop is stopped without calling abort on the handler.
+    for _ in 0..100 {
+        if !handler.is_running() {
+            break;
+        }
+        sleep(Duration::from_millis(10));
+    }
+    assert!(!handler.is_running());
+    handler.abort();
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_end_at_matching_event_tokio() {
+    must_end_at_matching_event().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_end_at_matching_event_async_std() {
+    must_end_at_matching_event().await;
+}
+
+async fn must_end_at_matching_event() {
+    let a = test_event("/a", StreamFlags::ITEM_CREATED);
+    let stop = test_event("/stop", StreamFlags::ITEM_CREATED);
+    let (tx, stream) = EventStream::channel(1);
+    tx.try_send(RawBatch {
+        seq: 0,
+        events: vec![Ok(a.clone()), Ok(stop.clone())],
+    })
+    .expect("to be sent");
+
+    let mut events = stream.take_until_event(|event| event.path == Path::new("/stop"));
+    assert_eq!(events.next().await, Some(a));
+    assert_eq!(events.next().await, Some(stop));
+    // No more batch is sent, but the channel is still open.
+    assert_eq!(events.next().now_or_never(), Some(None));
+    drop(tx);
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_replay_history_events_tokio() {
+    must_replay_history_events().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_replay_history_events_async_std() {
+    must_replay_history_events().await;
+}
+
+async fn must_replay_history_events() {
+    // Acquire the lock so that runloop created in this test won't affect others.
+    let _guard = TEST_PARALLEL_LOCK.lock().await;
+
+    let dir = tempdir().expect("to be created");
+    let root = dir.path().canonicalize().expect("to succeed");
+    let flags = kFSEventStreamCreateFlagFileEvents | kFSEventStreamCreateFlagNoDefer;
+
+    assert!(history_events([&root], kFSEventStreamEventIdSinceNow, 0, flags).is_err());
+    assert!(history_events([&root], 2, 1, flags).is_err());
+
+    let from_id = unsafe { FSEventsGetCurrentEventId() };
+    File::create(root.join("a")).expect("to be created");
+    unsafe { libc::sync() };
+    // Let FSEvents record the event before replaying.
+    sleep(Duration::from_secs(1));
+
+    let (events, mut handler) =
+        history_events([&root], from_id, kFSEventStreamEventIdSinceNow - 1, flags)
+            .expect("to be created");
+    #[cfg(feature = "tokio")]
+    let events: Vec<_> = tokio::time::timeout(Duration::from_secs(6), events.collect())
+        .await
+        .expect("to complete");
+    #[cfg(all(feature = "async-std", not(feature = "tokio")))]
+    let events: Vec<_> = async_std::future::timeout(Duration::from_secs(6), events.collect())
+        .await
+        .expect("to complete");
+    handler.abort();
+
+    assert!(events.iter().any(|event| event.path == root.join("a")));
+    assert!(events
+        .iter()
+        .all(|event| !event.flags.contains(StreamFlags::HISTORY_DONE)));
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_list_changed_paths_since_tokio() {
+    must_list_changed_paths_since().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_list_changed_paths_since_async_std() {
+    must_list_changed_paths_since().await;
+}
+
+async fn must_list_changed_paths_since() {
+    // Acquire the lock so that runloop created in this test won't affect others.
+    let _guard = TEST_PARALLEL_LOCK.lock().await;
+
+    let dir = tempdir().expect("to be created");
+    let root = dir.path().canonicalize().expect("to succeed");
+
+    assert!(changed_paths_since([&root], kFSEventStreamEventIdSinceNow).is_err());
+
+    let since_id = unsafe { FSEventsGetCurrentEventId() };
+    fs::write(root.join("a"), "1").expect("to be written");
+    fs::write(root.join("a"), "2").expect("to be written");
+    File::create(root.join("b")).expect("to be created");
+    unsafe { libc::sync() };
+    // Let FSEvents record the events before replaying.
+    sleep(Duration::from_secs(1));
+
+    let changed = changed_paths_since([&root], since_id).expect("to be listed");
+    assert_eq!(TEST_RUNNING_RUNLOOP_COUNT.load(Ordering::SeqCst), 0);
+
+    let a: Vec<_> = changed
+        .iter()
+        .filter(|(path, _)| path == &root.join("a"))
+        .collect();
+    assert_eq!(a.len(), 1);
+    assert!(a[0].1.contains(StreamFlags::ITEM_CREATED));
+    assert!(changed.iter().any(|(path, _)| path == &root.join("b")));
+    assert!(changed
+        .iter()
+        .all(|(_, flags)| !flags.contains(StreamFlags::HISTORY_DONE)));
+}
+
+#[test]
+fn must_fold_net_changes() {
+    let dir = tempdir().expect("to be created");
+    let root = dir.path();
+    fs::write(root.join("created"), "").expect("to be written");
+    fs::write(root.join("modified"), "").expect("to be written");
+    fs::write(root.join("replaced"), "").expect("to be written");
+    fs::write(root.join("moved_in"), "").expect("to be written");
+
+    let event = |name: &str, flags: StreamFlags| Event {
+        path: root.join(name),
+        ..test_event("", flags)
+    };
+    let changes = fold_net_changes(vec![
+        event("created", StreamFlags::ITEM_CREATED | StreamFlags::IS_FILE),
+        event("created", StreamFlags::ITEM_MODIFIED | StreamFlags::IS_FILE),
+        event(
+            "modified",
+            StreamFlags::ITEM_MODIFIED | StreamFlags::IS_FILE,
+        ),
+        event("replaced", StreamFlags::ITEM_REMOVED | StreamFlags::IS_FILE),
+        event("replaced", StreamFlags::ITEM_CREATED | StreamFlags::IS_FILE),
+        event("removed", StreamFlags::ITEM_REMOVED | StreamFlags::IS_FILE),
+        event("moved_in", StreamFlags::ITEM_RENAMED | StreamFlags::IS_FILE),
+        event(
+            "moved_out",
+            StreamFlags::ITEM_RENAMED | StreamFlags::IS_FILE,
+        ),
+        event(
+            "transient",
+            StreamFlags::ITEM_CREATED | StreamFlags::IS_FILE,
+        ),
+        event(
+            "transient",
+            StreamFlags::ITEM_REMOVED | StreamFlags::IS_FILE,
+        ),
+        event("", StreamFlags::MUST_SCAN_SUBDIRS),
+        Event {
+            id: 1,
+            ..test_event("", StreamFlags::HISTORY_DONE)
+        },
+    ]);
+
+    let expected: HashMap<_, _> = [
+        ("created", NetChange::Created),
+        ("modified", NetChange::Modified),
+        ("replaced", NetChange::Modified),
+        ("removed", NetChange::Removed),
+        ("moved_in", NetChange::Created),
+        ("moved_out", NetChange::Removed),
+        ("transient", NetChange::Transient),
+        ("", NetChange::Rescan),
+    ]
+    .into_iter()
+    .map(|(name, change)| (root.join(name), change))
+    .collect();
+    assert_eq!(changes, expected);
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_fold_net_changes_since_tokio() {
+    must_fold_net_changes_since().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_fold_net_changes_since_async_std() {
+    must_fold_net_changes_since().await;
+}
+
+async fn must_fold_net_changes_since() {
+    // Acquire the lock so that runloop created in this test won't affect others.
+    let _guard = TEST_PARALLEL_LOCK.lock().await;
+
+    let dir = tempdir().expect("to be created");
+    let root = dir.path().canonicalize().expect("to succeed");
+
+    assert!(net_changes_since([&root], kFSEventStreamEventIdSinceNow).is_err());
+
+    let since_id = unsafe { FSEventsGetCurrentEventId() };
+    fs::write(root.join("a"), "1").expect("to be written");
+    File::create(root.join("b")).expect("to be created");
+    fs::remove_file(root.join("b")).expect("to be removed");
+    unsafe { libc::sync() };
+    // Let FSEvents record the events before replaying.
+    sleep(Duration::from_secs(1));
+
+    let changes = net_changes_since([&root], since_id).expect("to be folded");
+    assert_eq!(TEST_RUNNING_RUNLOOP_COUNT.load(Ordering::SeqCst), 0);
+    assert_eq!(changes.get(&root.join("a")), Some(&NetChange::Created));
+    assert_eq!(changes.get(&root.join("b")), Some(&NetChange::Transient));
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_report_event_shape_tokio() {
+    must_report_event_shape().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_report_event_shape_async_std() {
+    must_report_event_shape().await;
+}
+
+async fn must_report_event_shape() {
+    // Acquire the lock so that runloop created in this test won't affect others.
+    let _guard = TEST_PARALLEL_LOCK.lock().await;
+
+    for (flags, cf_types, extended_data) in [
+        (kFSEventStreamCreateFlagNone, false, false),
+        (kFSEventStreamCreateFlagUseCFTypes, true, false),
+        (
+            kFSEventStreamCreateFlagUseCFTypes | kFSEventStreamCreateFlagUseExtendedData,
+            true,
+            true,
+        ),
+    ] {
+        let (_stream, mut handler) =
+            create_event_stream(["."], kFSEventStreamEventIdSinceNow, Duration::ZERO, flags)
+                .expect("to be created");
+        assert_eq!(handler.uses_cf_types(), cf_types);
+        assert_eq!(handler.has_extended_data(), extended_data);
+        handler.abort();
+        // The flags are still known after the stream is stopped.
+        assert_eq!(handler.has_extended_data(), extended_data);
+    }
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_read_symlink_targets_tokio() {
+    must_read_symlink_targets().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_read_symlink_targets_async_std() {
+    must_read_symlink_targets().await;
+}
+
+async fn must_read_symlink_targets() {
+    let dir = tempdir().expect("to be created");
+    let link = dir.path().join("link");
+    let removed = dir.path().join("removed");
+    std::os::unix::fs::symlink("target", &link).expect("to be created");
+    let link = link.to_str().expect("to be UTF-8");
+    let removed = removed.to_str().expect("to be UTF-8");
+
+    let created = test_event(link, StreamFlags::IS_SYMLINK | StreamFlags::ITEM_CREATED);
+    let gone = test_event(removed, StreamFlags::IS_SYMLINK | StreamFlags::ITEM_CREATED);
+    let renamed = test_event(link, StreamFlags::IS_SYMLINK | StreamFlags::ITEM_RENAMED);
+    let events: Vec<_> =
+        EventStream::from_batches(vec![vec![created.clone(), gone.clone(), renamed.clone()]])
+            .with_symlink_targets()
+            .collect()
+            .await;
+    assert_eq!(
+        events,
+        vec![
+            SymlinkEvent {
+                event: created,
+                symlink_target: Some(PathBuf::from("target"))
+            },
+            SymlinkEvent {
+                event: gone,
+                symlink_target: None
+            },
+            SymlinkEvent {
+                event: renamed,
+                symlink_target: None
+            },
+        ]
+    );
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_drop_transient_items_tokio() {
+    must_drop_transient_items().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_drop_transient_items_async_std() {
+    must_drop_transient_items().await;
+}
+
+async fn must_drop_transient_items() {
+    let with_inode = |path: &str, flags: StreamFlags, inode: u64| Event {
+        inode: Some(inode),
+        ..test_event(path, flags)
+    };
+    let coalesced = test_event(
+        "/tmp/coalesced",
+        StreamFlags::IS_FILE | StreamFlags::ITEM_CREATED | StreamFlags::ITEM_REMOVED,
+    );
+    let created = test_event(
+        "/tmp/split",
+        StreamFlags::IS_FILE | StreamFlags::ITEM_CREATED,
+    );
+    let removed = test_event(
+        "/tmp/split",
+        StreamFlags::IS_FILE | StreamFlags::ITEM_REMOVED,
+    );
+    let renamed_from = with_inode(
+        "/tmp/.swp",
+        StreamFlags::IS_FILE | StreamFlags::ITEM_CREATED | StreamFlags::ITEM_RENAMED,
+        42,
+    );
+    let renamed_to = with_inode(
+        "/tmp/.swp~",
+        StreamFlags::IS_FILE | StreamFlags::ITEM_RENAMED | StreamFlags::ITEM_REMOVED,
+        42,
+    );
+    let modified = test_event(
+        "/tmp/kept",
+        StreamFlags::IS_FILE | StreamFlags::ITEM_MODIFIED,
+    );
+
+    let batches: Vec<_> = EventStream::from_batches(vec![
+        vec![
+            coalesced,
+            created.clone(),
+            removed,
+            renamed_from,
+            renamed_to,
+            modified.clone(),
+        ],
+        vec![test_event(
+            "/tmp/only-transient",
+            StreamFlags::IS_FILE | StreamFlags::ITEM_CREATED | StreamFlags::ITEM_REMOVED,
+        )],
+        vec![created.clone()],
+    ])
+    .drop_transient(TransientPolicy::CheckExistence)
+    .collect()
+    .await;
+    assert_eq!(batches, vec![vec![modified], vec![created]]);
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_keep_existing_items_when_dropping_transient_tokio() {
+    must_keep_existing_items_when_dropping_transient().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_keep_existing_items_when_dropping_transient_async_std() {
+    must_keep_existing_items_when_dropping_transient().await;
+}
+
+async fn must_keep_existing_items_when_dropping_transient() {
+    let dir = tempdir().expect("to be created");
+    let root = dir.path().canonicalize().expect("to be canonicalized");
+    let event = |name: &str, flags: StreamFlags, inode: Option<u64>| Event {
+        inode,
+        ..test_event(root.join(name).to_str().expect("to be utf-8"), flags)
+    };
+
+    // `rm replaced; touch replaced`, coalesced into one event.
+    File::create(root.join("replaced")).expect("to be created");
+    let replaced = event(
+        "replaced",
+        StreamFlags::IS_FILE | StreamFlags::ITEM_CREATED | StreamFlags::ITEM_REMOVED,
+        None,
+    );
+    // An atomic save: write to a temporary file, then rename it over the original one.
+    File::create(root.join("saved")).expect("to be created");
+    let saved_tmp = event(
+        "saved.tmp",
+        StreamFlags::IS_FILE | StreamFlags::ITEM_CREATED | StreamFlags::ITEM_RENAMED,
+        Some(42),
+    );
+    let saved = event(
+        "saved",
+        StreamFlags::IS_FILE
+            | StreamFlags::ITEM_CREATED
+            | StreamFlags::ITEM_REMOVED
+            | StreamFlags::ITEM_RENAMED,
+        Some(42),
+    );
+    // Created in an earlier batch, modified later with the flags accumulated.
+    File::create(root.join("modified")).expect("to be created");
+    let created = event(
+        "modified",
+        StreamFlags::IS_FILE | StreamFlags::ITEM_CREATED,
+        None,
+    );
+    let modified = event(
+        "modified",
+        StreamFlags::IS_FILE
+            | StreamFlags::ITEM_CREATED
+            | StreamFlags::ITEM_REMOVED
+            | StreamFlags::ITEM_MODIFIED,
+        None,
+    );
+    let gone = event(
+        "gone",
+        StreamFlags::IS_FILE | StreamFlags::ITEM_CREATED | StreamFlags::ITEM_REMOVED,
+        None,
+    );
+
+    let batches = vec![
+        vec![replaced.clone(), saved_tmp.clone(), saved.clone(), gone],
+        vec![created.clone()],
+        vec![modified.clone()],
+    ];
+    let kept: Vec<_> = EventStream::from_batches(batches.clone())
+        .drop_transient(TransientPolicy::CheckExistence)
+        .collect()
+        .await;
+    assert_eq!(
+        kept,
+        vec![
+            vec![replaced, saved_tmp, saved],
+            vec![created.clone()],
+            vec![modified]
+        ]
+    );
+
+    let kept: Vec<_> = EventStream::from_batches(batches)
+        .drop_transient(TransientPolicy::FlagsOnly)
+        .collect()
+        .await;
+    assert_eq!(kept, vec![vec![created]]);
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_filter_flags_tokio() {
+    must_filter_flags().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_filter_flags_async_std() {
+    must_filter_flags().await;
+}
+
+async fn must_filter_flags() {
+    let modified = test_event("/a", StreamFlags::IS_FILE | StreamFlags::ITEM_MODIFIED);
+    let removed = test_event("/b", StreamFlags::IS_FILE | StreamFlags::ITEM_REMOVED);
+    let created = test_event("/c", StreamFlags::IS_DIR | StreamFlags::ITEM_CREATED);
+    let events: Vec<_> =
+        EventStream::from_batches(vec![vec![modified.clone(), removed.clone()], vec![created]])
+            .filter_flags(StreamFlags::ITEM_MODIFIED | StreamFlags::ITEM_REMOVED)
+            .collect()
+            .await;
+    assert_eq!(events, vec![modified, removed]);
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_report_changed_dirs_tokio() {
+    must_report_changed_dirs().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_report_changed_dirs_async_std() {
+    must_report_changed_dirs().await;
+}
+
+async fn must_report_changed_dirs() {
+    let file = |path: &str| test_event(path, StreamFlags::IS_FILE | StreamFlags::ITEM_MODIFIED);
+    let batches: Vec<_> = EventStream::from_batches(vec![
+        vec![
+            file("/a/1"),
+            file("/a/2"),
+            file("/a/b/1"),
+            test_event("/a/c", StreamFlags::IS_DIR | StreamFlags::ITEM_CREATED),
+        ],
+        vec![test_event("/d", StreamFlags::MUST_SCAN_SUBDIRS)],
+        vec![test_event(
+            "/",
+            StreamFlags::IS_DIR | StreamFlags::INODE_META_MOD,
+        )],
+    ])
+    .changed_dirs()
+    .collect()
+    .await;
+
+    let set = |paths: &[&str]| paths.iter().map(PathBuf::from).collect::<HashSet<_>>();
+    assert_eq!(
+        batches,
+        vec![set(&["/a", "/a/b", "/a/c"]), set(&["/", "/d"]), set(&["/"])]
+    );
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_debounce_and_classify_events_tokio() {
+    must_debounce_and_classify_events().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_debounce_and_classify_events_async_std() {
+    must_debounce_and_classify_events().await;
+}
+
+async fn must_debounce_and_classify_events() {
+    let dir = tempdir().expect("to be created");
+    let path = |name: &str| dir.path().join(name);
+    for name in ["created", "written", "chmod", "renamed_to"] {
+        File::create(path(name)).expect("to be created");
+    }
+    let event = |name: &str, flags: StreamFlags| Event {
+        path: path(name),
+        ..test_event("", StreamFlags::IS_FILE | flags)
+    };
+    let renamed = |name: &str| Event {
+        inode: Some(42),
+        ..event(name, StreamFlags::ITEM_RENAMED)
+    };
+
+    let events: Vec<_> = EventStream::from_batches(vec![
+        vec![
+            event("created", StreamFlags::ITEM_CREATED),
+            event("written", StreamFlags::ITEM_MODIFIED),
+            renamed("renamed_from"),
+        ],
+        vec![
+            event("created", StreamFlags::ITEM_MODIFIED),
+            renamed("renamed_to"),
+            event("chmod", StreamFlags::INODE_META_MOD),
+            event("removed", StreamFlags::ITEM_REMOVED),
+            event(
+                "transient",
+                StreamFlags::ITEM_CREATED | StreamFlags::ITEM_REMOVED,
+            ),
+            Event {
+                path: dir.path().to_path_buf(),
+                ..test_event("", StreamFlags::MUST_SCAN_SUBDIRS)
+            },
+        ],
+    ])
+    .into_debounced(Duration::from_millis(100))
+    .collect()
+    .await;
+
+    assert_eq!(
+        events,
+        vec![
+            DebouncedEvent::Create(path("created")),
+            DebouncedEvent::Write(path("written")),
+            DebouncedEvent::Rename(path("renamed_from"), path("renamed_to")),
+            DebouncedEvent::Chmod(path("chmod")),
+            DebouncedEvent::Remove(path("removed")),
+            DebouncedEvent::Rescan(dir.path().to_path_buf()),
+        ]
+    );
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_track_inode_tokio() {
+    must_track_inode().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_track_inode_async_std() {
+    must_track_inode().await;
+}
+
+async fn must_track_inode() {
+    let with_inode = |path: &str, flags: StreamFlags, inode: u64| Event {
+        inode: Some(inode),
+        ..test_event(path, StreamFlags::IS_FILE | flags)
+    };
+    let created = with_inode("/a", StreamFlags::ITEM_CREATED, 1);
+    let other = with_inode("/b", StreamFlags::ITEM_MODIFIED, 2);
+    let renamed_from = with_inode("/a", StreamFlags::ITEM_RENAMED, 1);
+    let renamed_to = with_inode("/c", StreamFlags::ITEM_RENAMED, 1);
+    let modified = with_inode("/c", StreamFlags::ITEM_MODIFIED, 1);
+    let without_inode = test_event("/c", StreamFlags::ITEM_MODIFIED);
+    let events: Vec<_> = EventStream::from_batches(vec![
+        vec![created.clone(), other, renamed_from.clone()],
+        vec![renamed_to.clone(), modified.clone(), without_inode],
+    ])
+    .track_inode(1)
+    .collect()
+    .await;
+    assert_eq!(events, vec![created, renamed_from, renamed_to, modified]);
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_filter_flags_in_callback_tokio() {
+    must_filter_flags_in_callback().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_filter_flags_in_callback_async_std() {
+    must_filter_flags_in_callback().await;
+}
+
+async fn must_filter_flags_in_callback() {
+    // Acquire the lock so that runloop created in this test won't affect others.
+    let _guard = TEST_PARALLEL_LOCK.lock().await;
+
+    let dir = tempdir().expect("to be created");
+    let root = dir.path().canonicalize().expect("to succeed");
+
+    let (stream, mut handler) = EventStreamBuilder::new([&root])
+        .flags(kFSEventStreamCreateFlagFileEvents | kFSEventStreamCreateFlagNoDefer)
+        .filter_flags(StreamFlags::ITEM_REMOVED)
+        .build()
+        .expect("to be created");
+    let (tx, rx) = channel();
+    let abort_thread = thread::spawn(move || {
+        rx.recv().expect("to be signaled");
+        handler.flush_sync();
+        handler.abort();
+    });
+
+    File::create(root.join("kept")).expect("to be created");
+    File::create(root.join("removed")).expect("to be created");
+    unsafe { libc::sync() };
+    fs::remove_file(root.join("removed")).expect("to be removed");
+    unsafe { libc::sync() };
+    tx.send(()).expect("to signal");
+
+    #[cfg(feature = "tokio")]
+    let events: Vec<_> =
+        tokio::time::timeout(Duration::from_secs(6), stream.into_flatten().collect())
+            .await
+            .expect("to complete");
+    #[cfg(all(feature = "async-std", not(feature = "tokio")))]
+    let events: Vec<_> =
+        async_std::future::timeout(Duration::from_secs(6), stream.into_flatten().collect())
+            .await
+            .expect("to complete");
+
+    assert!(events
+        .iter()
+        .any(|event| event.path == root.join("removed")));
+    assert!(events
+        .iter()
+        .all(|event| event.flags.contains(StreamFlags::ITEM_REMOVED)));
+
+    abort_thread.join().expect("to join");
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_ignore_watch_root_events_tokio() {
+    must_ignore_watch_root_events().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_ignore_watch_root_events_async_std() {
+    must_ignore_watch_root_events().await;
+}
+
+async fn must_ignore_watch_root_events() {
+    // Acquire the lock so that runloop created in this test won't affect others.
+    let _guard = TEST_PARALLEL_LOCK.lock().await;
+
+    let dir = tempdir().expect("to be created");
+    let root = dir.path().canonicalize().expect("to succeed");
+
+    // Watch the non-canonical path to make sure it's reconciled with reported paths.
+    let (stream, mut handler) = EventStreamBuilder::new([dir.path()])
+        .flags(kFSEventStreamCreateFlagFileEvents | kFSEventStreamCreateFlagNoDefer)
+        .ignore_watch_root_events(true)
+        .build()
+        .expect("to be created");
+    let (tx, rx) = channel();
+    let abort_thread = thread::spawn(move || {
+        rx.recv().expect("to be signaled");
+        handler.flush_sync();
+        handler.abort();
+    });
+
+    fs::set_permissions(&root, fs::Permissions::from_mode(0o755)).expect("to be set");
+    File::create(root.join("a")).expect("to be created");
+    unsafe { libc::sync() };
+    tx.send(()).expect("to signal");
+
+    #[cfg(feature = "tokio")]
+    let events: Vec<_> =
+        tokio::time::timeout(Duration::from_secs(6), stream.into_flatten().collect())
+            .await
+            .expect("to complete");
+    #[cfg(all(feature = "async-std", not(feature = "tokio")))]
+    let events: Vec<_> =
+        async_std::future::timeout(Duration::from_secs(6), stream.into_flatten().collect())
+            .await
+            .expect("to complete");
+
+    assert!(events.iter().any(|event| event.path == root.join("a")));
+    assert!(events.iter().all(|event| event.path != root));
+
+    abort_thread.join().expect("to join");
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_dedup_recent_events_tokio() {
+    must_dedup_recent_events().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_dedup_recent_events_async_std() {
+    must_dedup_recent_events().await;
+}
+
+async fn must_dedup_recent_events() {
+    let a = test_event("/a", StreamFlags::IS_FILE | StreamFlags::ITEM_MODIFIED);
+    let a_removed = test_event("/a", StreamFlags::IS_FILE | StreamFlags::ITEM_REMOVED);
+    let b = test_event("/b", StreamFlags::IS_FILE | StreamFlags::ITEM_MODIFIED);
+    let c = test_event("/c", StreamFlags::IS_FILE | StreamFlags::ITEM_MODIFIED);
+
+    let events: Vec<_> = EventStream::from_batches(vec![
+        vec![a.clone(), a.clone()],
+        vec![a.clone(), b.clone(), a_removed.clone()],
+        // `a` is evicted from the window by `b` and `a_removed`.
+        vec![c.clone(), a.clone()],
+    ])
+    .dedup_recent(2)
+    .collect()
+    .await;
+    assert_eq!(events, vec![a.clone(), b, a_removed, c, a]);
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_report_runloop_waiting_tokio() {
+    must_report_runloop_waiting().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_report_runloop_waiting_async_std() {
+    must_report_runloop_waiting().await;
+}
+
+async fn must_report_runloop_waiting() {
+    // Acquire the lock so that runloop created in this test won't affect others.
+    let _guard = TEST_PARALLEL_LOCK.lock().await;
+
+    let (_stream, mut handler) = create_event_stream(
+        ["."],
+        kFSEventStreamEventIdSinceNow,
+        Duration::ZERO,
+        kFSEventStreamCreateFlagNone,
+    )
+    .expect("to be created");
+
+    // Nothing happens, so the RunLoop should go idle soon.
+    for _ in 0..100 {
+        if handler.is_runloop_waiting() {
+            break;
+        }
+        sleep(Duration::from_millis(10));
+    }
+    assert!(handler.is_runloop_waiting());
+
+    handler.abort();
+    assert!(!handler.is_runloop_waiting());
+}
+
+#[test]
+fn must_wire_context_callbacks() {
+    extern "C" fn retain(info: *mut c_void) -> *mut c_void {
+        info
+    }
+    extern "C" fn copy_description(_info: *mut c_void) -> CFStringRef {
+        std::ptr::null()
+    }
+    impl_release_callback!(release_ctx, u32);
+
+    let ctx = SysFSEventStreamContext::with_callbacks(
+        42_u32,
+        release_ctx,
+        Some(retain),
+        Some(copy_description),
+    );
+    assert!(ctx.retain.is_some());
+    assert!(ctx.release.is_some());
+    assert!(ctx.copy_description.is_some());
+    assert_eq!(unsafe { *ctx.info.cast::<u32>() }, 42);
+
+    release_ctx(ctx.info);
+}
+
+#[test]
+fn must_report_unresolvable_bookmarks() {
+    let bookmarks = [
+        BookmarkData::new(b"not a bookmark".to_vec()),
+        BookmarkData::security_scoped(vec![]),
+    ];
+    match create_event_stream_from_bookmarks(
+        &bookmarks,
+        kFSEventStreamEventIdSinceNow,
+        Duration::ZERO,
+        kFSEventStreamCreateFlagNone,
+    ) {
+        Err(CreateFromBookmarksError::Bookmarks(errors)) => assert_eq!(
+            errors,
+            vec![
+                (0, BookmarkError::Unresolvable),
+                (1, BookmarkError::Unresolvable)
+            ]
+        ),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("must not be created"),
+    }
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_watch_home_tree_tokio() {
+    must_watch_home_tree().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_watch_home_tree_async_std() {
+    must_watch_home_tree().await;
+}
+
+async fn must_watch_home_tree() {
+    // Acquire the lock so that runloop created in this test won't affect others.
+    let _guard = TEST_PARALLEL_LOCK.lock().await;
+
+    let (_stream, mut handler) =
+        watch_home_tree(vec![PathBuf::from("Library"), PathBuf::from(".cache")])
+            .expect("to be created");
+    handler.abort();
+
+    let too_many = (0..=MAX_EXCLUSION_PATHS)
+        .map(|idx| PathBuf::from(idx.to_string()))
+        .collect();
+    assert_eq!(
+        watch_home_tree(too_many)
+            .err()
+            .expect("to be rejected")
+            .kind(),
+        io::ErrorKind::InvalidInput
+    );
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_deliver_with_block_on_full_tokio() {
+    must_deliver_with_block_on_full().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_deliver_with_block_on_full_async_std() {
+    must_deliver_with_block_on_full().await;
+}
+
+async fn must_deliver_with_block_on_full() {
+    // Acquire the lock so that runloop created in this test won't affect others.
+    let _guard = TEST_PARALLEL_LOCK.lock().await;
+
+    let dir = tempdir().expect("to be created");
+    let root = dir.path().canonicalize().expect("to succeed");
+
+    let (stream, mut handler) = EventStreamBuilder::new([&root])
+        .flags(kFSEventStreamCreateFlagFileEvents | kFSEventStreamCreateFlagNoDefer)
+        .delivery_policy(DeliveryPolicy::BlockOnFull)
+        .build()
+        .expect("to be created");
+    let (tx, rx) = channel();
+    let abort_thread = thread::spawn(move || {
+        rx.recv().expect("to be signaled");
+        handler.flush_sync();
+        handler.abort();
+    });
+
+    for idx in 0..10 {
+        File::create(root.join(idx.to_string())).expect("to be created");
+    }
+    unsafe { libc::sync() };
+    tx.send(()).expect("to signal");
+
+    #[cfg(feature = "tokio")]
+    let events: Vec<_> =
+        tokio::time::timeout(Duration::from_secs(6), stream.into_flatten().collect())
+            .await
+            .expect("to complete");
+    #[cfg(all(feature = "async-std", not(feature = "tokio")))]
+    let events: Vec<_> =
+        async_std::future::timeout(Duration::from_secs(6), stream.into_flatten().collect())
+            .await
+            .expect("to complete");
+
+    for idx in 0..10 {
+        assert!(events
+            .iter()
+            .any(|event| event.path == root.join(idx.to_string())));
+    }
+
+    abort_thread.join().expect("to join");
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_order_events_by_id_tokio() {
+    must_order_events_by_id().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_order_events_by_id_async_std() {
+    must_order_events_by_id().await;
+}
+
+async fn must_order_events_by_id() {
+    let with_id = |path: &str, id| Event {
+        id,
+        ..test_event(path, StreamFlags::IS_FILE)
+    };
+    let events: Vec<_> = EventStream::from_batches(vec![
+        vec![with_id("/c", 3), with_id("/a", 1)],
+        vec![with_id("/b", 2), with_id("/d", 3)],
+    ])
+    .ordered(Duration::from_millis(100))
+    .collect()
+    .await;
+    assert_eq!(
+        events,
+        vec![
+            with_id("/a", 1),
+            with_id("/b", 2),
+            with_id("/c", 3),
+            with_id("/d", 3)
+        ]
+    );
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_reuse_prepared_paths_tokio() {
+    must_reuse_prepared_paths().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_reuse_prepared_paths_async_std() {
+    must_reuse_prepared_paths().await;
+}
+
+async fn must_reuse_prepared_paths() {
+    // Acquire the lock so that runloop created in this test won't affect others.
+    let _guard = TEST_PARALLEL_LOCK.lock().await;
+
+    let paths = PreparedPaths::new(["."]).expect("to be prepared");
+    assert_eq!(paths.paths(), [PathBuf::from(".")]);
+    for _ in 0..2 {
+        let (_stream, mut handler) = EventStreamBuilder::from_prepared(paths.clone())
+            .build()
+            .expect("to be created");
+        handler.abort();
+    }
+}
+
+#[test]
+fn must_resume_from_valid_history_only() {
+    let uuid = [1; 16];
+    assert_eq!(resume_point_with(Some(&uuid), 42, &uuid), 42);
+    assert_eq!(
+        resume_point_with(Some(&[2; 16]), 42, &uuid),
+        kFSEventStreamEventIdSinceNow
+    );
+    assert_eq!(
+        resume_point_with(None, 42, &uuid),
+        kFSEventStreamEventIdSinceNow
+    );
+}
+
+#[test]
+#[allow(clippy::cast_possible_truncation)]
+fn must_resume_on_current_device() {
+    let dir = tempdir().expect("to be created");
+    let device = fs::metadata(dir.path()).expect("to be read").dev() as dev_t;
+    if let Some(uuid) = device_uuid(device) {
+        assert_eq!(resume_point(device, 42, &uuid), 42);
+        let mut stale = uuid;
+        stale[0] ^= 0xff;
+        assert_eq!(
+            resume_point(device, 42, &stale),
+            kFSEventStreamEventIdSinceNow
+        );
+    }
+}
+
+#[test]
+fn must_compare_event_ids() {
+    assert!(is_since_now(kFSEventStreamEventIdSinceNow));
+    assert!(!is_since_now(42));
+
+    assert_eq!(compare_ids(2, 1, false), Some(cmp::Ordering::Greater));
+    assert_eq!(compare_ids(1, 1, false), Some(cmp::Ordering::Equal));
+    assert_eq!(compare_ids(1, 2, false), Some(cmp::Ordering::Less));
+    // Ids restart after wrapping, so a smaller id is newer.
+    assert_eq!(compare_ids(1, 2, true), Some(cmp::Ordering::Greater));
+    assert_eq!(compare_ids(kFSEventStreamEventIdSinceNow, 1, false), None);
+    assert_eq!(compare_ids(1, kFSEventStreamEventIdSinceNow, true), None);
+}
+
+#[test]
+fn must_format_event_ids() {
+    assert_eq!(
+        EventId::from(kFSEventStreamEventIdSinceNow),
+        EventId::SINCE_NOW
+    );
+    assert_eq!(EventId::SINCE_NOW.to_string(), "SinceNow");
+    assert_eq!(format!("{:x}", EventId::SINCE_NOW), "SinceNow");
+    assert_eq!(EventId(255).to_string(), "255");
+    assert_eq!(format!("{:#x}", EventId(255)), "0xff");
+    assert_eq!(format!("{:X}", EventId(255)), "FF");
+    assert_eq!(u64::from(EventId(255)), 255);
+
+    let event = test_event("/a", StreamFlags::ITEM_MODIFIED);
+    assert_eq!(event.event_id(), EventId(event.id));
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn must_map_time_to_event_ids_tokio() {
+    must_map_time_to_event_ids().await;
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+#[async_std::test]
+async fn must_map_time_to_event_ids_async_std() {
+    must_map_time_to_event_ids().await;
+}
+
+async fn must_map_time_to_event_ids() {
+    let timeline = IdTimeline::new();
+    let start = SystemTime::now();
+    timeline.record(start, 10);
+    timeline.record(start + Duration::from_secs(60), 20);
+
+    assert_eq!(
+        timeline.id_before(start - Duration::from_secs(1)),
+        kFSEventStreamEventIdSinceNow
+    );
+    assert_eq!(timeline.id_before(start), 10);
+    assert_eq!(timeline.id_before(start + Duration::from_secs(30)), 10);
+    assert_eq!(timeline.id_before(start + Duration::from_secs(600)), 20);
+
+    // A checkpoint is taken per delivered batch, and batches pass through unchanged.
+    let events = vec![test_event("/a", StreamFlags::ITEM_MODIFIED)];
+    let timeline = IdTimeline::new();
+    let batches: Vec<_> = EventStream::from_batches(vec![events.clone(), events.clone()])
+        .record_timeline(timeline.clone())
+        .collect()
+        .await;
+    assert_eq!(batches, vec![events.clone(), events]);
+    assert_eq!(timeline.len(), 2);
+    assert!(timeline.id_before(SystemTime::now()) <= unsafe { FSEventsGetCurrentEventId() });
+
+    let dir = tempdir().expect("to be created");
+    let device = fs::metadata(dir.path()).expect("to be read").dev() as dev_t;
+    assert_eq!(
+        last_event_id_before(device, SystemTime::now() + Duration::from_secs(3600)),
+        kFSEventStreamEventIdSinceNow
+    );
+    assert!(
+        last_event_id_before(device, SystemTime::now()) <= unsafe { FSEventsGetCurrentEventId() }
+    );
+}
+
+#[test]
+fn must_convert_cf_string_paths() {
+    for path in [
+        "/tmp/a",
+        "/tmp/caf\u{e9}",
+        "/tmp/\u{1f980}/\u{4e2d}\u{6587}",
+    ] {
+        assert_eq!(
+            cf_string_to_path(&CFString::new(path)),
+            Some(PathBuf::from(path))
+        );
+    }
+
+    // An unpaired surrogate can't be encoded in UTF-8.
+    let chars = [u16::from(b'/'), 0xd800];
+    let lone_surrogate = unsafe {
+        CFString::wrap_under_create_rule(CFStringCreateWithCharacters(
+            kCFAllocatorDefault,
+            chars.as_ptr(),
+            chars.len() as CFIndex,
+        ))
+    };
+    assert_eq!(cf_string_to_path(&lone_surrogate), None);
+}
+
+#[test]
+fn must_report_callback_panics() {
+    let payloads = Arc::new(Mutex::new(vec![]));
+    let info = StreamContextInfo::with_handler(|_: Vec<Event>| panic!("handler panicked"))
+        .with_panic_handler({
+            let payloads = payloads.clone();
+            move |payload| {
+                let message = payload.downcast_ref::<&str>().copied().unwrap_or_default();
+                payloads.lock().expect("to lock").push(message.to_string());
+            }
+        });
+    let c_path = CString::new("/tmp/a").expect("no nul");
+    let c_paths = [c_path.as_ptr()];
+    let flags = [kFSEventStreamEventFlagItemCreated];
+    let ids = [42];
+    normal_callback(
+        ptr::null_mut(),
+        ptr::addr_of!(info) as *mut c_void,
+        1,
+        c_paths.as_ptr() as *mut c_void,
+        flags.as_ptr(),
+        ids.as_ptr(),
+    );
+    assert_eq!(
+        *payloads.lock().expect("to lock"),
+        vec!["handler panicked".to_string()]
+    );
+}
+
+// Drive `callback` with a single event, and return the delivered batch.
+fn drive_callback(
+    callback: extern "C" fn(
+        SysFSEventStreamRef,
+        *mut c_void,
+        usize,
+        *mut c_void,
+        *const FSEventStreamEventFlags,
+        *const FSEventStreamEventId,
+    ),
+    paths: *mut c_void,
+) -> Vec<Event> {
+    let delivered = Arc::new(Mutex::new(vec![]));
+    let info = StreamContextInfo::with_handler({
+        let delivered = delivered.clone();
+        move |events: Vec<Event>| delivered.lock().expect("to lock").extend(events)
+    });
+    let flags = [kFSEventStreamEventFlagItemCreated];
+    let ids = [42];
+    callback(
+        ptr::null_mut(),
+        ptr::addr_of!(info) as *mut c_void,
+        1,
+        paths,
+        flags.as_ptr(),
+        ids.as_ptr(),
+    );
+    let mut events = vec![];
+    events.append(&mut delivered.lock().expect("to lock"));
+    events
+}
+
+#[test]
+fn must_convert_paths_identically_in_all_callbacks() {
+    let path = "/tmp/caf\u{e9}/\u{1f980}";
+    let c_path = CString::new(path).expect("no nul");
+
+    let c_paths = [c_path.as_ptr()];
+    let from_c = drive_callback(normal_callback, c_paths.as_ptr() as *mut c_void);
+    assert_eq!(from_c[0].path, PathBuf::from(path));
+
+    let cf_paths = CFArray::from_CFTypes(&[CFString::new(path)]);
+    let from_cf = drive_callback(cf_callback, cf_paths.as_concrete_TypeRef() as *mut c_void);
+
+    let dict = CFDictionary::from_CFType_pairs(&[
+        (
+            (*kFSEventStreamEventExtendedDataPathKey).clone(),
+            CFString::new(path).as_CFType(),
+        ),
+        (
+            (*kFSEventStreamEventExtendedFileIDKey).clone(),
+            CFNumber::from(1_i64).as_CFType(),
+        ),
+    ]);
+    let ext_paths = CFArray::from_CFTypes(&[dict]);
+    let from_ext = drive_callback(
+        cf_ext_callback,
+        ext_paths.as_concrete_TypeRef() as *mut c_void,
+    );
+    let from_ext_with_id = drive_callback(
+        cf_ext_with_id_callback,
+        ext_paths.as_concrete_TypeRef() as *mut c_void,
+    );
+
+    for events in [&from_cf, &from_ext, &from_ext_with_id] {
+        assert_eq!(events[0].path, from_c[0].path);
+    }
+    assert_eq!(from_ext_with_id[0].inode, Some(1));
+}
+
+#[test]
+fn must_capture_extended_data() {
+    let delivered = Arc::new(Mutex::new(vec![]));
+    let info = StreamContextInfo::with_handler({
+        let delivered = delivered.clone();
+        move |events: Vec<Event>| delivered.lock().expect("to lock").extend(events)
+    })
+    .with_extended_data_capture();
+    let dict = CFDictionary::from_CFType_pairs(&[
+        (
+            (*kFSEventStreamEventExtendedDataPathKey).clone(),
+            CFString::new("/tmp/a").as_CFType(),
+        ),
+        (
+            (*kFSEventStreamEventExtendedFileIDKey).clone(),
+            CFNumber::from(1_i64).as_CFType(),
+        ),
+        (
+            CFString::new("futureKey"),
+            CFNumber::from(0.5_f64).as_CFType(),
+        ),
+    ]);
+    let ext_paths = CFArray::from_CFTypes(&[dict]);
+    let flags = [kFSEventStreamEventFlagItemCreated];
+    let ids = [42];
+    cf_ext_with_id_callback(
+        ptr::null_mut(),
+        ptr::addr_of!(info) as *mut c_void,
+        1,
+        ext_paths.as_concrete_TypeRef() as *mut c_void,
+        flags.as_ptr(),
+        ids.as_ptr(),
+    );
+
+    let events = delivered.lock().expect("to lock");
+    let extended_data = events[0].extended_data.as_ref().expect("to be captured");
+    assert_eq!(
+        extended_data.get("path"),
+        Some(&ExtendedValue::String("/tmp/a".to_string()))
+    );
+    assert_eq!(
+        extended_data.get("fileID"),
+        Some(&ExtendedValue::Integer(1))
+    );
+    assert!(matches!(
+        extended_data.get("futureKey"),
+        Some(ExtendedValue::Other(_))
+    ));
+
+    let err = EventStreamBuilder::new(["."])
+        .capture_extended_data(true)
+        .build()
+        .err()
+        .expect("to be rejected");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn must_not_read_file_ids_without_file_events() {
+    // Without kFSEventStreamCreateFlagFileEvents, the dictionary carries no file id.
+    let dict = CFDictionary::from_CFType_pairs(&[(
+        (*kFSEventStreamEventExtendedDataPathKey).clone(),
+        CFString::new("/tmp/a").as_CFType(),
+    )]);
+    let ext_paths = CFArray::from_CFTypes(&[dict]);
+    let events = drive_callback(
+        cf_ext_callback,
+        ext_paths.as_concrete_TypeRef() as *mut c_void,
+    );
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].path, PathBuf::from("/tmp/a"));
+    assert_eq!(events[0].inode, None);
+}
+
+#[test]
+fn must_skip_events_missing_extended_data_keys() {
+    let without_path = CFDictionary::from_CFType_pairs(&[(
+        (*kFSEventStreamEventExtendedFileIDKey).clone(),
+        CFNumber::from(1_i64).as_CFType(),
+    )]);
+    let ext_paths = CFArray::from_CFTypes(&[without_path]);
+    for callback in [cf_ext_callback, cf_ext_with_id_callback] {
+        let events = drive_callback(callback, ext_paths.as_concrete_TypeRef() as *mut c_void);
+        assert!(events.is_empty());
+    }
+
+    let without_file_id = CFDictionary::from_CFType_pairs(&[(
+        (*kFSEventStreamEventExtendedDataPathKey).clone(),
+        CFString::new("/tmp/a").as_CFType(),
+    )]);
+    let ext_paths = CFArray::from_CFTypes(&[without_file_id]);
+    let events = drive_callback(
+        cf_ext_with_id_callback,
+        ext_paths.as_concrete_TypeRef() as *mut c_void,
+    );
+    assert!(events.is_empty());
+
+    assert_eq!(
+        EventError::MissingKey {
+            id: 42,
+            key: "fileID"
+        }
+        .to_string(),
+        "[42] missing key fileID in extended data"
+    );
+}
+
+#[test]
+fn must_keep_non_utf8_paths_from_c_strings() {
+    let c_path = CString::new(b"/tmp/\xff\xfe".to_vec()).expect("no nul");
+    let c_paths = [c_path.as_ptr()];
+    let events = drive_callback(normal_callback, c_paths.as_ptr() as *mut c_void);
+    assert_eq!(
+        events[0].path.as_os_str().as_bytes(),
+        b"/tmp/\xff\xfe".as_slice()
+    );
+}
+
+#[test]
+fn must_deliver_borrowed_raw_events() {
+    let path = "/tmp/caf\u{e9}";
+    let c_path = CString::new(path).expect("no nul");
+    let c_paths = [c_path.as_ptr()];
+    let cf_paths = CFArray::from_CFTypes(&[CFString::new(path)]);
+
+    for (callback, paths) in [
+        (
+            normal_callback
+                as extern "C" fn(
+                    SysFSEventStreamRef,
+                    *mut c_void,
+                    usize,
+                    *mut c_void,
+                    *const FSEventStreamEventFlags,
+                    *const FSEventStreamEventId,
+                ),
+            c_paths.as_ptr() as *mut c_void,
+        ),
+        (cf_callback, cf_paths.as_concrete_TypeRef() as *mut c_void),
+    ] {
+        let delivered = Arc::new(Mutex::new(vec![]));
+        let info = StreamContextInfo::with_raw_handler({
+            let delivered = delivered.clone();
+            move |events: &[RawEventRef<'_>]| {
+                let mut delivered = delivered.lock().expect("to lock");
+                for event in events {
+                    // ASCII and precomposed paths are stored as UTF-8, so nothing is copied.
+                    assert!(matches!(event.path, Cow::Borrowed(_)));
+                    delivered.push(event.to_event().expect("to be parsed"));
+                }
+            }
+        });
+        let flags = [kFSEventStreamEventFlagItemCreated];
+        let ids = [42];
+        callback(
+            ptr::null_mut(),
+            ptr::addr_of!(info) as *mut c_void,
+            1,
+            paths,
+            flags.as_ptr(),
+            ids.as_ptr(),
+        );
+
+        let delivered = delivered.lock().expect("to lock");
+        assert_eq!(delivered.len(), 1);
+        assert_eq!(delivered[0].path, PathBuf::from(path));
+        assert_eq!(delivered[0].id, 42);
+        assert_eq!(delivered[0].flags, StreamFlags::ITEM_CREATED);
+    }
+}